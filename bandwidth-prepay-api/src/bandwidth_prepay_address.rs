@@ -0,0 +1,41 @@
+use crate::id;
+use solana_sdk::hash::hashv;
+use solana_sdk::pubkey::Pubkey;
+
+/// Address of the contract `initiator_id` opens with `gatekeeper_id` for session
+/// `session_nonce`. Nobody holds a key for it, but anyone can create an account
+/// there first, so an initiator finding its address taken moves on to the next
+/// session nonce; InitializeAccount only checks that the address was derived
+/// for the initiator.
+pub fn contract_address(
+    initiator_id: &Pubkey,
+    gatekeeper_id: &Pubkey,
+    session_nonce: u64,
+) -> Pubkey {
+    let hash = hashv(&[
+        initiator_id.as_ref(),
+        gatekeeper_id.as_ref(),
+        &session_nonce.to_le_bytes(),
+        id().as_ref(),
+    ]);
+    Pubkey::new(hash.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_contract_address() {
+        let initiator = Pubkey::new_rand();
+        let gatekeeper = Pubkey::new_rand();
+        let address = contract_address(&initiator, &gatekeeper, 0);
+        assert_eq!(address, contract_address(&initiator, &gatekeeper, 0));
+        assert_ne!(address, contract_address(&initiator, &gatekeeper, 1));
+        assert_ne!(address, contract_address(&gatekeeper, &initiator, 0));
+        assert_ne!(
+            address,
+            contract_address(&initiator, &Pubkey::new_rand(), 0)
+        );
+    }
+}
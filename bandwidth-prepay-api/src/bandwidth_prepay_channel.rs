@@ -0,0 +1,143 @@
+use bincode::{deserialize, serialize};
+use serde_derive::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
+use std::io;
+
+const DATA_FRAME: u8 = 0;
+const VOUCHER_FRAME: u8 = 1;
+const FRAME_HEADER_SIZE: usize = 5;
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Initiator's promise to pay up to `amount` lamports in total on a payment channel
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Voucher {
+    pub contract_id: Pubkey,
+    pub amount: u64,
+    pub signature: Signature,
+}
+
+impl Voucher {
+    pub fn new(initiator: &Keypair, contract_id: &Pubkey, amount: u64) -> Self {
+        let message = Self::message(contract_id, amount);
+        Self {
+            contract_id: *contract_id,
+            amount,
+            signature: initiator.sign_message(&message),
+        }
+    }
+
+    pub fn verify(&self, initiator_id: &Pubkey) -> bool {
+        let message = Self::message(&self.contract_id, self.amount);
+        self.signature.verify(initiator_id.as_ref(), &message)
+    }
+
+    fn message(contract_id: &Pubkey, amount: u64) -> Vec<u8> {
+        serialize(&(contract_id, amount)).unwrap()
+    }
+}
+
+/// Unit of the initiator's side of a payment-channel data connection
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ChannelFrame {
+    Data(Vec<u8>),
+    Voucher(Voucher),
+}
+
+impl ChannelFrame {
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = match self {
+            ChannelFrame::Data(data) => (DATA_FRAME, data.clone()),
+            ChannelFrame::Voucher(voucher) => (VOUCHER_FRAME, serialize(voucher).unwrap()),
+        };
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+}
+
+/// Reassembles channel frames from a byte stream
+#[derive(Default)]
+pub struct ChannelDecoder {
+    buffer: Vec<u8>,
+}
+
+impl ChannelDecoder {
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Next fully received frame, if any
+    pub fn next_frame(&mut self) -> Option<io::Result<ChannelFrame>> {
+        if self.buffer.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&self.buffer[1..FRAME_HEADER_SIZE]);
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Some(Err(invalid_frame("frame too large")));
+        }
+        if self.buffer.len() < FRAME_HEADER_SIZE + len {
+            return None;
+        }
+        let frame: Vec<u8> = self.buffer.drain(..FRAME_HEADER_SIZE + len).collect();
+        let payload = &frame[FRAME_HEADER_SIZE..];
+        Some(match frame[0] {
+            DATA_FRAME => Ok(ChannelFrame::Data(payload.to_vec())),
+            VOUCHER_FRAME => deserialize(payload)
+                .map(ChannelFrame::Voucher)
+                .map_err(|_| invalid_frame("malformed voucher")),
+            _ => Err(invalid_frame("unknown frame type")),
+        })
+    }
+}
+
+fn invalid_frame(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_voucher_verify() {
+        let initiator = Keypair::new();
+        let contract = Pubkey::new_rand();
+        let voucher = Voucher::new(&initiator, &contract, 100);
+        assert!(voucher.verify(&initiator.pubkey()));
+        assert!(!voucher.verify(&Pubkey::new_rand()));
+
+        let mut forged = voucher.clone();
+        forged.amount = 200;
+        assert!(!forged.verify(&initiator.pubkey()));
+    }
+
+    #[test]
+    fn test_channel_decoder() {
+        let voucher = Voucher::new(&Keypair::new(), &Pubkey::new_rand(), 100);
+        let data = ChannelFrame::Data(vec![1, 2, 3]);
+        let mut stream = data.encode();
+        stream.extend(ChannelFrame::Voucher(voucher.clone()).encode());
+
+        // Frames split across reads are held until complete
+        let mut decoder = ChannelDecoder::default();
+        decoder.extend(&stream[..4]);
+        assert!(decoder.next_frame().is_none());
+        decoder.extend(&stream[4..10]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), data);
+        assert!(decoder.next_frame().is_none());
+        decoder.extend(&stream[10..]);
+        assert_eq!(
+            decoder.next_frame().unwrap().unwrap(),
+            ChannelFrame::Voucher(voucher)
+        );
+        assert!(decoder.next_frame().is_none());
+
+        decoder.extend(&[7, 0, 0, 0, 0]);
+        assert!(decoder.next_frame().unwrap().is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use crate::bandwidth_prepay_state::BandwidthPrepayError;
+use bincode::{deserialize, serialize_into, serialized_size};
+use serde_derive::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+/// Balance of bandwidth credits issued by `mint`, held in an account owned by this program
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct CreditAccount {
+    pub mint: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+}
+
+impl CreditAccount {
+    pub fn deserialize(input: &[u8]) -> Result<Self, BandwidthPrepayError> {
+        deserialize(input).map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure)
+    }
+
+    pub fn serialize(&self, output: &mut [u8]) -> Result<(), BandwidthPrepayError> {
+        serialize_into(output, self).map_err(|_| BandwidthPrepayError::UserdataTooSmall)
+    }
+
+    pub fn max_size() -> usize {
+        let credit_account = CreditAccount::default();
+        serialized_size(&credit_account).unwrap() as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::id;
+    use solana_sdk::account::Account;
+
+    #[test]
+    fn test_max_size() {
+        assert_eq!(CreditAccount::max_size(), 72);
+    }
+
+    #[test]
+    fn test_serializer() {
+        let mut a = Account::new(0, 72, &id());
+        let b = CreditAccount {
+            mint: Pubkey::new_rand(),
+            owner: Pubkey::new_rand(),
+            amount: 42,
+        };
+        b.serialize(&mut a.data).unwrap();
+        let c = CreditAccount::deserialize(&a.data).unwrap();
+        assert_eq!(b, c);
+    }
+}
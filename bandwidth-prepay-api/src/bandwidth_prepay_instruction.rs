@@ -1,15 +1,63 @@
-use crate::bandwidth_prepay_state::BandwidthPrepayState;
+use crate::bandwidth_prepay_channel::Voucher;
+use crate::bandwidth_prepay_credit::CreditAccount;
+use crate::bandwidth_prepay_receipt::UsageReceipt;
+use crate::bandwidth_prepay_state::{BandwidthPrepayState, ContractTerms};
 use crate::id;
 use serde_derive::{Deserialize, Serialize};
 use solana_sdk::instruction::{AccountMeta, Instruction};
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::system_instruction;
+use solana_sdk::sysvar::clock;
+
+/// One contract's charge in a SpendMany batch
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct BatchCharge {
+    pub data_amount: u64,
+    pub receipt: Option<UsageReceipt>,
+}
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum BandwidthPrepayInstruction {
-    InitializeAccount,
+    /// Record the terms the initiator agrees to
+    InitializeAccount(ContractTerms),
+    /// Charge the contract for the given number of bytes at the agreed tariff,
+    /// priced on the bytes reported so far so fractions of a lamport carry over
     Spend(u64),
     Refund,
+    /// Charge the contract for bytes covered by an initiator-signed receipt
+    SpendWithReceipt(u64, UsageReceipt),
+    /// Pay a payment channel's highest voucher and refund the rest
+    SettleChannel(Voucher),
+    /// Start the dispute window after which the initiator may reclaim a channel
+    RequestChannelClose,
+    ReclaimChannel,
+    /// Return the balance to the initiator once the contract has expired
+    ReclaimExpired,
+    /// Record lamports the initiator transferred into the contract
+    TopUp(u64),
+    /// Return the balance to the initiator and zero the state so the account
+    /// can be initialized again
+    Close,
+    /// Set up a credit account holding credits of the given mint
+    InitializeCreditAccount(Pubkey),
+    /// Issue credits into a credit account; signed by the mint
+    MintCredits(u64),
+    TransferCredits(u64),
+    /// Move credits from the initiator's credit account into the contract's escrow
+    DepositCredits(u64),
+    /// Rewrite a contract created under an older state layout in the current
+    /// one, within its existing size. Contracts too small for it, such as
+    /// untagged ones, keep being served in their own layout. Signed by the
+    /// initiator or the gatekeeper
+    Migrate,
+    /// Hand the contract to a new gatekeeper key, which must not already be on
+    /// its route. The contract keeps the address derived for the gatekeeper it
+    /// was opened with. Signed by both the initiator and the current gatekeeper
+    SetGatekeeper(Pubkey),
+    /// Replace the hot keys allowed to sign for the gatekeeper
+    SetDelegates(Vec<Pubkey>),
+    /// Charge many contracts sharing a provider at once, one charge per contract
+    SpendMany(Vec<BatchCharge>),
 }
 
 pub fn initialize(
@@ -17,12 +65,71 @@ pub fn initialize(
     contract_id: &Pubkey,
     gatekeeper_id: &Pubkey,
     provider_id: &Pubkey,
+    terms: ContractTerms,
+    lamports: u64,
+) -> Vec<Instruction> {
+    let space = BandwidthPrepayState::max_size() as u64;
+    vec![
+        system_instruction::create_account(&initiator_id, contract_id, lamports, space, &id()),
+        initialize_account(
+            initiator_id,
+            contract_id,
+            gatekeeper_id,
+            provider_id,
+            terms,
+            None,
+        ),
+    ]
+}
+
+/// Create a contract escrowing `credits` from `initiator_credit_id`; `lamports`
+/// only fund the contract account. `provider_id` and any payees are credit
+/// accounts of `terms.credit_mint`.
+pub fn initialize_with_credits(
+    initiator_id: &Pubkey,
+    contract_id: &Pubkey,
+    gatekeeper_id: &Pubkey,
+    provider_id: &Pubkey,
+    terms: ContractTerms,
     lamports: u64,
+    initiator_credit_id: &Pubkey,
+    credits: u64,
 ) -> Vec<Instruction> {
     let space = BandwidthPrepayState::max_size() as u64;
     vec![
         system_instruction::create_account(&initiator_id, contract_id, lamports, space, &id()),
-        initialize_account(initiator_id, contract_id, gatekeeper_id, provider_id),
+        initialize_account(
+            initiator_id,
+            contract_id,
+            gatekeeper_id,
+            provider_id,
+            terms,
+            Some(initiator_credit_id),
+        ),
+        deposit_credits(initiator_id, initiator_credit_id, contract_id, credits),
+    ]
+}
+
+/// Start a new session on a contract account that was previously closed; the
+/// lamports it kept on closing count toward the new deposit
+pub fn reinitialize(
+    initiator_id: &Pubkey,
+    contract_id: &Pubkey,
+    gatekeeper_id: &Pubkey,
+    provider_id: &Pubkey,
+    terms: ContractTerms,
+    lamports: u64,
+) -> Vec<Instruction> {
+    vec![
+        system_instruction::transfer(&initiator_id, contract_id, lamports),
+        initialize_account(
+            initiator_id,
+            contract_id,
+            gatekeeper_id,
+            provider_id,
+            terms,
+            None,
+        ),
     ]
 }
 
@@ -31,16 +138,22 @@ fn initialize_account(
     contract_id: &Pubkey,
     gatekeeper_id: &Pubkey,
     provider_id: &Pubkey,
+    terms: ContractTerms,
+    initiator_credit_id: Option<&Pubkey>,
 ) -> Instruction {
-    let account_metas = vec![
+    let mut account_metas = vec![
         AccountMeta::new(*initiator_id, true),
         AccountMeta::new(*contract_id, false),
         AccountMeta::new(*gatekeeper_id, false),
         AccountMeta::new(*provider_id, false),
+        AccountMeta::new(clock::id(), false),
     ];
+    if let Some(initiator_credit_id) = initiator_credit_id {
+        account_metas.push(AccountMeta::new(*initiator_credit_id, false));
+    }
     Instruction::new(
         id(),
-        &BandwidthPrepayInstruction::InitializeAccount,
+        &BandwidthPrepayInstruction::InitializeAccount(terms),
         account_metas,
     )
 }
@@ -49,16 +162,68 @@ pub fn spend(
     gatekeeper_id: &Pubkey,
     contract_id: &Pubkey,
     provider_id: &Pubkey,
-    amount: u64,
+    payee_ids: &[Pubkey],
+    data_amount: u64,
 ) -> Instruction {
-    let account_metas = vec![
+    let mut account_metas = vec![
         AccountMeta::new(*gatekeeper_id, true),
         AccountMeta::new(*contract_id, false),
         AccountMeta::new(*provider_id, false),
+        AccountMeta::new(clock::id(), false),
+    ];
+    account_metas.extend(payee_ids.iter().map(|id| AccountMeta::new(*id, false)));
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::Spend(data_amount),
+        account_metas,
+    )
+}
+
+pub fn spend_with_receipt(
+    gatekeeper_id: &Pubkey,
+    contract_id: &Pubkey,
+    provider_id: &Pubkey,
+    payee_ids: &[Pubkey],
+    data_amount: u64,
+    receipt: &UsageReceipt,
+) -> Instruction {
+    let mut account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(*provider_id, false),
+        AccountMeta::new(clock::id(), false),
+    ];
+    account_metas.extend(payee_ids.iter().map(|id| AccountMeta::new(*id, false)));
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::SpendWithReceipt(data_amount, receipt.clone()),
+        account_metas,
+    )
+}
+
+/// Settle `charges` against contracts that all pay `provider_id`. `payee_ids`
+/// lists every payee of those contracts once.
+pub fn spend_many(
+    gatekeeper_id: &Pubkey,
+    provider_id: &Pubkey,
+    charges: Vec<(Pubkey, BatchCharge)>,
+    payee_ids: &[Pubkey],
+) -> Instruction {
+    let mut account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*provider_id, false),
+        AccountMeta::new(clock::id(), false),
     ];
+    account_metas.extend(
+        charges
+            .iter()
+            .map(|(contract_id, _)| AccountMeta::new(*contract_id, false)),
+    );
+    account_metas.extend(payee_ids.iter().map(|id| AccountMeta::new(*id, false)));
+    let charges = charges.into_iter().map(|(_, charge)| charge).collect();
     Instruction::new(
         id(),
-        &BandwidthPrepayInstruction::Spend(amount),
+        &BandwidthPrepayInstruction::SpendMany(charges),
         account_metas,
     )
 }
@@ -71,3 +236,201 @@ pub fn refund(gatekeeper_id: &Pubkey, contract_id: &Pubkey, initiator_id: &Pubke
     ];
     Instruction::new(id(), &BandwidthPrepayInstruction::Refund, account_metas)
 }
+
+pub fn settle_channel(
+    gatekeeper_id: &Pubkey,
+    contract_id: &Pubkey,
+    provider_id: &Pubkey,
+    initiator_id: &Pubkey,
+    payee_ids: &[Pubkey],
+    voucher: &Voucher,
+) -> Instruction {
+    let mut account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(*provider_id, false),
+        AccountMeta::new(*initiator_id, false),
+        AccountMeta::new(clock::id(), false),
+    ];
+    account_metas.extend(payee_ids.iter().map(|id| AccountMeta::new(*id, false)));
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::SettleChannel(voucher.clone()),
+        account_metas,
+    )
+}
+
+pub fn request_channel_close(initiator_id: &Pubkey, contract_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*initiator_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(clock::id(), false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::RequestChannelClose,
+        account_metas,
+    )
+}
+
+pub fn reclaim_channel(initiator_id: &Pubkey, contract_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*initiator_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(clock::id(), false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::ReclaimChannel,
+        account_metas,
+    )
+}
+
+pub fn reclaim_expired(initiator_id: &Pubkey, contract_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*initiator_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(clock::id(), false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::ReclaimExpired,
+        account_metas,
+    )
+}
+
+pub fn top_up(initiator_id: &Pubkey, contract_id: &Pubkey, lamports: u64) -> Vec<Instruction> {
+    let account_metas = vec![
+        AccountMeta::new(*initiator_id, true),
+        AccountMeta::new(*contract_id, false),
+    ];
+    vec![
+        system_instruction::transfer(initiator_id, contract_id, lamports),
+        Instruction::new(
+            id(),
+            &BandwidthPrepayInstruction::TopUp(lamports),
+            account_metas,
+        ),
+    ]
+}
+
+pub fn close(gatekeeper_id: &Pubkey, contract_id: &Pubkey, initiator_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*contract_id, false),
+        AccountMeta::new(*initiator_id, false),
+    ];
+    Instruction::new(id(), &BandwidthPrepayInstruction::Close, account_metas)
+}
+
+pub fn create_credit_account(
+    payer_id: &Pubkey,
+    credit_account_id: &Pubkey,
+    owner_id: &Pubkey,
+    mint_id: &Pubkey,
+    lamports: u64,
+) -> Vec<Instruction> {
+    let space = CreditAccount::max_size() as u64;
+    let account_metas = vec![
+        AccountMeta::new(*owner_id, false),
+        AccountMeta::new(*credit_account_id, false),
+    ];
+    vec![
+        system_instruction::create_account(payer_id, credit_account_id, lamports, space, &id()),
+        Instruction::new(
+            id(),
+            &BandwidthPrepayInstruction::InitializeCreditAccount(*mint_id),
+            account_metas,
+        ),
+    ]
+}
+
+pub fn mint_credits(mint_id: &Pubkey, credit_account_id: &Pubkey, amount: u64) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*mint_id, true),
+        AccountMeta::new(*credit_account_id, false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::MintCredits(amount),
+        account_metas,
+    )
+}
+
+pub fn transfer_credits(
+    owner_id: &Pubkey,
+    from_id: &Pubkey,
+    to_id: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*owner_id, true),
+        AccountMeta::new(*from_id, false),
+        AccountMeta::new(*to_id, false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::TransferCredits(amount),
+        account_metas,
+    )
+}
+
+pub fn deposit_credits(
+    initiator_id: &Pubkey,
+    initiator_credit_id: &Pubkey,
+    contract_id: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*initiator_id, true),
+        AccountMeta::new(*initiator_credit_id, false),
+        AccountMeta::new(*contract_id, false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::DepositCredits(amount),
+        account_metas,
+    )
+}
+
+pub fn migrate(authority_id: &Pubkey, contract_id: &Pubkey) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*authority_id, true),
+        AccountMeta::new(*contract_id, false),
+    ];
+    Instruction::new(id(), &BandwidthPrepayInstruction::Migrate, account_metas)
+}
+
+pub fn set_gatekeeper(
+    initiator_id: &Pubkey,
+    gatekeeper_id: &Pubkey,
+    contract_id: &Pubkey,
+    new_gatekeeper_id: &Pubkey,
+) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*initiator_id, true),
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*contract_id, false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::SetGatekeeper(*new_gatekeeper_id),
+        account_metas,
+    )
+}
+
+pub fn set_delegates(
+    gatekeeper_id: &Pubkey,
+    contract_id: &Pubkey,
+    delegates: Vec<Pubkey>,
+) -> Instruction {
+    let account_metas = vec![
+        AccountMeta::new(*gatekeeper_id, true),
+        AccountMeta::new(*contract_id, false),
+    ];
+    Instruction::new(
+        id(),
+        &BandwidthPrepayInstruction::SetDelegates(delegates),
+        account_metas,
+    )
+}
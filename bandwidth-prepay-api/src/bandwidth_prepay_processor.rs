@@ -1,48 +1,509 @@
-use crate::bandwidth_prepay_instruction::BandwidthPrepayInstruction;
-use crate::bandwidth_prepay_state::{BandwidthPrepayError, BandwidthPrepayState};
+use crate::bandwidth_prepay_address::contract_address;
+use crate::bandwidth_prepay_channel::Voucher;
+use crate::bandwidth_prepay_credit::CreditAccount;
+use crate::bandwidth_prepay_instruction::{BandwidthPrepayInstruction, BatchCharge};
+use crate::bandwidth_prepay_receipt::UsageReceipt;
+use crate::bandwidth_prepay_state::{
+    BandwidthPrepayError, BandwidthPrepayState, ContractTerms, Hop, CLOSED_CONTRACT_RESERVE,
+    MAX_BASIS_POINTS, MAX_DELEGATES, MAX_HOPS, MAX_PAYEES,
+};
+use crate::id;
 use bincode::deserialize;
 use solana_sdk::account::KeyedAccount;
 use solana_sdk::instruction::InstructionError;
 use solana_sdk::pubkey::Pubkey;
+use solana_sdk::sysvar::clock::{self, Clock};
+use std::cmp;
 
-fn initialize_account(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+fn verify_gatekeeper(
+    keyed_account: &KeyedAccount,
+    state: &BandwidthPrepayState,
+) -> Result<(), BandwidthPrepayError> {
+    if let Some(gatekeeper_pubkey) = keyed_account.signer_key() {
+        if !state.is_gatekeeper(gatekeeper_pubkey) {
+            Err(BandwidthPrepayError::NoGatekeeperAccount)?
+        }
+    } else {
+        Err(BandwidthPrepayError::NotSignedByGatekeeper)?
+    }
+    Ok(())
+}
+
+/// Like verify_gatekeeper, but refuses delegates
+fn verify_gatekeeper_key(
+    keyed_account: &KeyedAccount,
+    state: &BandwidthPrepayState,
+) -> Result<(), BandwidthPrepayError> {
+    verify_gatekeeper(keyed_account, state)?;
+    if keyed_account.signer_key() != Some(&state.gatekeeper_id) {
+        Err(BandwidthPrepayError::NoGatekeeperAccount)?
+    }
+    Ok(())
+}
+
+fn verify_initiator(
+    keyed_account: &KeyedAccount,
+    state: &BandwidthPrepayState,
+) -> Result<(), BandwidthPrepayError> {
+    if let Some(initiator_pubkey) = keyed_account.signer_key() {
+        if initiator_pubkey != &state.initiator_id {
+            Err(BandwidthPrepayError::NoInitiatorAccount)?
+        }
+    } else {
+        Err(BandwidthPrepayError::NotSignedByInitiator)?
+    }
+    Ok(())
+}
+
+fn current_slot(keyed_account: &KeyedAccount) -> Result<u64, BandwidthPrepayError> {
+    if !clock::check_id(keyed_account.unsigned_key()) {
+        Err(BandwidthPrepayError::NoClockAccount)?
+    }
+    let clock: Clock = deserialize(&keyed_account.account.data)
+        .map_err(|_| BandwidthPrepayError::NoClockAccount)?;
+    Ok(clock.slot)
+}
+
+/// Fail unless at least `count` accounts were passed, so indexing them cannot panic
+fn check_account_count(
+    keyed_accounts: &[KeyedAccount],
+    count: usize,
+) -> Result<(), BandwidthPrepayError> {
+    if keyed_accounts.len() < count {
+        Err(BandwidthPrepayError::NotEnoughAccounts)?
+    }
+    Ok(())
+}
+
+/// Fail if any account is passed twice. Each alias would get its own copy of the
+/// account, so lamports moved between them could be created or lost.
+fn check_distinct_accounts(keyed_accounts: &[KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    for (i, keyed_account) in keyed_accounts.iter().enumerate() {
+        if keyed_accounts[..i]
+            .iter()
+            .any(|other| other.unsigned_key() == keyed_account.unsigned_key())
+        {
+            Err(BandwidthPrepayError::DuplicateAccounts)?
+        }
+    }
+    Ok(())
+}
+
+/// State of a contract account, which only this program may own
+fn contract_state(
+    keyed_account: &KeyedAccount,
+) -> Result<BandwidthPrepayState, BandwidthPrepayError> {
+    if keyed_account.account.owner != id() {
+        Err(BandwidthPrepayError::NotProgramOwned)?
+    }
+    BandwidthPrepayState::deserialize(&keyed_account.account.data)
+}
+
+fn credit_account(
+    keyed_account: &KeyedAccount,
+    mint: &Pubkey,
+) -> Result<CreditAccount, BandwidthPrepayError> {
+    if keyed_account.account.owner != id() {
+        Err(BandwidthPrepayError::InvalidCreditAccount)?
+    }
+    let credit_account = CreditAccount::deserialize(&keyed_account.account.data)?;
+    if &credit_account.mint != mint {
+        Err(BandwidthPrepayError::InvalidCreditAccount)?
+    }
+    Ok(credit_account)
+}
+
+/// Credit `amount` to `keyed_account`, in credits if the contract escrows them
+fn deposit(
+    keyed_account: &mut KeyedAccount,
+    state: &BandwidthPrepayState,
+    amount: u64,
+) -> Result<(), BandwidthPrepayError> {
+    if state.credits {
+        let mut credit_account = credit_account(keyed_account, &state.credit_mint)?;
+        credit_account.amount = credit_account
+            .amount
+            .checked_add(amount)
+            .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+        credit_account.serialize(&mut keyed_account.account.data)
+    } else {
+        keyed_account.account.lamports += amount;
+        Ok(())
+    }
+}
+
+/// Empty the contract's escrow, returning the amount withdrawn
+fn withdraw_all(keyed_account: &mut KeyedAccount, state: &mut BandwidthPrepayState) -> u64 {
+    if state.credits {
+        let balance = state.credit_balance;
+        state.credit_balance = 0;
+        balance
+    } else {
+        let balance = keyed_account.account.lamports;
+        keyed_account.account.lamports = 0;
+        balance
+    }
+}
+
+/// Pay `amount` out of the contract, crediting each payee its cut and the provider the rest.
+/// `payee_account_indexes` holds the account of each of the contract's payees, in order.
+fn pay_out(
+    keyed_accounts: &mut [KeyedAccount],
+    state: &mut BandwidthPrepayState,
+    amount: u64,
+    contract_account_index: usize,
+    provider_account_index: usize,
+    payee_account_indexes: &[usize],
+) -> Result<(), BandwidthPrepayError> {
+    if payee_account_indexes.len() != state.payees.len() {
+        Err(BandwidthPrepayError::NoPayeeAccount)?
+    }
+    for (payee, &index) in state.payees.iter().zip(payee_account_indexes) {
+        match keyed_accounts.get(index) {
+            Some(keyed_account) if keyed_account.unsigned_key() == &payee.id => (),
+            _ => Err(BandwidthPrepayError::NoPayeeAccount)?,
+        }
+    }
+
+    let cuts = state.payee_cuts(amount);
+    if state.credits {
+        state.credit_balance -= amount;
+    } else {
+        keyed_accounts[contract_account_index].account.lamports -= amount;
+    }
+    let provider_amount = amount - cuts.iter().sum::<u64>();
+    deposit(
+        &mut keyed_accounts[provider_account_index],
+        state,
+        provider_amount,
+    )?;
+    for (&index, cut) in payee_account_indexes.iter().zip(cuts) {
+        deposit(&mut keyed_accounts[index], state, cut)?;
+    }
+    Ok(())
+}
+
+/// Payee accounts expected at consecutive indexes from `first_payee_account_index`
+fn consecutive_payee_accounts(
+    state: &BandwidthPrepayState,
+    first_payee_account_index: usize,
+) -> Vec<usize> {
+    (first_payee_account_index..)
+        .take(state.payees.len())
+        .collect()
+}
+
+fn initialize_account(
+    keyed_accounts: &mut [KeyedAccount],
+    terms: ContractTerms,
+) -> Result<(), BandwidthPrepayError> {
+    let clock_account_index = 4;
+    let initiator_credit_account_index = 5;
+    check_account_count(keyed_accounts, 4)?;
+    let initiator_id = *keyed_accounts[0]
+        .signer_key()
+        .ok_or(BandwidthPrepayError::NotSignedByInitiator)?;
+    if keyed_accounts[1].account.owner != id() {
+        Err(BandwidthPrepayError::NotProgramOwned)?
+    }
     if let Ok(state) = BandwidthPrepayState::deserialize(&keyed_accounts[1].account.data) {
         if state != BandwidthPrepayState::default() {
             Err(BandwidthPrepayError::AlreadyInitialized)?
         }
     }
+    let derived_address = contract_address(
+        &initiator_id,
+        keyed_accounts[2].unsigned_key(),
+        terms.session_nonce,
+    );
+    if keyed_accounts[1].unsigned_key() != &derived_address {
+        Err(BandwidthPrepayError::NotDerivedAddress)?
+    }
+    let split_basis_points: u32 = terms
+        .payees
+        .iter()
+        .map(|payee| u32::from(payee.basis_points))
+        .sum();
+    if terms.payees.len() > MAX_PAYEES || split_basis_points > u32::from(MAX_BASIS_POINTS) {
+        Err(BandwidthPrepayError::InvalidRevenueSplit)?
+    }
+    // Spends pass the provider and every payee, which must all be distinct accounts
+    let provider_id = keyed_accounts[3].unsigned_key();
+    for (i, payee) in terms.payees.iter().enumerate() {
+        if &payee.id == provider_id || terms.payees[..i].iter().any(|other| other.id == payee.id) {
+            Err(BandwidthPrepayError::InvalidRevenueSplit)?
+        }
+    }
+    if !terms.hops.is_empty() {
+        let hop_basis_points: u32 = terms
+            .hops
+            .iter()
+            .map(|hop| u32::from(hop.basis_points))
+            .sum();
+        // Hops charge straight to their own providers, so they cannot share
+        // charges with payees, settle through vouchers or receipts, or pay in credits
+        if terms.hops.len() > MAX_HOPS
+            || hop_basis_points > u32::from(MAX_BASIS_POINTS)
+            || &terms.hops[0].gatekeeper_id != keyed_accounts[2].unsigned_key()
+            || &terms.hops[0].provider_id != keyed_accounts[3].unsigned_key()
+            || !terms.payees.is_empty()
+            || terms.receipts_required
+            || terms.channel_dispute_window.is_some()
+            || terms.credit_mint.is_some()
+        {
+            Err(BandwidthPrepayError::InvalidHops)?
+        }
+        for (i, hop) in terms.hops.iter().enumerate() {
+            if terms.hops[..i]
+                .iter()
+                .any(|other| other.gatekeeper_id == hop.gatekeeper_id)
+            {
+                Err(BandwidthPrepayError::InvalidHops)?
+            }
+        }
+    }
+    let credit_refund_id = match terms.credit_mint {
+        Some(mint) => {
+            // Channels and expiry pay the initiator directly, which only works in lamports
+            if terms.channel_dispute_window.is_some() || terms.expiry_timeout.is_some() {
+                Err(BandwidthPrepayError::CreditsUnsupported)?
+            }
+            credit_account(&keyed_accounts[3], &mint)?;
+            let refund_account = keyed_accounts
+                .get(initiator_credit_account_index)
+                .ok_or(BandwidthPrepayError::InvalidCreditAccount)?;
+            if credit_account(refund_account, &mint)?.owner != initiator_id {
+                Err(BandwidthPrepayError::InvalidCreditAccount)?
+            }
+            *refund_account.unsigned_key()
+        }
+        None => Pubkey::default(),
+    };
+    let expiry_slot = match terms.expiry_timeout {
+        Some(timeout) => {
+            let clock_account = keyed_accounts
+                .get(clock_account_index)
+                .ok_or(BandwidthPrepayError::NoClockAccount)?;
+            current_slot(clock_account)?.saturating_add(timeout)
+        }
+        None => 0,
+    };
     let state = BandwidthPrepayState {
-        initiator_id: *keyed_accounts[0].signer_key().unwrap(),
+        initiator_id,
         gatekeeper_id: *keyed_accounts[2].unsigned_key(),
         provider_id: *keyed_accounts[3].unsigned_key(),
+        tariff: terms.tariff,
+        receipts_required: terms.receipts_required,
+        payment_channel: terms.channel_dispute_window.is_some(),
+        dispute_window: terms.channel_dispute_window.unwrap_or(0),
+        expires: terms.expiry_timeout.is_some(),
+        expiry_slot,
+        total_deposited: if terms.credit_mint.is_some() {
+            0
+        } else {
+            keyed_accounts[1].account.lamports
+        },
+        payees: terms.payees,
+        credits: terms.credit_mint.is_some(),
+        credit_mint: terms.credit_mint.unwrap_or_default(),
+        credit_refund_id,
+        spending_capped: terms.spending_cap.is_some(),
+        spending_cap: terms.spending_cap.unwrap_or_default(),
+        hops: terms
+            .hops
+            .into_iter()
+            .map(|hop| Hop {
+                bytes_reported: 0,
+                ..hop
+            })
+            .collect(),
+        origin_gatekeeper_id: *keyed_accounts[2].unsigned_key(),
+        ..BandwidthPrepayState::default()
     };
     state.serialize(&mut keyed_accounts[1].account.data)
 }
 
-fn spend(keyed_accounts: &mut [KeyedAccount], amount: u64) -> Result<(), BandwidthPrepayError> {
+fn spend(
+    keyed_accounts: &mut [KeyedAccount],
+    data_amount: u64,
+    receipt: Option<&UsageReceipt>,
+) -> Result<(), BandwidthPrepayError> {
     let gatekeeper_account_index = 0;
     let contract_account_index = 1;
     let provider_account_index = 2;
-    let state =
-        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    let clock_account_index = 3;
+    let first_payee_account_index = 4;
+    check_account_count(keyed_accounts, 4)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
 
-    if let Some(gatekeeper_pubkey) = keyed_accounts[gatekeeper_account_index].signer_key() {
-        if gatekeeper_pubkey != &state.gatekeeper_id {
-            Err(BandwidthPrepayError::NoGatekeeperAccount)?
-        }
-    } else {
-        Err(BandwidthPrepayError::NotSignedByGatekeeper)?
+    let hop = charging_hop(&keyed_accounts[gatekeeper_account_index], &state)?;
+    let usage = Usage {
+        data_amount,
+        receipt,
+        slot: current_slot(&keyed_accounts[clock_account_index])?,
+    };
+    let payee_account_indexes = consecutive_payee_accounts(&state, first_payee_account_index);
+    charge(
+        keyed_accounts,
+        &mut state,
+        hop,
+        contract_account_index,
+        provider_account_index,
+        &payee_account_indexes,
+        &usage,
+    )?;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+/// Bytes a gatekeeper asks to be paid for, at `slot`
+struct Usage<'a> {
+    data_amount: u64,
+    receipt: Option<&'a UsageReceipt>,
+    slot: u64,
+}
+
+/// Hop of a multi-hop contract that the signing gatekeeper charges for; None
+/// for single-gatekeeper contracts
+fn charging_hop(
+    keyed_account: &KeyedAccount,
+    state: &BandwidthPrepayState,
+) -> Result<Option<usize>, BandwidthPrepayError> {
+    if state.hops.is_empty() {
+        verify_gatekeeper(keyed_account, state)?;
+        return Ok(None);
     }
-    if keyed_accounts[provider_account_index].unsigned_key() != &state.provider_id {
+    let signer = keyed_account
+        .signer_key()
+        .ok_or(BandwidthPrepayError::NotSignedByGatekeeper)?;
+    if state.is_gatekeeper(signer) {
+        return Ok(Some(0));
+    }
+    let hop = state
+        .hop_index(signer)
+        .ok_or(BandwidthPrepayError::NoGatekeeperAccount)?;
+    Ok(Some(hop))
+}
+
+/// Charge one contract for `usage`, updating `state` but not writing it back
+fn charge(
+    keyed_accounts: &mut [KeyedAccount],
+    state: &mut BandwidthPrepayState,
+    hop: Option<usize>,
+    contract_account_index: usize,
+    provider_account_index: usize,
+    payee_account_indexes: &[usize],
+    usage: &Usage,
+) -> Result<(), BandwidthPrepayError> {
+    if state.payment_channel {
+        Err(BandwidthPrepayError::PaymentChannelOnly)?
+    }
+    let (provider_id, bytes_reported) = match hop {
+        Some(hop) => (state.hops[hop].provider_id, state.hops[hop].bytes_reported),
+        None => (state.provider_id, state.bytes_reported),
+    };
+    if keyed_accounts[provider_account_index].unsigned_key() != &provider_id {
         Err(BandwidthPrepayError::NoProviderAccount)?
     }
-    if keyed_accounts[contract_account_index].account.lamports < amount {
+    // Priced on the running total, so fractions of a lamport carry over
+    let amount = match hop {
+        Some(hop) => state.tariff.share_after(
+            state.hops[hop].basis_points,
+            bytes_reported,
+            usage.data_amount,
+        ),
+        None => state.tariff.charge_after(bytes_reported, usage.data_amount),
+    }
+    .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+    let bytes_reported = bytes_reported
+        .checked_add(usage.data_amount)
+        .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+    match usage.receipt {
+        Some(receipt) => {
+            if &receipt.contract_id != keyed_accounts[contract_account_index].unsigned_key()
+                || receipt.nonce < state.receipt_nonce
+                || !receipt.verify(&state.initiator_id)
+            {
+                Err(BandwidthPrepayError::InvalidReceipt)?
+            }
+            if bytes_reported > receipt.cumulative_bytes {
+                Err(BandwidthPrepayError::UnacknowledgedUsage)?
+            }
+            state.receipt_nonce = receipt.nonce;
+        }
+        None => {
+            if state.receipts_required {
+                Err(BandwidthPrepayError::ReceiptRequired)?
+            }
+        }
+    }
+    if state.balance(keyed_accounts[contract_account_index].account.lamports) < amount {
         Err(BandwidthPrepayError::BalanceTooLow)?
     }
+    if !state.within_spending_cap(amount, usage.slot) {
+        Err(BandwidthPrepayError::RateLimitExceeded)?
+    }
+
+    pay_out(
+        keyed_accounts,
+        state,
+        amount,
+        contract_account_index,
+        provider_account_index,
+        payee_account_indexes,
+    )?;
+
+    match hop {
+        Some(hop) => state.hops[hop].bytes_reported = bytes_reported,
+        None => state.bytes_reported = bytes_reported,
+    }
+    state.record_spend(amount, usage.slot);
+    Ok(())
+}
+
+/// Settle one charge per contract, all paying the same provider. Payee accounts
+/// follow the contracts, each listed once however many contracts share it.
+fn spend_many(
+    keyed_accounts: &mut [KeyedAccount],
+    charges: &[BatchCharge],
+) -> Result<(), BandwidthPrepayError> {
+    let gatekeeper_account_index = 0;
+    let provider_account_index = 1;
+    let clock_account_index = 2;
+    let first_contract_account_index = 3;
+    let first_payee_account_index = first_contract_account_index + charges.len();
+    check_account_count(keyed_accounts, first_payee_account_index)?;
+    let slot = current_slot(&keyed_accounts[clock_account_index])?;
 
-    keyed_accounts[contract_account_index].account.lamports -= amount;
-    keyed_accounts[provider_account_index].account.lamports += amount;
+    for (i, batch_charge) in charges.iter().enumerate() {
+        let contract_account_index = first_contract_account_index + i;
+        let mut state = contract_state(&keyed_accounts[contract_account_index])?;
 
+        let hop = charging_hop(&keyed_accounts[gatekeeper_account_index], &state)?;
+        let payee_account_indexes = state
+            .payees
+            .iter()
+            .map(|payee| {
+                (first_payee_account_index..keyed_accounts.len())
+                    .find(|&index| keyed_accounts[index].unsigned_key() == &payee.id)
+                    .ok_or(BandwidthPrepayError::NoPayeeAccount)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let usage = Usage {
+            data_amount: batch_charge.data_amount,
+            receipt: batch_charge.receipt.as_ref(),
+            slot,
+        };
+        charge(
+            keyed_accounts,
+            &mut state,
+            hop,
+            contract_account_index,
+            provider_account_index,
+            &payee_account_indexes,
+            &usage,
+        )?;
+        state.serialize(&mut keyed_accounts[contract_account_index].account.data)?;
+    }
     Ok(())
 }
 
@@ -50,38 +511,435 @@ fn refund(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayErro
     let gatekeeper_account_index = 0;
     let contract_account_index = 1;
     let initiator_account_index = 2;
-    let state =
-        BandwidthPrepayState::deserialize(&keyed_accounts[contract_account_index].account.data)?;
+    check_account_count(keyed_accounts, 3)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
 
-    if let Some(gatekeeper_pubkey) = keyed_accounts[gatekeeper_account_index].signer_key() {
-        if gatekeeper_pubkey != &state.gatekeeper_id {
-            Err(BandwidthPrepayError::NoGatekeeperAccount)?
-        }
-    } else {
-        Err(BandwidthPrepayError::NotSignedByGatekeeper)?
+    verify_gatekeeper(&keyed_accounts[gatekeeper_account_index], &state)?;
+    if keyed_accounts[initiator_account_index].unsigned_key() != state.refund_id() {
+        Err(BandwidthPrepayError::NoInitiatorAccount)?
+    }
+
+    let balance = withdraw_all(&mut keyed_accounts[contract_account_index], &mut state);
+    deposit(
+        &mut keyed_accounts[initiator_account_index],
+        &state,
+        balance,
+    )?;
+
+    state.total_withdrawn += balance;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+fn settle_channel(
+    keyed_accounts: &mut [KeyedAccount],
+    voucher: &Voucher,
+) -> Result<(), BandwidthPrepayError> {
+    let gatekeeper_account_index = 0;
+    let contract_account_index = 1;
+    let provider_account_index = 2;
+    let initiator_account_index = 3;
+    let clock_account_index = 4;
+    let first_payee_account_index = 5;
+    check_account_count(keyed_accounts, 5)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_gatekeeper(&keyed_accounts[gatekeeper_account_index], &state)?;
+    if !state.payment_channel {
+        Err(BandwidthPrepayError::NotPaymentChannel)?
+    }
+    if keyed_accounts[provider_account_index].unsigned_key() != &state.provider_id {
+        Err(BandwidthPrepayError::NoProviderAccount)?
     }
     if keyed_accounts[initiator_account_index].unsigned_key() != &state.initiator_id {
         Err(BandwidthPrepayError::NoInitiatorAccount)?
     }
+    if &voucher.contract_id != keyed_accounts[contract_account_index].unsigned_key()
+        || !voucher.verify(&state.initiator_id)
+    {
+        Err(BandwidthPrepayError::InvalidVoucher)?
+    }
+
+    let amount = cmp::min(
+        voucher.amount,
+        keyed_accounts[contract_account_index].account.lamports,
+    );
+    let payee_account_indexes = consecutive_payee_accounts(&state, first_payee_account_index);
+    pay_out(
+        keyed_accounts,
+        &mut state,
+        amount,
+        contract_account_index,
+        provider_account_index,
+        &payee_account_indexes,
+    )?;
+    let slot = current_slot(&keyed_accounts[clock_account_index])?;
+    state.record_spend(amount, slot);
+
+    let balance = keyed_accounts[contract_account_index].account.lamports;
+    keyed_accounts[initiator_account_index].account.lamports += balance;
+    keyed_accounts[contract_account_index].account.lamports = 0;
+
+    state.total_withdrawn += balance;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+fn request_channel_close(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let initiator_account_index = 0;
+    let contract_account_index = 1;
+    let clock_account_index = 2;
+    check_account_count(keyed_accounts, 3)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_initiator(&keyed_accounts[initiator_account_index], &state)?;
+    if !state.payment_channel {
+        Err(BandwidthPrepayError::NotPaymentChannel)?
+    }
+    if state.close_requested {
+        return Ok(());
+    }
+
+    state.close_requested = true;
+    state.close_requested_slot = current_slot(&keyed_accounts[clock_account_index])?;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+fn reclaim_channel(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let initiator_account_index = 0;
+    let contract_account_index = 1;
+    let clock_account_index = 2;
+    check_account_count(keyed_accounts, 3)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_initiator(&keyed_accounts[initiator_account_index], &state)?;
+    if !state.payment_channel {
+        Err(BandwidthPrepayError::NotPaymentChannel)?
+    }
+    if !state.close_requested {
+        Err(BandwidthPrepayError::CloseNotRequested)?
+    }
+    let slot = current_slot(&keyed_accounts[clock_account_index])?;
+    if slot
+        < state
+            .close_requested_slot
+            .saturating_add(state.dispute_window)
+    {
+        Err(BandwidthPrepayError::DisputeWindowOpen)?
+    }
+
+    let balance = keyed_accounts[contract_account_index].account.lamports;
+    keyed_accounts[initiator_account_index].account.lamports += balance;
+    keyed_accounts[contract_account_index].account.lamports = 0;
+
+    state.total_withdrawn += balance;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+fn reclaim_expired(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let initiator_account_index = 0;
+    let contract_account_index = 1;
+    let clock_account_index = 2;
+    check_account_count(keyed_accounts, 3)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_initiator(&keyed_accounts[initiator_account_index], &state)?;
+    let slot = current_slot(&keyed_accounts[clock_account_index])?;
+    if !state.expires || slot < state.expiry_slot {
+        Err(BandwidthPrepayError::NotExpired)?
+    }
 
-    keyed_accounts[initiator_account_index].account.lamports +=
-        keyed_accounts[contract_account_index].account.lamports;
+    let balance = keyed_accounts[contract_account_index].account.lamports;
+    keyed_accounts[initiator_account_index].account.lamports += balance;
     keyed_accounts[contract_account_index].account.lamports = 0;
 
+    state.total_withdrawn += balance;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+fn top_up(keyed_accounts: &mut [KeyedAccount], lamports: u64) -> Result<(), BandwidthPrepayError> {
+    let initiator_account_index = 0;
+    let contract_account_index = 1;
+    check_account_count(keyed_accounts, 2)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_initiator(&keyed_accounts[initiator_account_index], &state)?;
+    if state.credits {
+        Err(BandwidthPrepayError::CreditsUnsupported)?
+    }
+    let total_deposited = state
+        .total_deposited
+        .checked_add(lamports)
+        .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+    // The transfer preceding this instruction must already have landed
+    let expected_balance = state
+        .expected_balance()
+        .checked_add(lamports)
+        .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+    if keyed_accounts[contract_account_index].account.lamports < expected_balance {
+        Err(BandwidthPrepayError::TopUpNotReceived)?
+    }
+
+    state.total_deposited = total_deposited;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+fn close(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let gatekeeper_account_index = 0;
+    let contract_account_index = 1;
+    let initiator_account_index = 2;
+    check_account_count(keyed_accounts, 3)?;
+    let state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_gatekeeper(&keyed_accounts[gatekeeper_account_index], &state)?;
+    if keyed_accounts[initiator_account_index].unsigned_key() != &state.initiator_id {
+        Err(BandwidthPrepayError::NoInitiatorAccount)?
+    }
+    if state.credit_balance > 0 {
+        Err(BandwidthPrepayError::CreditsNotRefunded)?
+    }
+
+    // The reserve stays behind and counts toward the next session's deposit
+    let lamports = keyed_accounts[contract_account_index].account.lamports;
+    let reserve = cmp::min(lamports, CLOSED_CONTRACT_RESERVE);
+    keyed_accounts[initiator_account_index].account.lamports += lamports - reserve;
+    keyed_accounts[contract_account_index].account.lamports = reserve;
+
+    // A zeroed account deserializes to the default state, which InitializeAccount accepts
+    for byte in keyed_accounts[contract_account_index]
+        .account
+        .data
+        .iter_mut()
+    {
+        *byte = 0;
+    }
     Ok(())
 }
 
+fn initialize_credit_account(
+    keyed_accounts: &mut [KeyedAccount],
+    mint: Pubkey,
+) -> Result<(), BandwidthPrepayError> {
+    let owner_account_index = 0;
+    let credit_account_index = 1;
+    check_account_count(keyed_accounts, 2)?;
+    if let Ok(credit_account) =
+        CreditAccount::deserialize(&keyed_accounts[credit_account_index].account.data)
+    {
+        if credit_account != CreditAccount::default() {
+            Err(BandwidthPrepayError::AlreadyInitialized)?
+        }
+    }
+    let credit_account = CreditAccount {
+        mint,
+        owner: *keyed_accounts[owner_account_index].unsigned_key(),
+        amount: 0,
+    };
+    credit_account.serialize(&mut keyed_accounts[credit_account_index].account.data)
+}
+
+fn mint_credits(
+    keyed_accounts: &mut [KeyedAccount],
+    amount: u64,
+) -> Result<(), BandwidthPrepayError> {
+    let mint_account_index = 0;
+    let credit_account_index = 1;
+    check_account_count(keyed_accounts, 2)?;
+    let mint = *keyed_accounts[mint_account_index]
+        .signer_key()
+        .ok_or(BandwidthPrepayError::InvalidCreditAccount)?;
+    let mut credit_account = credit_account(&keyed_accounts[credit_account_index], &mint)?;
+
+    credit_account.amount = credit_account
+        .amount
+        .checked_add(amount)
+        .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+    credit_account.serialize(&mut keyed_accounts[credit_account_index].account.data)
+}
+
+fn transfer_credits(
+    keyed_accounts: &mut [KeyedAccount],
+    amount: u64,
+) -> Result<(), BandwidthPrepayError> {
+    let owner_account_index = 0;
+    let from_account_index = 1;
+    let to_account_index = 2;
+    check_account_count(keyed_accounts, 3)?;
+    let mint = CreditAccount::deserialize(&keyed_accounts[from_account_index].account.data)?.mint;
+    let mut from = credit_account(&keyed_accounts[from_account_index], &mint)?;
+    let mut to = credit_account(&keyed_accounts[to_account_index], &mint)?;
+
+    if keyed_accounts[owner_account_index].signer_key() != Some(&from.owner) {
+        Err(BandwidthPrepayError::InvalidCreditAccount)?
+    }
+    if from.amount < amount {
+        Err(BandwidthPrepayError::BalanceTooLow)?
+    }
+
+    from.amount -= amount;
+    to.amount = to
+        .amount
+        .checked_add(amount)
+        .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+    from.serialize(&mut keyed_accounts[from_account_index].account.data)?;
+    to.serialize(&mut keyed_accounts[to_account_index].account.data)
+}
+
+fn deposit_credits(
+    keyed_accounts: &mut [KeyedAccount],
+    amount: u64,
+) -> Result<(), BandwidthPrepayError> {
+    let initiator_account_index = 0;
+    let initiator_credit_account_index = 1;
+    let contract_account_index = 2;
+    check_account_count(keyed_accounts, 3)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_initiator(&keyed_accounts[initiator_account_index], &state)?;
+    if !state.credits {
+        Err(BandwidthPrepayError::NotCreditContract)?
+    }
+    let mut from = credit_account(
+        &keyed_accounts[initiator_credit_account_index],
+        &state.credit_mint,
+    )?;
+    if from.owner != state.initiator_id {
+        Err(BandwidthPrepayError::InvalidCreditAccount)?
+    }
+    if from.amount < amount {
+        Err(BandwidthPrepayError::BalanceTooLow)?
+    }
+
+    from.amount -= amount;
+    from.serialize(&mut keyed_accounts[initiator_credit_account_index].account.data)?;
+    state.credit_balance = state
+        .credit_balance
+        .checked_add(amount)
+        .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+    state.total_deposited = state
+        .total_deposited
+        .checked_add(amount)
+        .ok_or(BandwidthPrepayError::ChargeOverflow)?;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+fn migrate(keyed_accounts: &mut [KeyedAccount]) -> Result<(), BandwidthPrepayError> {
+    let authority_account_index = 0;
+    let contract_account_index = 1;
+    check_account_count(keyed_accounts, 2)?;
+    let state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    // A closed contract has no parties left to protect
+    if state != BandwidthPrepayState::default() {
+        match keyed_accounts[authority_account_index].signer_key() {
+            Some(authority)
+                if authority == &state.initiator_id || state.is_gatekeeper(authority) => {}
+            _ => Err(BandwidthPrepayError::NotContractParty)?,
+        }
+    }
+    let contract_account = &mut keyed_accounts[contract_account_index].account;
+    if !BandwidthPrepayState::needs_migration(&contract_account.data) {
+        return Ok(());
+    }
+
+    // Programs can't resize accounts, so the current layout has to fit in the
+    // space the contract was created with. Contracts it doesn't fit in are
+    // still served, in the layout they hold.
+    state.serialize_current(&mut contract_account.data)
+}
+
+fn set_gatekeeper(
+    keyed_accounts: &mut [KeyedAccount],
+    gatekeeper_id: Pubkey,
+) -> Result<(), BandwidthPrepayError> {
+    let initiator_account_index = 0;
+    let gatekeeper_account_index = 1;
+    let contract_account_index = 2;
+    check_account_count(keyed_accounts, 3)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_initiator(&keyed_accounts[initiator_account_index], &state)?;
+    verify_gatekeeper_key(&keyed_accounts[gatekeeper_account_index], &state)?;
+    if state
+        .hops
+        .iter()
+        .skip(1)
+        .any(|hop| hop.gatekeeper_id == gatekeeper_id)
+    {
+        Err(BandwidthPrepayError::InvalidHops)?
+    }
+
+    // The contract stays at the address derived for the gatekeeper it was
+    // opened with, which contracts from before it was recorded take to be this one
+    state.origin_gatekeeper_id = *state.address_gatekeeper_id();
+    // Delegates belong to the outgoing key
+    state.gatekeeper_id = gatekeeper_id;
+    state.delegates.clear();
+    if let Some(first_hop) = state.hops.first_mut() {
+        first_hop.gatekeeper_id = gatekeeper_id;
+    }
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
+fn set_delegates(
+    keyed_accounts: &mut [KeyedAccount],
+    delegates: Vec<Pubkey>,
+) -> Result<(), BandwidthPrepayError> {
+    let gatekeeper_account_index = 0;
+    let contract_account_index = 1;
+    check_account_count(keyed_accounts, 2)?;
+    let mut state = contract_state(&keyed_accounts[contract_account_index])?;
+
+    verify_gatekeeper_key(&keyed_accounts[gatekeeper_account_index], &state)?;
+    if delegates.len() > MAX_DELEGATES {
+        Err(BandwidthPrepayError::TooManyDelegates)?
+    }
+
+    state.delegates = delegates;
+    state.serialize(&mut keyed_accounts[contract_account_index].account.data)
+}
+
 pub fn process_instruction(
     _program_id: &Pubkey,
     keyed_accounts: &mut [KeyedAccount],
     data: &[u8],
 ) -> Result<(), InstructionError> {
     let instruction = deserialize(data).map_err(|_| InstructionError::InvalidInstructionData)?;
+    check_distinct_accounts(keyed_accounts).map_err(|e| InstructionError::CustomError(e as u32))?;
 
     match instruction {
-        BandwidthPrepayInstruction::InitializeAccount => initialize_account(keyed_accounts),
-        BandwidthPrepayInstruction::Spend(amount) => spend(keyed_accounts, amount),
+        BandwidthPrepayInstruction::InitializeAccount(terms) => {
+            initialize_account(keyed_accounts, terms)
+        }
+        BandwidthPrepayInstruction::Spend(data_amount) => spend(keyed_accounts, data_amount, None),
         BandwidthPrepayInstruction::Refund => refund(keyed_accounts),
+        BandwidthPrepayInstruction::SpendWithReceipt(data_amount, receipt) => {
+            spend(keyed_accounts, data_amount, Some(&receipt))
+        }
+        BandwidthPrepayInstruction::SettleChannel(voucher) => {
+            settle_channel(keyed_accounts, &voucher)
+        }
+        BandwidthPrepayInstruction::RequestChannelClose => request_channel_close(keyed_accounts),
+        BandwidthPrepayInstruction::ReclaimChannel => reclaim_channel(keyed_accounts),
+        BandwidthPrepayInstruction::ReclaimExpired => reclaim_expired(keyed_accounts),
+        BandwidthPrepayInstruction::TopUp(lamports) => top_up(keyed_accounts, lamports),
+        BandwidthPrepayInstruction::Close => close(keyed_accounts),
+        BandwidthPrepayInstruction::InitializeCreditAccount(mint) => {
+            initialize_credit_account(keyed_accounts, mint)
+        }
+        BandwidthPrepayInstruction::MintCredits(amount) => mint_credits(keyed_accounts, amount),
+        BandwidthPrepayInstruction::TransferCredits(amount) => {
+            transfer_credits(keyed_accounts, amount)
+        }
+        BandwidthPrepayInstruction::DepositCredits(amount) => {
+            deposit_credits(keyed_accounts, amount)
+        }
+        BandwidthPrepayInstruction::Migrate => migrate(keyed_accounts),
+        BandwidthPrepayInstruction::SetGatekeeper(gatekeeper_id) => {
+            set_gatekeeper(keyed_accounts, gatekeeper_id)
+        }
+        BandwidthPrepayInstruction::SetDelegates(delegates) => {
+            set_delegates(keyed_accounts, delegates)
+        }
+        BandwidthPrepayInstruction::SpendMany(charges) => spend_many(keyed_accounts, &charges),
     }
     .map_err(|e| InstructionError::CustomError(e as u32))
 }
@@ -90,14 +948,46 @@ pub fn process_instruction(
 mod tests {
     use super::*;
     use crate::bandwidth_prepay_instruction;
+    use crate::bandwidth_prepay_state::{
+        BandwidthPrepayStateV0, BandwidthPrepayStateV1, Payee, SpendingCap, Tariff,
+        VersionedBandwidthPrepayState, V0_STATE_SIZE,
+    };
     use crate::id;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaChaRng;
     use solana_runtime::bank::Bank;
     use solana_runtime::bank_client::BankClient;
+    use solana_sdk::account::Account;
     use solana_sdk::client::SyncClient;
     use solana_sdk::genesis_block::create_genesis_block;
+    use solana_sdk::hash::hash;
+    use solana_sdk::instruction::Instruction;
     use solana_sdk::message::Message;
     use solana_sdk::signature::{Keypair, KeypairUtil};
     use solana_sdk::system_instruction;
+    use solana_sdk::transaction::TransactionError;
+    use solana_sdk::transport::TransportError;
+    use std::sync::Arc;
+
+    const TARIFF: Tariff = Tariff {
+        lamports_per_kib: 1,
+        minimum_charge: 0,
+    };
+
+    fn terms() -> ContractTerms {
+        ContractTerms {
+            tariff: TARIFF,
+            ..ContractTerms::default()
+        }
+    }
+
+    fn instruction_error(err: TransportError) -> InstructionError {
+        match err {
+            TransportError::TransactionError(TransactionError::InstructionError(_, err)) => err,
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
 
     fn create_bank(lamports: u64) -> (Bank, Keypair) {
         let (genesis_block, mint_keypair) = create_genesis_block(lamports);
@@ -106,21 +996,51 @@ mod tests {
         (bank, mint_keypair)
     }
 
+    // Freeze `bank` and return a child one slot later, with a fresh blockhash
+    fn next_slot(bank: &Arc<Bank>) -> Arc<Bank> {
+        let slot = bank.slot() + 1;
+        let bank = Bank::new_from_parent(bank, &Pubkey::default(), slot);
+        for tick in 0..bank.ticks_per_slot() {
+            bank.register_tick(&hash(&[slot as u8, tick as u8]));
+        }
+        Arc::new(bank)
+    }
+
     #[test]
     fn test_bandwidth_prepay_initialize() {
         let (bank, alice_keypair) = create_bank(10_000);
         let bank_client = BankClient::new(bank);
 
         let alice_pubkey = alice_keypair.pubkey();
-        let contract = Keypair::new().pubkey();
         let gatekeeper = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper, 0);
         let provider = Keypair::new().pubkey();
 
+        // The contract must live at the address derived for this session
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract_address(&alice_pubkey, &gatekeeper, 1),
+            &gatekeeper,
+            &provider,
+            terms(),
+            500,
+        );
+        let message = Message::new(instructions);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NotDerivedAddress as u32)
+        );
+
         let instructions = bandwidth_prepay_instruction::initialize(
             &alice_pubkey,
             &contract,
             &gatekeeper,
             &provider,
+            terms(),
             500,
         );
         let message = Message::new(instructions);
@@ -135,6 +1055,7 @@ mod tests {
         assert_eq!(state.gatekeeper_id, gatekeeper);
         assert_eq!(state.provider_id, provider);
         assert_eq!(state.initiator_id, alice_pubkey);
+        assert_eq!(state.tariff, TARIFF);
     }
 
     #[test]
@@ -143,9 +1064,9 @@ mod tests {
         let bank_client = BankClient::new(bank);
 
         let alice_pubkey = alice_keypair.pubkey();
-        let contract = Keypair::new().pubkey();
         let provider = Keypair::new().pubkey();
         let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
 
         // Initialize contract
         let instructions = bandwidth_prepay_instruction::initialize(
@@ -153,6 +1074,7 @@ mod tests {
             &contract,
             &gatekeeper.pubkey(),
             &provider,
+            terms(),
             500,
         );
         let message = Message::new(instructions);
@@ -168,23 +1090,399 @@ mod tests {
             .unwrap();
         assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 1);
 
-        let instruction =
-            bandwidth_prepay_instruction::spend(&gatekeeper.pubkey(), &contract, &provider, 100);
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            100 * 1024,
+        );
         let message = Message::new(vec![instruction]);
         bank_client.send_message(&[&gatekeeper], message).unwrap();
         assert_eq!(bank_client.get_balance(&contract).unwrap(), 400);
         assert_eq!(bank_client.get_balance(&provider).unwrap(), 100);
-    }
 
-    #[test]
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            50 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.bytes_reported, 150 * 1024);
+        assert_eq!(state.total_spent, 150);
+        assert_eq!(state.spend_count, 2);
+        assert_eq!(state.last_spend_slot, 0);
+
+        // Charges above the remaining balance are rejected
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            351 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 350);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.spend_count, 2);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_carries_fractions() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let mut instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            terms(),
+            500,
+        );
+        instructions.push(system_instruction::transfer(
+            &alice_pubkey,
+            &gatekeeper.pubkey(),
+            1,
+        ));
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Half a KiB is worth nothing on its own, but is paid for once the
+        // running total passes a KiB
+        for (data_amount, paid) in &[(512, 0), (768, 1), (256, 1)] {
+            let instruction = bandwidth_prepay_instruction::spend(
+                &gatekeeper.pubkey(),
+                &contract,
+                &provider,
+                &[],
+                *data_amount,
+            );
+            let message = Message::new(vec![instruction]);
+            bank_client.send_message(&[&gatekeeper], message).unwrap();
+            assert_eq!(bank_client.get_balance(&provider).unwrap(), *paid);
+        }
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.bytes_reported, 1536);
+        assert_eq!(state.total_spent, 1);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spending_cap() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank = Arc::new(bank);
+        let bank_client = BankClient::new_shared(&bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                spending_cap: Some(SpendingCap {
+                    lamports: 150,
+                    slots: 1,
+                }),
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+
+        // The balance covers the charge, but the window's cap does not
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            60 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::RateLimitExceeded as u32)
+        );
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 400);
+
+        let bank = next_slot(&bank);
+        let bank_client = BankClient::new_shared(&bank);
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            60 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 340);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 160);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_multi_hop() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeepers = [Keypair::new(), Keypair::new()];
+        let providers = [Keypair::new().pubkey(), Keypair::new().pubkey()];
+        let contract = contract_address(&alice_pubkey, &gatekeepers[0].pubkey(), 0);
+        let hops: Vec<_> = gatekeepers
+            .iter()
+            .zip(providers.iter())
+            .zip([6_000, 4_000].iter())
+            .map(|((gatekeeper, provider), basis_points)| Hop {
+                gatekeeper_id: gatekeeper.pubkey(),
+                provider_id: *provider,
+                basis_points: *basis_points,
+                bytes_reported: 0,
+            })
+            .collect();
+
+        // The first hop must be the contract's own gatekeeper
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeepers[0].pubkey(),
+            &providers[0],
+            ContractTerms {
+                hops: hops.iter().rev().cloned().collect(),
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::InvalidHops as u32)
+        );
+
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeepers[0].pubkey(),
+            &providers[0],
+            ContractTerms {
+                hops: hops.clone(),
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper accounts exist
+        let outsider = Keypair::new();
+        for pubkey in &[
+            gatekeepers[0].pubkey(),
+            gatekeepers[1].pubkey(),
+            outsider.pubkey(),
+        ] {
+            let instruction = system_instruction::transfer(&alice_pubkey, pubkey, 1);
+            let message = Message::new(vec![instruction]);
+            bank_client
+                .send_message(&[&alice_keypair], message)
+                .unwrap();
+        }
+
+        // Each hop charges its share of the tariff to its own provider
+        for (gatekeeper, provider) in gatekeepers.iter().zip(providers.iter()) {
+            let instruction = bandwidth_prepay_instruction::spend(
+                &gatekeeper.pubkey(),
+                &contract,
+                provider,
+                &[],
+                100 * 1024,
+            );
+            let message = Message::new(vec![instruction]);
+            bank_client.send_message(&[gatekeeper], message).unwrap();
+        }
+        assert_eq!(bank_client.get_balance(&providers[0]).unwrap(), 60);
+        assert_eq!(bank_client.get_balance(&providers[1]).unwrap(), 40);
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 400);
+
+        // A hop cannot pay another hop's provider
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeepers[1].pubkey(),
+            &contract,
+            &providers[0],
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeepers[1]], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoProviderAccount as u32)
+        );
+
+        // Gatekeepers off the route cannot charge at all
+        let instruction = bandwidth_prepay_instruction::spend(
+            &outsider.pubkey(),
+            &contract,
+            &providers[1],
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(bank_client.send_message(&[&outsider], message).unwrap_err()),
+            InstructionError::CustomError(BandwidthPrepayError::NoGatekeeperAccount as u32)
+        );
+
+        let state = BandwidthPrepayState::deserialize(
+            &bank_client.get_account_data(&contract).unwrap().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(state.hops[0].bytes_reported, 100 * 1024);
+        assert_eq!(state.hops[1].bytes_reported, 100 * 1024);
+        assert_eq!(state.bytes_reported, 0);
+        assert_eq!(state.total_spent, 100);
+
+        // The first hop cannot be handed to a gatekeeper already on the route
+        let instruction = bandwidth_prepay_instruction::set_gatekeeper(
+            &alice_pubkey,
+            &gatekeepers[0].pubkey(),
+            &contract,
+            &gatekeepers[1].pubkey(),
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair, &gatekeepers[0]], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::InvalidHops as u32)
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_minimum_charge() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let tariff = Tariff {
+            lamports_per_kib: 3,
+            minimum_charge: 10,
+        };
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                tariff,
+                ..ContractTerms::default()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            100,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 490);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 10);
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            10 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 460);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 40);
+    }
+
+    #[test]
     fn test_bandwidth_prepay_refund() {
         let (bank, alice_keypair) = create_bank(10_000);
         let bank_client = BankClient::new(bank);
 
         let alice_pubkey = alice_keypair.pubkey();
-        let contract = Keypair::new().pubkey();
         let provider = Keypair::new().pubkey();
         let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
 
         // Initialize contract
         let instructions = bandwidth_prepay_instruction::initialize(
@@ -192,6 +1490,7 @@ mod tests {
             &contract,
             &gatekeeper.pubkey(),
             &provider,
+            terms(),
             500,
         );
         let message = Message::new(instructions);
@@ -215,4 +1514,1929 @@ mod tests {
         assert_eq!(bank_client.get_balance(&provider).unwrap(), 0);
         assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_999);
     }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_with_receipt() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                receipts_required: true,
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Spends without a receipt are rejected
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            10 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::ReceiptRequired as u32)
+        );
+
+        let receipt = UsageReceipt::new(&alice_keypair, &contract, 20 * 1024, 1);
+        let instruction = bandwidth_prepay_instruction::spend_with_receipt(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            10 * 1024,
+            &receipt,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 490);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 10);
+
+        // The receipt only acknowledges 10 more KiB
+        let instruction = bandwidth_prepay_instruction::spend_with_receipt(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            11 * 1024,
+            &receipt,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::UnacknowledgedUsage as u32)
+        );
+
+        // Receipts must be signed by the initiator
+        let forged = UsageReceipt::new(&gatekeeper, &contract, 100 * 1024, 2);
+        let instruction = bandwidth_prepay_instruction::spend_with_receipt(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            10 * 1024,
+            &forged,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::InvalidReceipt as u32)
+        );
+
+        let receipt = UsageReceipt::new(&alice_keypair, &contract, 40 * 1024, 2);
+        let instruction = bandwidth_prepay_instruction::spend_with_receipt(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            30 * 1024,
+            &receipt,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 460);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 40);
+
+        // Older receipts are no longer accepted
+        let stale = UsageReceipt::new(&alice_keypair, &contract, 100 * 1024, 1);
+        let instruction = bandwidth_prepay_instruction::spend_with_receipt(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            1024,
+            &stale,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::InvalidReceipt as u32)
+        );
+
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.bytes_reported, 40 * 1024);
+        assert_eq!(state.receipt_nonce, 2);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_settle_channel() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                channel_dispute_window: Some(10),
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Channels only settle once
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            10 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::PaymentChannelOnly as u32)
+        );
+
+        // The initiator must ask to close before reclaiming
+        let instruction = bandwidth_prepay_instruction::reclaim_channel(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::CloseNotRequested as u32)
+        );
+
+        // Vouchers must be signed by the initiator
+        let forged = Voucher::new(&gatekeeper, &contract, 500);
+        let instruction = bandwidth_prepay_instruction::settle_channel(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &alice_pubkey,
+            &[],
+            &forged,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::InvalidVoucher as u32)
+        );
+
+        let voucher = Voucher::new(&alice_keypair, &contract, 120);
+        let instruction = bandwidth_prepay_instruction::settle_channel(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &alice_pubkey,
+            &[],
+            &voucher,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 120);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_879);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_spent, 120);
+        assert_eq!(state.spend_count, 1);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_reclaim_channel() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank = Arc::new(bank);
+        let bank_client = BankClient::new_shared(&bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper, 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            ContractTerms {
+                channel_dispute_window: Some(1),
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction =
+            bandwidth_prepay_instruction::request_channel_close(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // The gatekeeper still has a slot to settle
+        let instruction = bandwidth_prepay_instruction::reclaim_channel(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::DisputeWindowOpen as u32)
+        );
+
+        let bank = next_slot(&bank);
+        let bank_client = BankClient::new_shared(&bank);
+        let instruction = bandwidth_prepay_instruction::reclaim_channel(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 10_000);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_reclaim_expired() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank = Arc::new(bank);
+        let bank_client = BankClient::new_shared(&bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper, 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            ContractTerms {
+                expiry_timeout: Some(1),
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert!(state.expires);
+        assert_eq!(state.expiry_slot, bank.slot() + 1);
+
+        // Only the initiator may reclaim
+        let mallory_keypair = Keypair::new();
+        let instruction = system_instruction::transfer(&alice_pubkey, &mallory_keypair.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instruction =
+            bandwidth_prepay_instruction::reclaim_expired(&mallory_keypair.pubkey(), &contract);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&mallory_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoInitiatorAccount as u32)
+        );
+
+        // Not expired yet
+        let instruction = bandwidth_prepay_instruction::reclaim_expired(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NotExpired as u32)
+        );
+
+        let bank = next_slot(&bank);
+        let bank_client = BankClient::new_shared(&bank);
+        let instruction = bandwidth_prepay_instruction::reclaim_expired(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_999);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_reclaim_without_expiry() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank = Arc::new(bank);
+        let bank_client = BankClient::new_shared(&bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper, 0);
+
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            terms(),
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let bank = next_slot(&bank);
+        let bank_client = BankClient::new_shared(&bank);
+        let instruction = bandwidth_prepay_instruction::reclaim_expired(&alice_pubkey, &contract);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NotExpired as u32)
+        );
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_top_up() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            terms(),
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+
+        let instructions = bandwidth_prepay_instruction::top_up(&alice_pubkey, &contract, 200);
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 600);
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_299);
+
+        // Only the initiator may top up
+        let instructions = bandwidth_prepay_instruction::top_up(&gatekeeper.pubkey(), &contract, 1);
+        let message = Message::new(instructions);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoInitiatorAccount as u32)
+        );
+
+        // Deposits must be backed by a transfer
+        let instructions = bandwidth_prepay_instruction::top_up(&alice_pubkey, &contract, 200);
+        let message = Message::new(vec![instructions[1].clone()]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::TopUpNotReceived as u32)
+        );
+
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_deposited, 700);
+        assert_eq!(state.total_withdrawn, 100);
+        assert_eq!(state.expected_balance(), 600);
+
+        let instruction =
+            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &contract, &alice_pubkey);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.total_withdrawn, 700);
+        assert_eq!(state.expected_balance(), 0);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_close() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank = Arc::new(bank);
+        let bank_client = BankClient::new_shared(&bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            terms(),
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Initialized contracts can't be initialized again
+        let instructions = bandwidth_prepay_instruction::reinitialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            terms(),
+            100,
+        );
+        let message = Message::new(instructions);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::AlreadyInitialized as u32)
+        );
+
+        // Only the gatekeeper may close
+        let instruction =
+            bandwidth_prepay_instruction::close(&alice_pubkey, &contract, &alice_pubkey);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoGatekeeperAccount as u32)
+        );
+
+        let instruction =
+            bandwidth_prepay_instruction::close(&gatekeeper.pubkey(), &contract, &alice_pubkey);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(
+            bank_client.get_balance(&contract).unwrap(),
+            CLOSED_CONTRACT_RESERVE
+        );
+        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_998);
+
+        // The closed account survives into later slots and can host a new session
+        let bank = next_slot(&bank);
+        let bank_client = BankClient::new_shared(&bank);
+        assert_eq!(bank.get_account(&contract).unwrap().owner, id());
+        let new_provider = Keypair::new().pubkey();
+        let instructions = bandwidth_prepay_instruction::reinitialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &new_provider,
+            terms(),
+            100,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 101);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.provider_id, new_provider);
+        assert_eq!(state.total_deposited, 101);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_revenue_split() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let backhaul = Keypair::new().pubkey();
+        let operator = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let payees = vec![
+            Payee {
+                id: backhaul,
+                basis_points: 3_000,
+            },
+            Payee {
+                id: operator,
+                basis_points: 1_250,
+            },
+        ];
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                payees: payees.clone(),
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.payees, payees);
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Every payee account must be passed in
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[backhaul],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoPayeeAccount as u32)
+        );
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[backhaul, operator],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 400);
+        assert_eq!(bank_client.get_balance(&backhaul).unwrap(), 30);
+        assert_eq!(bank_client.get_balance(&operator).unwrap(), 12);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 58);
+
+        // Rounding remainders go to the provider
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[backhaul, operator],
+            7 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 393);
+        assert_eq!(bank_client.get_balance(&backhaul).unwrap(), 32);
+        assert_eq!(bank_client.get_balance(&operator).unwrap(), 12);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 63);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_initialize_invalid_revenue_split() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper, 0);
+
+        // Cuts may not add up to more than the whole charge
+        let payees = vec![
+            Payee {
+                id: Keypair::new().pubkey(),
+                basis_points: 6_000,
+            },
+            Payee {
+                id: Keypair::new().pubkey(),
+                basis_points: 4_001,
+            },
+        ];
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper,
+            &provider,
+            ContractTerms { payees, ..terms() },
+            500,
+        );
+        let message = Message::new(instructions);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::InvalidRevenueSplit as u32)
+        );
+    }
+
+    fn credit_balance(bank_client: &BankClient, credit_account: &Pubkey) -> u64 {
+        let account = bank_client
+            .get_account_data(credit_account)
+            .unwrap()
+            .unwrap();
+        CreditAccount::deserialize(&account).unwrap().amount
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_credits() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let mint = Keypair::new();
+        let alice_credits = Keypair::new().pubkey();
+        let provider_credits = Keypair::new().pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // Make sure mint and gatekeeper accounts exist
+        let instructions = vec![
+            system_instruction::transfer(&alice_pubkey, &mint.pubkey(), 1),
+            system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1),
+        ];
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let mut instructions = bandwidth_prepay_instruction::create_credit_account(
+            &alice_pubkey,
+            &alice_credits,
+            &alice_pubkey,
+            &mint.pubkey(),
+            1,
+        );
+        instructions.extend(bandwidth_prepay_instruction::create_credit_account(
+            &alice_pubkey,
+            &provider_credits,
+            &provider,
+            &mint.pubkey(),
+            1,
+        ));
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction =
+            bandwidth_prepay_instruction::mint_credits(&mint.pubkey(), &alice_credits, 1_000);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&mint], message).unwrap();
+        assert_eq!(credit_balance(&bank_client, &alice_credits), 1_000);
+
+        let instructions = bandwidth_prepay_instruction::initialize_with_credits(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider_credits,
+            ContractTerms {
+                credit_mint: Some(mint.pubkey()),
+                ..terms()
+            },
+            10,
+            &alice_credits,
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(credit_balance(&bank_client, &alice_credits), 500);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert!(state.credits);
+        assert_eq!(state.credit_balance, 500);
+        assert_eq!(state.total_deposited, 500);
+        assert_eq!(state.refund_id(), &alice_credits);
+
+        // Spends move credits and leave the contract's lamports alone
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider_credits,
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(credit_balance(&bank_client, &provider_credits), 100);
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 10);
+
+        // Credits can't be paid to a plain account
+        let instruction =
+            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &contract, &alice_pubkey);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoInitiatorAccount as u32)
+        );
+
+        // The contract account can't be closed with credits in escrow
+        let instruction =
+            bandwidth_prepay_instruction::close(&gatekeeper.pubkey(), &contract, &alice_pubkey);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::CreditsNotRefunded as u32)
+        );
+
+        let instruction =
+            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &contract, &alice_credits);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(credit_balance(&bank_client, &alice_credits), 900);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.credit_balance, 0);
+        assert_eq!(state.total_withdrawn, 500);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_transfer_credits() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let mint = Keypair::new();
+        let other_mint = Keypair::new().pubkey();
+        let alice_credits = Keypair::new().pubkey();
+        let bob_credits = Keypair::new().pubkey();
+        let other_credits = Keypair::new().pubkey();
+        let bob = Keypair::new().pubkey();
+
+        // Make sure mint account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &mint.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let mut instructions = bandwidth_prepay_instruction::create_credit_account(
+            &alice_pubkey,
+            &alice_credits,
+            &alice_pubkey,
+            &mint.pubkey(),
+            1,
+        );
+        instructions.extend(bandwidth_prepay_instruction::create_credit_account(
+            &alice_pubkey,
+            &bob_credits,
+            &bob,
+            &mint.pubkey(),
+            1,
+        ));
+        instructions.extend(bandwidth_prepay_instruction::create_credit_account(
+            &alice_pubkey,
+            &other_credits,
+            &bob,
+            &other_mint,
+            1,
+        ));
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Only the mint may issue credits
+        let instruction =
+            bandwidth_prepay_instruction::mint_credits(&alice_pubkey, &alice_credits, 1_000);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::InvalidCreditAccount as u32)
+        );
+
+        let instruction =
+            bandwidth_prepay_instruction::mint_credits(&mint.pubkey(), &alice_credits, 100);
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&mint], message).unwrap();
+
+        let instruction = bandwidth_prepay_instruction::transfer_credits(
+            &alice_pubkey,
+            &alice_credits,
+            &bob_credits,
+            30,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(credit_balance(&bank_client, &alice_credits), 70);
+        assert_eq!(credit_balance(&bank_client, &bob_credits), 30);
+
+        // Credits of different mints don't mix
+        let instruction = bandwidth_prepay_instruction::transfer_credits(
+            &alice_pubkey,
+            &alice_credits,
+            &other_credits,
+            30,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::InvalidCreditAccount as u32)
+        );
+
+        let instruction = bandwidth_prepay_instruction::transfer_credits(
+            &alice_pubkey,
+            &alice_credits,
+            &bob_credits,
+            71,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::BalanceTooLow as u32)
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_migrate() {
+        let initiator = Pubkey::new_rand();
+        let gatekeeper = Pubkey::new_rand();
+        let contract = Pubkey::new_rand();
+        let v0 = BandwidthPrepayStateV0 {
+            gatekeeper_id: gatekeeper,
+            provider_id: Pubkey::new_rand(),
+            initiator_id: initiator,
+        };
+        let mut contract_account = Account::new(500, 0, &id());
+        contract_account.data = bincode::serialize(&v0).unwrap();
+        let mut initiator_account = Account::new(1, 0, &Pubkey::default());
+        let mut mallory_account = Account::new(1, 0, &Pubkey::default());
+        let data = bincode::serialize(&BandwidthPrepayInstruction::Migrate).unwrap();
+
+        // Only the contract's parties may migrate it
+        let mallory = Pubkey::new_rand();
+        let mut keyed_accounts = vec![
+            KeyedAccount::new(&mallory, true, &mut mallory_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+        ];
+        assert_eq!(
+            process_instruction(&id(), &mut keyed_accounts, &data),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::NotContractParty as u32
+            ))
+        );
+
+        // The current layout doesn't fit in an untagged contract
+        let mut keyed_accounts = vec![
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+        ];
+        assert_eq!(
+            process_instruction(&id(), &mut keyed_accounts, &data),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::UserdataTooSmall as u32
+            ))
+        );
+        assert_eq!(contract_account.data.len(), V0_STATE_SIZE);
+
+        // A tagged contract with room to spare is rewritten in place
+        let v1 = BandwidthPrepayStateV1 {
+            gatekeeper_id: gatekeeper,
+            initiator_id: initiator,
+            tariff: TARIFF,
+            total_deposited: 500,
+            ..BandwidthPrepayStateV1::default()
+        };
+        let v1_data = bincode::serialize(&VersionedBandwidthPrepayState::V1(v1)).unwrap();
+        let expected_state = BandwidthPrepayState::deserialize(&v1_data).unwrap();
+        let size =
+            bincode::serialized_size(&VersionedBandwidthPrepayState::V6(expected_state.clone()))
+                .unwrap() as usize;
+        contract_account.data = vec![0; size];
+        contract_account.data[..v1_data.len()].copy_from_slice(&v1_data);
+        let mut keyed_accounts = vec![
+            KeyedAccount::new(&initiator, true, &mut initiator_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+        ];
+        process_instruction(&id(), &mut keyed_accounts, &data).unwrap();
+        assert_eq!(contract_account.data.len(), size);
+        assert!(!BandwidthPrepayState::needs_migration(
+            &contract_account.data
+        ));
+        let state = BandwidthPrepayState::deserialize(&contract_account.data).unwrap();
+        assert_eq!(state, expected_state);
+    }
+
+    // Size the program gave contracts before gatekeeper delegates
+    fn v1_state_size() -> usize {
+        let state = BandwidthPrepayStateV1 {
+            payees: vec![Payee::default(); MAX_PAYEES],
+            ..BandwidthPrepayStateV1::default()
+        };
+        bincode::serialized_size(&VersionedBandwidthPrepayState::V1(state)).unwrap() as usize
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_migrate_stored_contracts() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let provider = Pubkey::new_rand();
+        let payee_ids = vec![Pubkey::new_rand(), Pubkey::new_rand()];
+
+        // Contracts written by older versions of the program, at the sizes
+        // they were created with
+        let v0_contract = Pubkey::new_rand();
+        let v0 = BandwidthPrepayStateV0 {
+            gatekeeper_id: gatekeeper.pubkey(),
+            provider_id: provider,
+            initiator_id: alice_pubkey,
+        };
+        let mut v0_account = Account::new(100, V0_STATE_SIZE, &id());
+        bincode::serialize_into(&mut v0_account.data[..], &v0).unwrap();
+        bank.store_account(&v0_contract, &v0_account);
+
+        let stored_v1 = |state: BandwidthPrepayStateV1| {
+            let mut account = Account::new(100, v1_state_size(), &id());
+            bincode::serialize_into(
+                &mut account.data[..],
+                &VersionedBandwidthPrepayState::V1(state),
+            )
+            .unwrap();
+            account
+        };
+        let v1 = BandwidthPrepayStateV1 {
+            gatekeeper_id: gatekeeper.pubkey(),
+            provider_id: provider,
+            initiator_id: alice_pubkey,
+            tariff: TARIFF,
+            total_deposited: 100,
+            ..BandwidthPrepayStateV1::default()
+        };
+        let v1_contract = Pubkey::new_rand();
+        let v1_account = stored_v1(v1.clone());
+        let expected_state = BandwidthPrepayState::deserialize(&v1_account.data).unwrap();
+        bank.store_account(&v1_contract, &v1_account);
+
+        // Its payees leave no room for the fields added since
+        let full_v1_contract = Pubkey::new_rand();
+        let full_v1 = BandwidthPrepayStateV1 {
+            payees: payee_ids
+                .iter()
+                .map(|&id| Payee {
+                    id,
+                    basis_points: 1_000,
+                })
+                .collect(),
+            ..v1
+        };
+        bank.store_account(&full_v1_contract, &stored_v1(full_v1));
+
+        let bank_client = BankClient::new(bank);
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        bank_client
+            .send_instruction(&alice_keypair, instruction)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::migrate(&alice_pubkey, &v1_contract);
+        bank_client
+            .send_instruction(&alice_keypair, instruction)
+            .unwrap();
+        let data = bank_client.get_account_data(&v1_contract).unwrap().unwrap();
+        assert_eq!(data.len(), v1_state_size());
+        assert!(!BandwidthPrepayState::needs_migration(&data));
+        assert_eq!(
+            BandwidthPrepayState::deserialize(&data).unwrap(),
+            expected_state
+        );
+
+        // The current layout fits in neither the untagged contract nor the full one
+        for contract in &[v0_contract, full_v1_contract] {
+            let data = bank_client.get_account_data(contract).unwrap().unwrap();
+            assert!(!BandwidthPrepayState::can_migrate(&data));
+            let instruction = bandwidth_prepay_instruction::migrate(&alice_pubkey, contract);
+            assert_eq!(
+                instruction_error(
+                    bank_client
+                        .send_instruction(&alice_keypair, instruction)
+                        .unwrap_err()
+                ),
+                InstructionError::CustomError(BandwidthPrepayError::UserdataTooSmall as u32)
+            );
+        }
+
+        // Both are still charged, and written back in the layout they hold
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &v0_contract,
+            &provider,
+            &[],
+            10 * 1024,
+        );
+        bank_client
+            .send_instruction(&gatekeeper, instruction)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&v0_contract).unwrap(), 90);
+        let data = bank_client.get_account_data(&v0_contract).unwrap().unwrap();
+        assert_eq!(data, bincode::serialize(&v0).unwrap());
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &full_v1_contract,
+            &provider,
+            &payee_ids,
+            10 * 1024,
+        );
+        bank_client
+            .send_instruction(&gatekeeper, instruction)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&full_v1_contract).unwrap(), 90);
+        assert_eq!(bank_client.get_balance(&payee_ids[0]).unwrap(), 1);
+        let data = bank_client
+            .get_account_data(&full_v1_contract)
+            .unwrap()
+            .unwrap();
+        match bincode::deserialize(&data).unwrap() {
+            VersionedBandwidthPrepayState::V1(state) => {
+                assert_eq!(state.bytes_reported, 10 * 1024);
+                assert_eq!(state.total_withdrawn, 10);
+            }
+            state => panic!("unexpected layout: {:?}", state),
+        }
+
+        let instruction =
+            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &v0_contract, &alice_pubkey);
+        bank_client
+            .send_instruction(&gatekeeper, instruction)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&v0_contract).unwrap(), 0);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 18);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_set_gatekeeper() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let new_gatekeeper = Keypair::new();
+        let hot_key = Keypair::new();
+
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            terms(),
+            1_000,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure every signing account exists
+        for pubkey in &[
+            gatekeeper.pubkey(),
+            new_gatekeeper.pubkey(),
+            hot_key.pubkey(),
+        ] {
+            let instruction = system_instruction::transfer(&alice_pubkey, pubkey, 1);
+            let message = Message::new(vec![instruction]);
+            bank_client
+                .send_message(&[&alice_keypair], message)
+                .unwrap();
+        }
+
+        // Only the current gatekeeper may hand the contract over
+        let instruction = bandwidth_prepay_instruction::set_gatekeeper(
+            &alice_pubkey,
+            &hot_key.pubkey(),
+            &contract,
+            &new_gatekeeper.pubkey(),
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&alice_keypair, &hot_key], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoGatekeeperAccount as u32)
+        );
+
+        let instruction = bandwidth_prepay_instruction::set_gatekeeper(
+            &alice_pubkey,
+            &gatekeeper.pubkey(),
+            &contract,
+            &new_gatekeeper.pubkey(),
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair, &gatekeeper], message)
+            .unwrap();
+
+        // The old key no longer signs for the contract
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoGatekeeperAccount as u32)
+        );
+
+        // Delegates are set by the gatekeeper and may spend on its behalf
+        let instruction = bandwidth_prepay_instruction::set_delegates(
+            &new_gatekeeper.pubkey(),
+            &contract,
+            vec![Pubkey::new_rand(); MAX_DELEGATES + 1],
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&new_gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::TooManyDelegates as u32)
+        );
+
+        let instruction = bandwidth_prepay_instruction::set_delegates(
+            &new_gatekeeper.pubkey(),
+            &contract,
+            vec![hot_key.pubkey()],
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&new_gatekeeper], message)
+            .unwrap();
+
+        let instruction = bandwidth_prepay_instruction::spend(
+            &hot_key.pubkey(),
+            &contract,
+            &provider,
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&hot_key], message).unwrap();
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 900);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 100);
+
+        // Delegates cannot appoint other delegates
+        let instruction = bandwidth_prepay_instruction::set_delegates(
+            &hot_key.pubkey(),
+            &contract,
+            vec![Pubkey::new_rand()],
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(bank_client.send_message(&[&hot_key], message).unwrap_err()),
+            InstructionError::CustomError(BandwidthPrepayError::NoGatekeeperAccount as u32)
+        );
+
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.gatekeeper_id, new_gatekeeper.pubkey());
+        assert_eq!(state.delegates, vec![hot_key.pubkey()]);
+
+        // The contract is still found where it was derived for the old key
+        assert_eq!(state.address_gatekeeper_id(), &gatekeeper.pubkey());
+        assert_eq!(
+            contract_address(&alice_pubkey, state.address_gatekeeper_id(), 0),
+            contract
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_spend_many() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let backhaul = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let plain_contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let split_contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 1);
+
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &plain_contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            terms(),
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &split_contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                payees: vec![Payee {
+                    id: backhaul,
+                    basis_points: 5_000,
+                }],
+                session_nonce: 1,
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let charges = |plain_bytes, split_bytes| {
+            vec![
+                (
+                    plain_contract,
+                    BatchCharge {
+                        data_amount: plain_bytes,
+                        receipt: None,
+                    },
+                ),
+                (
+                    split_contract,
+                    BatchCharge {
+                        data_amount: split_bytes,
+                        receipt: None,
+                    },
+                ),
+            ]
+        };
+
+        // One failing charge fails the whole batch
+        let instruction = bandwidth_prepay_instruction::spend_many(
+            &gatekeeper.pubkey(),
+            &provider,
+            charges(100 * 1024, 600 * 1024),
+            &[backhaul],
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::BalanceTooLow as u32)
+        );
+        assert_eq!(bank_client.get_balance(&plain_contract).unwrap(), 500);
+
+        let instruction = bandwidth_prepay_instruction::spend_many(
+            &gatekeeper.pubkey(),
+            &provider,
+            charges(100 * 1024, 200 * 1024),
+            &[],
+        );
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NoPayeeAccount as u32)
+        );
+
+        let instruction = bandwidth_prepay_instruction::spend_many(
+            &gatekeeper.pubkey(),
+            &provider,
+            charges(100 * 1024, 200 * 1024),
+            &[backhaul],
+        );
+        let message = Message::new(vec![instruction]);
+        bank_client.send_message(&[&gatekeeper], message).unwrap();
+        assert_eq!(bank_client.get_balance(&plain_contract).unwrap(), 400);
+        assert_eq!(bank_client.get_balance(&split_contract).unwrap(), 300);
+        assert_eq!(bank_client.get_balance(&backhaul).unwrap(), 100);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 200);
+
+        let account = bank_client
+            .get_account_data(&split_contract)
+            .unwrap()
+            .unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.bytes_reported, 200 * 1024);
+        assert_eq!(state.total_withdrawn, 200);
+    }
+
+    // Lamports held by each of `pubkeys`
+    fn balances(bank_client: &BankClient, pubkeys: &[Pubkey]) -> Vec<u64> {
+        pubkeys
+            .iter()
+            .map(|pubkey| bank_client.get_balance(pubkey).unwrap())
+            .collect()
+    }
+
+    // Drive random Spend, SpendMany, Refund and TopUp instructions, with random signers and
+    // now and then scrambled account metas, against one contract and check that:
+    // - lamports are conserved
+    // - only the gatekeeper takes lamports out of the contract
+    // - the state always deserializes and agrees with the contract balance
+    // - a refund empties the contract, which the runtime then drops, so nothing
+    //   is spent after it
+    fn run_random_instructions(rng: &mut ChaChaRng, steps: usize) {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let mut bank = Arc::new(bank);
+        let bank_client = BankClient::new_shared(&bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let mallory = Keypair::new();
+        let provider = Keypair::new().pubkey();
+        let payee = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                payees: vec![Payee {
+                    id: payee,
+                    basis_points: 1_000,
+                }],
+                ..terms()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper and mallory accounts exist
+        let instructions = vec![
+            system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 100),
+            system_instruction::transfer(&alice_pubkey, &mallory.pubkey(), 100),
+        ];
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let pubkeys = [
+            alice_pubkey,
+            gatekeeper.pubkey(),
+            mallory.pubkey(),
+            provider,
+            payee,
+            contract,
+        ];
+        let provider_index = 3;
+        let payee_index = 4;
+        let contract_index = 5;
+        let signers = [&alice_keypair, &gatekeeper, &mallory];
+        let mut refunded = false;
+
+        for _ in 0..steps {
+            // A fresh blockhash keeps repeated instructions from being rejected as duplicates
+            bank = next_slot(&bank);
+            let bank_client = BankClient::new_shared(&bank);
+            let signer = signers[rng.gen_range(0, signers.len())];
+            let data_amount = rng.gen_range(1, 200) * 1024;
+            // Once refunded the session is over, so the initiator stops topping up
+            let action = rng.gen_range(0, if refunded { 3 } else { 4 });
+            let mut instructions = match action {
+                0 => vec![bandwidth_prepay_instruction::spend(
+                    &signer.pubkey(),
+                    &contract,
+                    &provider,
+                    &[payee],
+                    data_amount,
+                )],
+                1 => vec![bandwidth_prepay_instruction::spend_many(
+                    &signer.pubkey(),
+                    &provider,
+                    vec![(
+                        contract,
+                        BatchCharge {
+                            data_amount,
+                            receipt: None,
+                        },
+                    )],
+                    &[payee],
+                )],
+                2 => vec![bandwidth_prepay_instruction::refund(
+                    &signer.pubkey(),
+                    &contract,
+                    &alice_pubkey,
+                )],
+                _ => bandwidth_prepay_instruction::top_up(
+                    &signer.pubkey(),
+                    &contract,
+                    rng.gen_range(1, 100),
+                ),
+            };
+            if rng.gen_range(0, 4) == 0 {
+                instructions.last_mut().unwrap().accounts.shuffle(rng);
+            }
+
+            let before = balances(&bank_client, &pubkeys);
+            let result = bank_client.send_message(&[signer], Message::new(instructions));
+            let after = balances(&bank_client, &pubkeys);
+            let account = bank_client.get_account_data(&contract).unwrap();
+
+            assert_eq!(after.iter().sum::<u64>(), 10_000);
+            if signer.pubkey() != gatekeeper.pubkey() {
+                assert!(after[contract_index] >= before[contract_index]);
+                assert_eq!(after[provider_index], before[provider_index]);
+                assert_eq!(after[payee_index], before[payee_index]);
+            }
+            if action == 2 && result.is_ok() {
+                refunded = true;
+            }
+            if refunded {
+                // A refund leaves no reserve, so the contract is gone for good
+                assert_eq!(after[contract_index], 0);
+                assert_eq!(account, None);
+                assert_eq!(after[provider_index], before[provider_index]);
+                assert_eq!(after[payee_index], before[payee_index]);
+                continue;
+            }
+            let state = BandwidthPrepayState::deserialize(&account.unwrap()).unwrap();
+            assert_eq!(state.initiator_id, alice_pubkey);
+            assert_eq!(state.gatekeeper_id, gatekeeper.pubkey());
+            assert_eq!(state.provider_id, provider);
+            assert_eq!(state.expected_balance(), after[contract_index]);
+            assert_eq!(
+                state.total_spent,
+                after[provider_index] + after[payee_index]
+            );
+        }
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_random_instructions() {
+        for seed in 0..4 {
+            run_random_instructions(&mut ChaChaRng::from_seed([seed; 32]), 100);
+        }
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_wrong_account_metas() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let mallory = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            terms(),
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let spend = || {
+            bandwidth_prepay_instruction::spend(
+                &gatekeeper.pubkey(),
+                &contract,
+                &provider,
+                &[],
+                100 * 1024,
+            )
+        };
+        let send = |instruction: Instruction| {
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], Message::new(vec![instruction]))
+                    .unwrap_err(),
+            )
+        };
+
+        // Contract and provider swapped
+        let mut instruction = spend();
+        instruction.accounts.swap(1, 2);
+        assert_eq!(
+            send(instruction),
+            InstructionError::CustomError(BandwidthPrepayError::NotProgramOwned as u32)
+        );
+
+        // Someone else's account in place of the provider
+        let mut instruction = spend();
+        instruction.accounts[2].pubkey = mallory;
+        assert_eq!(
+            send(instruction),
+            InstructionError::CustomError(BandwidthPrepayError::NoProviderAccount as u32)
+        );
+
+        // Any account in place of the clock
+        let mut instruction = spend();
+        instruction.accounts[3].pubkey = mallory;
+        assert_eq!(
+            send(instruction),
+            InstructionError::CustomError(BandwidthPrepayError::NoClockAccount as u32)
+        );
+
+        // Gatekeeper listed but not signing
+        let mut instruction = spend();
+        instruction.accounts[0].is_signer = false;
+        let message = Message::new(vec![
+            system_instruction::transfer(&alice_pubkey, &provider, 1),
+            instruction,
+        ]);
+        assert!(bank_client
+            .send_message(&[&alice_keypair], message)
+            .is_err());
+
+        // Refund to an account other than the initiator
+        let instruction =
+            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &contract, &mallory);
+        assert_eq!(
+            send(instruction),
+            InstructionError::CustomError(BandwidthPrepayError::NoInitiatorAccount as u32)
+        );
+
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_duplicate_accounts() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let provider = Keypair::new().pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+
+        // A contract naming itself as provider
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &contract,
+            terms(),
+            500,
+        );
+        let message = Message::new(instructions);
+        assert!(bank_client
+            .send_message(&[&alice_keypair], message)
+            .is_err());
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+
+        // Initialize contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            terms(),
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Provider == contract
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &contract,
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+
+        // Initiator == contract
+        let instruction =
+            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &contract, &contract);
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+
+        // Gatekeeper == provider
+        let instruction = bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract,
+            &gatekeeper.pubkey(),
+            &[],
+            100 * 1024,
+        );
+        let message = Message::new(vec![instruction]);
+        assert!(bank_client.send_message(&[&gatekeeper], message).is_err());
+
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 1);
+        let account = bank_client.get_account_data(&contract).unwrap().unwrap();
+        let state = BandwidthPrepayState::deserialize(&account).unwrap();
+        assert_eq!(state.spend_count, 0);
+        assert_eq!(state.total_withdrawn, 0);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_aliased_accounts() {
+        let gatekeeper = Pubkey::new_rand();
+        let contract = Pubkey::new_rand();
+        let initiator = Pubkey::new_rand();
+        let state = BandwidthPrepayState {
+            gatekeeper_id: gatekeeper,
+            initiator_id: initiator,
+            provider_id: contract,
+            tariff: TARIFF,
+            total_deposited: 500,
+            ..BandwidthPrepayState::default()
+        };
+        let mut contract_account = Account::new(500, BandwidthPrepayState::max_size(), &id());
+        state.serialize(&mut contract_account.data).unwrap();
+        let mut contract_alias_account = contract_account.clone();
+        let mut gatekeeper_account = Account::new(1, 0, &Pubkey::default());
+        let mut clock_account = Account::new(1, 0, &Pubkey::default());
+        let clock_id = clock::id();
+
+        // The contract passed again as its own provider
+        let data = bincode::serialize(&BandwidthPrepayInstruction::Spend(100 * 1024)).unwrap();
+        let mut keyed_accounts = vec![
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&contract, false, &mut contract_alias_account),
+            KeyedAccount::new(&clock_id, false, &mut clock_account),
+        ];
+        assert_eq!(
+            process_instruction(&id(), &mut keyed_accounts, &data),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::DuplicateAccounts as u32
+            ))
+        );
+
+        // The contract passed again as the initiator
+        let data = bincode::serialize(&BandwidthPrepayInstruction::Refund).unwrap();
+        let mut keyed_accounts = vec![
+            KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+            KeyedAccount::new(&contract, false, &mut contract_account),
+            KeyedAccount::new(&contract, false, &mut contract_alias_account),
+        ];
+        assert_eq!(
+            process_instruction(&id(), &mut keyed_accounts, &data),
+            Err(InstructionError::CustomError(
+                BandwidthPrepayError::DuplicateAccounts as u32
+            ))
+        );
+        assert_eq!(contract_account.lamports, 500);
+        assert_eq!(contract_alias_account.lamports, 500);
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_missing_accounts() {
+        let gatekeeper = Pubkey::new_rand();
+        let contract = Pubkey::new_rand();
+        let mut gatekeeper_account = Account::new(1, 0, &Pubkey::default());
+        let mut contract_account = Account::new(500, BandwidthPrepayState::max_size(), &id());
+
+        let instructions = vec![
+            BandwidthPrepayInstruction::InitializeAccount(terms()),
+            BandwidthPrepayInstruction::Spend(100 * 1024),
+            BandwidthPrepayInstruction::Refund,
+            BandwidthPrepayInstruction::Close,
+            BandwidthPrepayInstruction::SpendMany(vec![BatchCharge {
+                data_amount: 100 * 1024,
+                receipt: None,
+            }]),
+        ];
+        for instruction in instructions {
+            let data = bincode::serialize(&instruction).unwrap();
+            let mut keyed_accounts = vec![
+                KeyedAccount::new(&gatekeeper, true, &mut gatekeeper_account),
+                KeyedAccount::new(&contract, false, &mut contract_account),
+            ];
+            assert_eq!(
+                process_instruction(&id(), &mut keyed_accounts, &data),
+                Err(InstructionError::CustomError(
+                    BandwidthPrepayError::NotEnoughAccounts as u32
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_bandwidth_prepay_contract_not_owned() {
+        let (bank, alice_keypair) = create_bank(10_000);
+        let bank_client = BankClient::new(bank);
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = Keypair::new().pubkey();
+
+        // A system account in place of the contract
+        let instructions = vec![
+            system_instruction::transfer(&alice_pubkey, &contract, 500),
+            system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1),
+        ];
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let instruction =
+            bandwidth_prepay_instruction::refund(&gatekeeper.pubkey(), &contract, &alice_pubkey);
+        let message = Message::new(vec![instruction]);
+        assert_eq!(
+            instruction_error(
+                bank_client
+                    .send_message(&[&gatekeeper], message)
+                    .unwrap_err()
+            ),
+            InstructionError::CustomError(BandwidthPrepayError::NotProgramOwned as u32)
+        );
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 500);
+    }
 }
@@ -0,0 +1,57 @@
+use bincode::serialize;
+use serde_derive::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
+
+/// Usage acknowledged by the initiator, signed over (contract, cumulative bytes, nonce)
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct UsageReceipt {
+    pub contract_id: Pubkey,
+    pub cumulative_bytes: u64,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+impl UsageReceipt {
+    pub fn new(
+        initiator: &Keypair,
+        contract_id: &Pubkey,
+        cumulative_bytes: u64,
+        nonce: u64,
+    ) -> Self {
+        let message = Self::message(contract_id, cumulative_bytes, nonce);
+        Self {
+            contract_id: *contract_id,
+            cumulative_bytes,
+            nonce,
+            signature: initiator.sign_message(&message),
+        }
+    }
+
+    pub fn verify(&self, initiator_id: &Pubkey) -> bool {
+        let message = Self::message(&self.contract_id, self.cumulative_bytes, self.nonce);
+        self.signature.verify(initiator_id.as_ref(), &message)
+    }
+
+    fn message(contract_id: &Pubkey, cumulative_bytes: u64, nonce: u64) -> Vec<u8> {
+        serialize(&(contract_id, cumulative_bytes, nonce)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_receipt_verify() {
+        let initiator = Keypair::new();
+        let contract = Pubkey::new_rand();
+        let receipt = UsageReceipt::new(&initiator, &contract, 4096, 1);
+        assert!(receipt.verify(&initiator.pubkey()));
+        assert!(!receipt.verify(&Pubkey::new_rand()));
+
+        let mut forged = receipt.clone();
+        forged.cumulative_bytes = 8192;
+        assert!(!forged.verify(&initiator.pubkey()));
+    }
+}
@@ -1,7 +1,10 @@
 use bincode::{deserialize, serialize_into, serialized_size};
 use serde_derive::{Deserialize, Serialize};
+use solana_sdk::instruction::InstructionError;
 use solana_sdk::pubkey::Pubkey;
-use std::{error, fmt};
+use solana_sdk::transaction::TransactionError;
+use std::convert::TryFrom;
+use std::{cmp, error, fmt};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum BandwidthPrepayError {
@@ -13,35 +16,1148 @@ pub enum BandwidthPrepayError {
     NoGatekeeperAccount,
     NoProviderAccount,
     NoInitiatorAccount,
+    ChargeOverflow,
+    ReceiptRequired,
+    InvalidReceipt,
+    UnacknowledgedUsage,
+    NotPaymentChannel,
+    PaymentChannelOnly,
+    InvalidVoucher,
+    NotSignedByInitiator,
+    CloseNotRequested,
+    DisputeWindowOpen,
+    NoClockAccount,
+    NotExpired,
+    TopUpNotReceived,
+    InvalidRevenueSplit,
+    NoPayeeAccount,
+    CreditsUnsupported,
+    NotCreditContract,
+    InvalidCreditAccount,
+    CreditsNotRefunded,
+    NotContractParty,
+    TooManyDelegates,
+    NotDerivedAddress,
+    NoContractAccount,
+    RateLimitExceeded,
+    NotEnoughAccounts,
+    DuplicateAccounts,
+    NotProgramOwned,
+    InvalidHops,
+}
+
+impl BandwidthPrepayError {
+    /// Decode the code carried by `InstructionError::CustomError`
+    pub fn from_custom_code(code: u32) -> Option<Self> {
+        let error = match code {
+            0 => BandwidthPrepayError::AlreadyInitialized,
+            1 => BandwidthPrepayError::UserdataTooSmall,
+            2 => BandwidthPrepayError::UserdataDeserializeFailure,
+            3 => BandwidthPrepayError::NotSignedByGatekeeper,
+            4 => BandwidthPrepayError::BalanceTooLow,
+            5 => BandwidthPrepayError::NoGatekeeperAccount,
+            6 => BandwidthPrepayError::NoProviderAccount,
+            7 => BandwidthPrepayError::NoInitiatorAccount,
+            8 => BandwidthPrepayError::ChargeOverflow,
+            9 => BandwidthPrepayError::ReceiptRequired,
+            10 => BandwidthPrepayError::InvalidReceipt,
+            11 => BandwidthPrepayError::UnacknowledgedUsage,
+            12 => BandwidthPrepayError::NotPaymentChannel,
+            13 => BandwidthPrepayError::PaymentChannelOnly,
+            14 => BandwidthPrepayError::InvalidVoucher,
+            15 => BandwidthPrepayError::NotSignedByInitiator,
+            16 => BandwidthPrepayError::CloseNotRequested,
+            17 => BandwidthPrepayError::DisputeWindowOpen,
+            18 => BandwidthPrepayError::NoClockAccount,
+            19 => BandwidthPrepayError::NotExpired,
+            20 => BandwidthPrepayError::TopUpNotReceived,
+            21 => BandwidthPrepayError::InvalidRevenueSplit,
+            22 => BandwidthPrepayError::NoPayeeAccount,
+            23 => BandwidthPrepayError::CreditsUnsupported,
+            24 => BandwidthPrepayError::NotCreditContract,
+            25 => BandwidthPrepayError::InvalidCreditAccount,
+            26 => BandwidthPrepayError::CreditsNotRefunded,
+            27 => BandwidthPrepayError::NotContractParty,
+            28 => BandwidthPrepayError::TooManyDelegates,
+            29 => BandwidthPrepayError::NotDerivedAddress,
+            30 => BandwidthPrepayError::NoContractAccount,
+            31 => BandwidthPrepayError::RateLimitExceeded,
+            32 => BandwidthPrepayError::NotEnoughAccounts,
+            33 => BandwidthPrepayError::DuplicateAccounts,
+            34 => BandwidthPrepayError::NotProgramOwned,
+            35 => BandwidthPrepayError::InvalidHops,
+            _ => return None,
+        };
+        Some(error)
+    }
+
+    /// The program error behind a failed transaction, if there is one
+    pub fn from_transaction_error(err: &TransactionError) -> Option<Self> {
+        match err {
+            TransactionError::InstructionError(_, InstructionError::CustomError(code)) => {
+                Self::from_custom_code(*code)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for BandwidthPrepayError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "invalid")
+        let message = match self {
+            BandwidthPrepayError::AlreadyInitialized => "contract account is already initialized",
+            BandwidthPrepayError::UserdataTooSmall => {
+                "account data is too small for the contract state"
+            }
+            BandwidthPrepayError::UserdataDeserializeFailure => {
+                "account data does not hold a contract state"
+            }
+            BandwidthPrepayError::NotSignedByGatekeeper => {
+                "instruction is not signed by the gatekeeper"
+            }
+            BandwidthPrepayError::BalanceTooLow => "contract balance is too low",
+            BandwidthPrepayError::NoGatekeeperAccount => "signer is not the contract's gatekeeper",
+            BandwidthPrepayError::NoProviderAccount => {
+                "provider account does not match the contract"
+            }
+            BandwidthPrepayError::NoInitiatorAccount => {
+                "initiator account does not match the contract"
+            }
+            BandwidthPrepayError::ChargeOverflow => "charge overflows",
+            BandwidthPrepayError::ReceiptRequired => {
+                "contract only accepts spends backed by a receipt"
+            }
+            BandwidthPrepayError::InvalidReceipt => {
+                "receipt is stale, for another contract or not signed by the initiator"
+            }
+            BandwidthPrepayError::UnacknowledgedUsage => {
+                "spend exceeds the usage acknowledged by the receipt"
+            }
+            BandwidthPrepayError::NotPaymentChannel => "contract is not a payment channel",
+            BandwidthPrepayError::PaymentChannelOnly => {
+                "payment channels only settle through SettleChannel"
+            }
+            BandwidthPrepayError::InvalidVoucher => {
+                "voucher is for another contract or not signed by the initiator"
+            }
+            BandwidthPrepayError::NotSignedByInitiator => {
+                "instruction is not signed by the initiator"
+            }
+            BandwidthPrepayError::CloseNotRequested => {
+                "initiator has not asked to close the channel"
+            }
+            BandwidthPrepayError::DisputeWindowOpen => "channel dispute window is still open",
+            BandwidthPrepayError::NoClockAccount => "clock sysvar account is missing",
+            BandwidthPrepayError::NotExpired => "contract has not expired",
+            BandwidthPrepayError::TopUpNotReceived => {
+                "top-up lamports were not transferred into the contract"
+            }
+            BandwidthPrepayError::InvalidRevenueSplit => {
+                "revenue split has too many payees or exceeds 100%"
+            }
+            BandwidthPrepayError::NoPayeeAccount => "payee accounts do not match the contract",
+            BandwidthPrepayError::CreditsUnsupported => {
+                "operation is not supported on credit contracts"
+            }
+            BandwidthPrepayError::NotCreditContract => "contract does not escrow credits",
+            BandwidthPrepayError::InvalidCreditAccount => {
+                "credit account is of another mint or not authorized"
+            }
+            BandwidthPrepayError::CreditsNotRefunded => {
+                "credits must be refunded before closing the contract"
+            }
+            BandwidthPrepayError::NotContractParty => {
+                "signer is neither the initiator nor the gatekeeper"
+            }
+            BandwidthPrepayError::TooManyDelegates => "too many gatekeeper delegates",
+            BandwidthPrepayError::NotDerivedAddress => {
+                "contract address is not derived from the initiator, gatekeeper and session nonce"
+            }
+            BandwidthPrepayError::NoContractAccount => "contract account is missing",
+            BandwidthPrepayError::RateLimitExceeded => {
+                "charge exceeds the contract's spending cap for this window"
+            }
+            BandwidthPrepayError::NotEnoughAccounts => "instruction is missing accounts",
+            BandwidthPrepayError::DuplicateAccounts => "the same account is passed more than once",
+            BandwidthPrepayError::NotProgramOwned => {
+                "contract account is not owned by the prepay program"
+            }
+            BandwidthPrepayError::InvalidHops => "multi-hop route is malformed or unsupported",
+        };
+        write!(f, "{:?}: {}", self, message)
     }
 }
 
 impl error::Error for BandwidthPrepayError {}
 
+/// Price agreed to by the initiator when the contract is initialized
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct Tariff {
+    pub lamports_per_kib: u64,
+    pub minimum_charge: u64,
+}
+
+impl Tariff {
+    /// Lamports owed for `data_amount` bytes, or None if the charge overflows
+    pub fn charge(&self, data_amount: u64) -> Option<u64> {
+        if data_amount == 0 {
+            return Some(0);
+        }
+        let charge = data_amount.checked_mul(self.lamports_per_kib)? / 1024;
+        Some(cmp::max(charge, self.minimum_charge))
+    }
+
+    /// Lamports owed for `data_amount` more bytes once `bytes_reported` have
+    /// been charged; the running total is priced, so fractions of a lamport
+    /// carry over to the next charge instead of being dropped from each
+    pub fn charge_after(&self, bytes_reported: u64, data_amount: u64) -> Option<u64> {
+        self.share_after(MAX_BASIS_POINTS, bytes_reported, data_amount)
+    }
+
+    /// `basis_points` of what `charge_after` owes, priced on the running total
+    /// the same way, for hops that take a share of the tariff
+    pub fn share_after(
+        &self,
+        basis_points: u16,
+        bytes_reported: u64,
+        data_amount: u64,
+    ) -> Option<u64> {
+        if data_amount == 0 {
+            return Some(0);
+        }
+        let owed = |bytes: u64| {
+            (u128::from(bytes) * u128::from(self.lamports_per_kib))
+                .checked_mul(u128::from(basis_points))
+                .map(|owed| owed / (1024 * u128::from(MAX_BASIS_POINTS)))
+        };
+        let total = bytes_reported.checked_add(data_amount)?;
+        let charge = u64::try_from(owed(total)? - owed(bytes_reported)?).ok()?;
+        let minimum_charge = u128::from(self.minimum_charge) * u128::from(basis_points)
+            / u128::from(MAX_BASIS_POINTS);
+        Some(cmp::max(charge, minimum_charge as u64))
+    }
+}
+
+/// Most payees a contract can split its charges between, besides the provider
+pub const MAX_PAYEES: usize = 4;
+pub const MAX_BASIS_POINTS: u16 = 10_000;
+
+/// Most hot keys a gatekeeper can delegate signing to
+pub const MAX_DELEGATES: usize = 4;
+
+/// Lamports a closed contract keeps, so the runtime neither purges the account
+/// nor hands it back to the system program before it hosts another session
+pub const CLOSED_CONTRACT_RESERVE: u64 = 1;
+
+/// Most the gatekeeper may charge within any window of `slots` slots
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct SpendingCap {
+    pub lamports: u64,
+    pub slots: u64,
+}
+
+/// Most gatekeepers a multi-hop route can pass through
+pub const MAX_HOPS: usize = 4;
+
+/// One gatekeeper along a multi-hop route
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct Hop {
+    pub gatekeeper_id: Pubkey,
+    /// Receives this hop's charges
+    pub provider_id: Pubkey,
+    /// Share of the tariff this hop may charge
+    pub basis_points: u16,
+    /// Cumulative bytes this hop's gatekeeper has attested to forwarding
+    pub bytes_reported: u64,
+}
+
+impl Hop {
+    /// This hop's share of `amount`, rounded down
+    pub fn share(&self, amount: u64) -> u64 {
+        let share =
+            u128::from(amount) * u128::from(self.basis_points) / u128::from(MAX_BASIS_POINTS);
+        share as u64
+    }
+}
+
+/// Account taking a fixed cut of every charge
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct Payee {
+    pub id: Pubkey,
+    pub basis_points: u16,
+}
+
+/// Terms the initiator agrees to by signing InitializeAccount
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct ContractTerms {
+    pub tariff: Tariff,
+    /// Only allow spends backed by a receipt signed by the initiator
+    pub receipts_required: bool,
+    /// Settle once through a payment channel; the initiator may reclaim the
+    /// balance this many slots after asking to close
+    pub channel_dispute_window: Option<u64>,
+    /// Let the initiator reclaim the balance this many slots after
+    /// initialization, in case the gatekeeper never refunds
+    pub expiry_timeout: Option<u64>,
+    /// Cuts paid out of every charge; the provider receives the rest
+    pub payees: Vec<Payee>,
+    /// Escrow bandwidth credits of this mint instead of lamports
+    pub credit_mint: Option<Pubkey>,
+    /// Distinguishes the initiator's contracts with the same gatekeeper; the
+    /// contract must live at the matching `contract_address`
+    pub session_nonce: u64,
+    /// Limit how fast the gatekeeper can drain the contract
+    pub spending_cap: Option<SpendingCap>,
+    /// Route through several gatekeepers, each charging its own share; the
+    /// first hop must be the contract's gatekeeper and provider
+    pub hops: Vec<Hop>,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct BandwidthPrepayState {
     pub gatekeeper_id: Pubkey,
     pub provider_id: Pubkey,
     pub initiator_id: Pubkey,
+    pub tariff: Tariff,
+    pub receipts_required: bool,
+    /// Cumulative bytes charged by the gatekeeper
+    pub bytes_reported: u64,
+    /// Nonce of the latest receipt a spend was settled against
+    pub receipt_nonce: u64,
+    pub payment_channel: bool,
+    pub dispute_window: u64,
+    pub close_requested: bool,
+    pub close_requested_slot: u64,
+    pub expires: bool,
+    /// Slot from which the initiator may reclaim the balance
+    pub expiry_slot: u64,
+    /// Lamports funded at initialization plus every TopUp
+    pub total_deposited: u64,
+    /// Lamports paid out of the contract by any instruction
+    pub total_withdrawn: u64,
+    pub payees: Vec<Payee>,
+    /// The contract escrows credits; provider, payees and refunds are paid
+    /// into credit accounts of `credit_mint`
+    pub credits: bool,
+    pub credit_mint: Pubkey,
+    pub credit_balance: u64,
+    /// Initiator's credit account, which receives refunds
+    pub credit_refund_id: Pubkey,
+    /// Hot keys allowed to sign for the gatekeeper
+    pub delegates: Vec<Pubkey>,
+    /// Amount paid to the provider and payees, excluding refunds
+    pub total_spent: u64,
+    pub spend_count: u64,
+    pub last_spend_slot: u64,
+    pub spending_capped: bool,
+    pub spending_cap: SpendingCap,
+    /// Slot at which the current cap window opened
+    pub window_start_slot: u64,
+    /// Amount charged since `window_start_slot`
+    pub window_spent: u64,
+    /// Gatekeepers along a multi-hop route, starting with `gatekeeper_id`;
+    /// empty for a contract served by a single gatekeeper
+    pub hops: Vec<Hop>,
+    /// Gatekeeper the contract's address was derived for, which SetGatekeeper
+    /// leaves in place; default for contracts opened before it was recorded
+    pub origin_gatekeeper_id: Pubkey,
+}
+
+/// Layout of contracts created before the state carried a version tag
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct BandwidthPrepayStateV0 {
+    pub gatekeeper_id: Pubkey,
+    pub provider_id: Pubkey,
+    pub initiator_id: Pubkey,
+}
+
+/// Size of every account holding a BandwidthPrepayStateV0
+pub const V0_STATE_SIZE: usize = 96;
+
+/// Untagged contracts were charged one lamport per KiB
+const V0_TARIFF: Tariff = Tariff {
+    lamports_per_kib: 1,
+    minimum_charge: 0,
+};
+
+impl From<BandwidthPrepayStateV0> for BandwidthPrepayState {
+    fn from(state: BandwidthPrepayStateV0) -> Self {
+        BandwidthPrepayState {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: V0_TARIFF,
+            ..BandwidthPrepayState::default()
+        }
+    }
+}
+
+/// Layout of contracts created before gatekeeper delegates
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct BandwidthPrepayStateV1 {
+    pub gatekeeper_id: Pubkey,
+    pub provider_id: Pubkey,
+    pub initiator_id: Pubkey,
+    pub tariff: Tariff,
+    pub receipts_required: bool,
+    pub bytes_reported: u64,
+    pub receipt_nonce: u64,
+    pub payment_channel: bool,
+    pub dispute_window: u64,
+    pub close_requested: bool,
+    pub close_requested_slot: u64,
+    pub expires: bool,
+    pub expiry_slot: u64,
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    pub payees: Vec<Payee>,
+    pub credits: bool,
+    pub credit_mint: Pubkey,
+    pub credit_balance: u64,
+    pub credit_refund_id: Pubkey,
+}
+
+impl From<BandwidthPrepayStateV1> for BandwidthPrepayStateV2 {
+    fn from(state: BandwidthPrepayStateV1) -> Self {
+        BandwidthPrepayStateV2 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: vec![],
+        }
+    }
+}
+
+/// Layout of contracts created before on-chain usage counters
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct BandwidthPrepayStateV2 {
+    pub gatekeeper_id: Pubkey,
+    pub provider_id: Pubkey,
+    pub initiator_id: Pubkey,
+    pub tariff: Tariff,
+    pub receipts_required: bool,
+    pub bytes_reported: u64,
+    pub receipt_nonce: u64,
+    pub payment_channel: bool,
+    pub dispute_window: u64,
+    pub close_requested: bool,
+    pub close_requested_slot: u64,
+    pub expires: bool,
+    pub expiry_slot: u64,
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    pub payees: Vec<Payee>,
+    pub credits: bool,
+    pub credit_mint: Pubkey,
+    pub credit_balance: u64,
+    pub credit_refund_id: Pubkey,
+    pub delegates: Vec<Pubkey>,
+}
+
+impl From<BandwidthPrepayStateV2> for BandwidthPrepayStateV3 {
+    fn from(state: BandwidthPrepayStateV2) -> Self {
+        BandwidthPrepayStateV3 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: state.delegates,
+            ..BandwidthPrepayStateV3::default()
+        }
+    }
+}
+
+/// Layout of contracts created before spending caps
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct BandwidthPrepayStateV3 {
+    pub gatekeeper_id: Pubkey,
+    pub provider_id: Pubkey,
+    pub initiator_id: Pubkey,
+    pub tariff: Tariff,
+    pub receipts_required: bool,
+    pub bytes_reported: u64,
+    pub receipt_nonce: u64,
+    pub payment_channel: bool,
+    pub dispute_window: u64,
+    pub close_requested: bool,
+    pub close_requested_slot: u64,
+    pub expires: bool,
+    pub expiry_slot: u64,
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    pub payees: Vec<Payee>,
+    pub credits: bool,
+    pub credit_mint: Pubkey,
+    pub credit_balance: u64,
+    pub credit_refund_id: Pubkey,
+    pub delegates: Vec<Pubkey>,
+    pub total_spent: u64,
+    pub spend_count: u64,
+    pub last_spend_slot: u64,
+}
+
+impl From<BandwidthPrepayStateV3> for BandwidthPrepayStateV4 {
+    fn from(state: BandwidthPrepayStateV3) -> Self {
+        BandwidthPrepayStateV4 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: state.delegates,
+            total_spent: state.total_spent,
+            spend_count: state.spend_count,
+            last_spend_slot: state.last_spend_slot,
+            ..BandwidthPrepayStateV4::default()
+        }
+    }
+}
+
+/// Layout of contracts created before multi-hop routes
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct BandwidthPrepayStateV4 {
+    pub gatekeeper_id: Pubkey,
+    pub provider_id: Pubkey,
+    pub initiator_id: Pubkey,
+    pub tariff: Tariff,
+    pub receipts_required: bool,
+    pub bytes_reported: u64,
+    pub receipt_nonce: u64,
+    pub payment_channel: bool,
+    pub dispute_window: u64,
+    pub close_requested: bool,
+    pub close_requested_slot: u64,
+    pub expires: bool,
+    pub expiry_slot: u64,
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    pub payees: Vec<Payee>,
+    pub credits: bool,
+    pub credit_mint: Pubkey,
+    pub credit_balance: u64,
+    pub credit_refund_id: Pubkey,
+    pub delegates: Vec<Pubkey>,
+    pub total_spent: u64,
+    pub spend_count: u64,
+    pub last_spend_slot: u64,
+    pub spending_capped: bool,
+    pub spending_cap: SpendingCap,
+    pub window_start_slot: u64,
+    pub window_spent: u64,
+}
+
+impl From<BandwidthPrepayStateV4> for BandwidthPrepayStateV5 {
+    fn from(state: BandwidthPrepayStateV4) -> Self {
+        BandwidthPrepayStateV5 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: state.delegates,
+            total_spent: state.total_spent,
+            spend_count: state.spend_count,
+            last_spend_slot: state.last_spend_slot,
+            spending_capped: state.spending_capped,
+            spending_cap: state.spending_cap,
+            window_start_slot: state.window_start_slot,
+            window_spent: state.window_spent,
+            ..BandwidthPrepayStateV5::default()
+        }
+    }
+}
+
+/// Layout of contracts created before they recorded the gatekeeper their
+/// address was derived for
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct BandwidthPrepayStateV5 {
+    pub gatekeeper_id: Pubkey,
+    pub provider_id: Pubkey,
+    pub initiator_id: Pubkey,
+    pub tariff: Tariff,
+    pub receipts_required: bool,
+    pub bytes_reported: u64,
+    pub receipt_nonce: u64,
+    pub payment_channel: bool,
+    pub dispute_window: u64,
+    pub close_requested: bool,
+    pub close_requested_slot: u64,
+    pub expires: bool,
+    pub expiry_slot: u64,
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    pub payees: Vec<Payee>,
+    pub credits: bool,
+    pub credit_mint: Pubkey,
+    pub credit_balance: u64,
+    pub credit_refund_id: Pubkey,
+    pub delegates: Vec<Pubkey>,
+    pub total_spent: u64,
+    pub spend_count: u64,
+    pub last_spend_slot: u64,
+    pub spending_capped: bool,
+    pub spending_cap: SpendingCap,
+    pub window_start_slot: u64,
+    pub window_spent: u64,
+    pub hops: Vec<Hop>,
+}
+
+impl From<BandwidthPrepayStateV5> for BandwidthPrepayState {
+    fn from(state: BandwidthPrepayStateV5) -> Self {
+        BandwidthPrepayState {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: state.delegates,
+            total_spent: state.total_spent,
+            spend_count: state.spend_count,
+            last_spend_slot: state.last_spend_slot,
+            spending_capped: state.spending_capped,
+            spending_cap: state.spending_cap,
+            window_start_slot: state.window_start_slot,
+            window_spent: state.window_spent,
+            hops: state.hops,
+            ..BandwidthPrepayState::default()
+        }
+    }
+}
+
+// Contracts the current layout doesn't fit in are written back in the layout
+// they were created with. Counters an older layout lacks are dropped; state it
+// can't represent is refused, since the contract would lose its terms.
+
+impl TryFrom<BandwidthPrepayState> for BandwidthPrepayStateV5 {
+    type Error = BandwidthPrepayError;
+
+    fn try_from(state: BandwidthPrepayState) -> Result<Self, Self::Error> {
+        // The older layout can only stand for an origin that is the gatekeeper itself
+        if state.origin_gatekeeper_id != Pubkey::default()
+            && state.origin_gatekeeper_id != state.gatekeeper_id
+        {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        Ok(BandwidthPrepayStateV5 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: state.delegates,
+            total_spent: state.total_spent,
+            spend_count: state.spend_count,
+            last_spend_slot: state.last_spend_slot,
+            spending_capped: state.spending_capped,
+            spending_cap: state.spending_cap,
+            window_start_slot: state.window_start_slot,
+            window_spent: state.window_spent,
+            hops: state.hops,
+        })
+    }
+}
+
+impl TryFrom<BandwidthPrepayStateV5> for BandwidthPrepayStateV4 {
+    type Error = BandwidthPrepayError;
+
+    fn try_from(state: BandwidthPrepayStateV5) -> Result<Self, Self::Error> {
+        if !state.hops.is_empty() {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        Ok(BandwidthPrepayStateV4 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: state.delegates,
+            total_spent: state.total_spent,
+            spend_count: state.spend_count,
+            last_spend_slot: state.last_spend_slot,
+            spending_capped: state.spending_capped,
+            spending_cap: state.spending_cap,
+            window_start_slot: state.window_start_slot,
+            window_spent: state.window_spent,
+        })
+    }
+}
+
+impl TryFrom<BandwidthPrepayStateV4> for BandwidthPrepayStateV3 {
+    type Error = BandwidthPrepayError;
+
+    fn try_from(state: BandwidthPrepayStateV4) -> Result<Self, Self::Error> {
+        if state.spending_capped {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        Ok(BandwidthPrepayStateV3 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: state.delegates,
+            total_spent: state.total_spent,
+            spend_count: state.spend_count,
+            last_spend_slot: state.last_spend_slot,
+        })
+    }
+}
+
+impl From<BandwidthPrepayStateV3> for BandwidthPrepayStateV2 {
+    fn from(state: BandwidthPrepayStateV3) -> Self {
+        BandwidthPrepayStateV2 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+            delegates: state.delegates,
+        }
+    }
+}
+
+impl TryFrom<BandwidthPrepayStateV2> for BandwidthPrepayStateV1 {
+    type Error = BandwidthPrepayError;
+
+    fn try_from(state: BandwidthPrepayStateV2) -> Result<Self, Self::Error> {
+        if !state.delegates.is_empty() {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        Ok(BandwidthPrepayStateV1 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+            tariff: state.tariff,
+            receipts_required: state.receipts_required,
+            bytes_reported: state.bytes_reported,
+            receipt_nonce: state.receipt_nonce,
+            payment_channel: state.payment_channel,
+            dispute_window: state.dispute_window,
+            close_requested: state.close_requested,
+            close_requested_slot: state.close_requested_slot,
+            expires: state.expires,
+            expiry_slot: state.expiry_slot,
+            total_deposited: state.total_deposited,
+            total_withdrawn: state.total_withdrawn,
+            payees: state.payees,
+            credits: state.credits,
+            credit_mint: state.credit_mint,
+            credit_balance: state.credit_balance,
+            credit_refund_id: state.credit_refund_id,
+        })
+    }
+}
+
+impl TryFrom<BandwidthPrepayStateV1> for BandwidthPrepayStateV0 {
+    type Error = BandwidthPrepayError;
+
+    fn try_from(state: BandwidthPrepayStateV1) -> Result<Self, Self::Error> {
+        if state == BandwidthPrepayStateV1::default() {
+            return Ok(BandwidthPrepayStateV0::default());
+        }
+        // Untagged contracts only ever charged the fixed tariff, straight to the provider
+        if state.tariff != V0_TARIFF
+            || state.receipts_required
+            || state.payment_channel
+            || state.expires
+            || !state.payees.is_empty()
+            || state.credits
+        {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        Ok(BandwidthPrepayStateV0 {
+            gatekeeper_id: state.gatekeeper_id,
+            provider_id: state.provider_id,
+            initiator_id: state.initiator_id,
+        })
+    }
+}
+
+/// Serialized contract state, led by a discriminator naming its layout. New
+/// layouts are added as new variants so older accounts keep deserializing.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum VersionedBandwidthPrepayState {
+    V1(BandwidthPrepayStateV1),
+    V2(BandwidthPrepayStateV2),
+    V3(BandwidthPrepayStateV3),
+    V4(BandwidthPrepayStateV4),
+    V5(BandwidthPrepayStateV5),
+    V6(BandwidthPrepayState),
 }
 
 impl BandwidthPrepayState {
+    /// Decode any historical layout into the current one
     pub fn deserialize(input: &[u8]) -> Result<Self, BandwidthPrepayError> {
-        deserialize(input).map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure)
+        if Self::is_v0(input) {
+            let state: BandwidthPrepayStateV0 =
+                deserialize(input).map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure)?;
+            // A zeroed untagged account was closed
+            if state == BandwidthPrepayStateV0::default() {
+                return Ok(BandwidthPrepayState::default());
+            }
+            return Ok(state.into());
+        }
+        match deserialize(input).map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure)? {
+            VersionedBandwidthPrepayState::V1(state) => {
+                let state = BandwidthPrepayStateV3::from(BandwidthPrepayStateV2::from(state));
+                let state = BandwidthPrepayStateV4::from(state);
+                Ok(BandwidthPrepayStateV5::from(state).into())
+            }
+            VersionedBandwidthPrepayState::V2(state) => {
+                let state = BandwidthPrepayStateV4::from(BandwidthPrepayStateV3::from(state));
+                Ok(BandwidthPrepayStateV5::from(state).into())
+            }
+            VersionedBandwidthPrepayState::V3(state) => {
+                let state = BandwidthPrepayStateV4::from(state);
+                Ok(BandwidthPrepayStateV5::from(state).into())
+            }
+            VersionedBandwidthPrepayState::V4(state) => {
+                Ok(BandwidthPrepayStateV5::from(state).into())
+            }
+            VersionedBandwidthPrepayState::V5(state) => Ok(state.into()),
+            VersionedBandwidthPrepayState::V6(state) => Ok(state),
+        }
     }
 
+    /// Write the state in the current layout or, if that doesn't fit in an
+    /// account created under an older one, in the layout the account holds
     pub fn serialize(&self, output: &mut [u8]) -> Result<(), BandwidthPrepayError> {
-        serialize_into(output, self).map_err(|_| BandwidthPrepayError::UserdataTooSmall)
+        if self.fits(output) {
+            return self.serialize_current(output);
+        }
+        let state = self.clone();
+        if Self::is_v0(output) {
+            let state = BandwidthPrepayStateV0::try_from(Self::into_v1(state)?)?;
+            return serialize_into(output, &state)
+                .map_err(|_| BandwidthPrepayError::UserdataTooSmall);
+        }
+        // A zeroed account holds no layout to fall back on
+        if output.iter().all(|&byte| byte == 0) {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        let versioned_state = match deserialize(output)
+            .map_err(|_| BandwidthPrepayError::UserdataDeserializeFailure)?
+        {
+            VersionedBandwidthPrepayState::V1(_) => {
+                VersionedBandwidthPrepayState::V1(Self::into_v1(state)?)
+            }
+            VersionedBandwidthPrepayState::V2(_) => {
+                VersionedBandwidthPrepayState::V2(Self::into_v3(state)?.into())
+            }
+            VersionedBandwidthPrepayState::V3(_) => {
+                VersionedBandwidthPrepayState::V3(Self::into_v3(state)?)
+            }
+            VersionedBandwidthPrepayState::V4(_) => {
+                let state = BandwidthPrepayStateV5::try_from(state)?;
+                VersionedBandwidthPrepayState::V4(BandwidthPrepayStateV4::try_from(state)?)
+            }
+            VersionedBandwidthPrepayState::V5(_) => {
+                VersionedBandwidthPrepayState::V5(BandwidthPrepayStateV5::try_from(state)?)
+            }
+            VersionedBandwidthPrepayState::V6(_) => {
+                return Err(BandwidthPrepayError::UserdataTooSmall)
+            }
+        };
+        serialize_into(output, &versioned_state).map_err(|_| BandwidthPrepayError::UserdataTooSmall)
+    }
+
+    /// Write the state in the current layout, whatever the account holds
+    pub fn serialize_current(&self, output: &mut [u8]) -> Result<(), BandwidthPrepayError> {
+        if !self.fits(output) {
+            Err(BandwidthPrepayError::UserdataTooSmall)?
+        }
+        let versioned_state = VersionedBandwidthPrepayState::V6(self.clone());
+        serialize_into(output, &versioned_state).map_err(|_| BandwidthPrepayError::UserdataTooSmall)
+    }
+
+    /// Whether the state fits in `output` in the current layout. Checked up
+    /// front, since a failed write leaves the account partly overwritten.
+    fn fits(&self, output: &[u8]) -> bool {
+        let versioned_state = VersionedBandwidthPrepayState::V6(self.clone());
+        serialized_size(&versioned_state).map_or(false, |size| size as usize <= output.len())
+    }
+
+    fn into_v3(state: Self) -> Result<BandwidthPrepayStateV3, BandwidthPrepayError> {
+        let state = BandwidthPrepayStateV4::try_from(BandwidthPrepayStateV5::try_from(state)?)?;
+        BandwidthPrepayStateV3::try_from(state)
+    }
+
+    fn into_v1(state: Self) -> Result<BandwidthPrepayStateV1, BandwidthPrepayError> {
+        BandwidthPrepayStateV1::try_from(BandwidthPrepayStateV2::from(Self::into_v3(state)?))
     }
 
     pub fn max_size() -> usize {
-        let bandwidth_prepay_state = BandwidthPrepayState::default();
-        serialized_size(&bandwidth_prepay_state).unwrap() as usize
+        let versioned_state = VersionedBandwidthPrepayState::V6(BandwidthPrepayState {
+            payees: vec![Payee::default(); MAX_PAYEES],
+            delegates: vec![Pubkey::default(); MAX_DELEGATES],
+            hops: vec![Hop::default(); MAX_HOPS],
+            ..BandwidthPrepayState::default()
+        });
+        serialized_size(&versioned_state).unwrap() as usize
+    }
+
+    /// Untagged accounts are told apart by size, since account data never shrinks
+    pub fn is_v0(input: &[u8]) -> bool {
+        input.len() == V0_STATE_SIZE
+    }
+
+    /// Whether Migrate can rewrite the account in the current layout: it holds
+    /// an older one and the current one fits in the space it was created with
+    pub fn can_migrate(input: &[u8]) -> bool {
+        Self::needs_migration(input)
+            && Self::deserialize(input).map_or(false, |state| state.fits(input))
+    }
+
+    /// Whether the account holds state in an older layout; zeroed accounts,
+    /// fresh or closed, have nothing to rewrite
+    pub fn needs_migration(input: &[u8]) -> bool {
+        if Self::is_v0(input) {
+            return true;
+        }
+        match deserialize(input) {
+            Ok(VersionedBandwidthPrepayState::V6(_)) | Err(_) => false,
+            Ok(_) => input.iter().any(|&byte| byte != 0),
+        }
+    }
+
+    /// Lamports the contract should hold according to its deposits and withdrawals
+    pub fn expected_balance(&self) -> u64 {
+        self.total_deposited.saturating_sub(self.total_withdrawn)
+    }
+
+    /// Escrowed balance, given the lamports held by the contract account
+    pub fn balance(&self, lamports: u64) -> u64 {
+        if self.credits {
+            self.credit_balance
+        } else {
+            lamports
+        }
+    }
+
+    /// Account that receives whatever is left in the contract
+    pub fn refund_id(&self) -> &Pubkey {
+        if self.credits {
+            &self.credit_refund_id
+        } else {
+            &self.initiator_id
+        }
+    }
+
+    /// Record `amount` paid out of the contract at `slot`
+    pub fn record_spend(&mut self, amount: u64, slot: u64) {
+        if self.window_elapsed(slot) {
+            self.window_start_slot = slot;
+            self.window_spent = 0;
+        }
+        self.total_withdrawn += amount;
+        self.total_spent += amount;
+        self.spend_count += 1;
+        self.last_spend_slot = slot;
+        self.window_spent += amount;
+    }
+
+    /// Whether charging `amount` at `slot` stays within the spending cap
+    pub fn within_spending_cap(&self, amount: u64, slot: u64) -> bool {
+        if !self.spending_capped {
+            return true;
+        }
+        let window_spent = if self.window_elapsed(slot) {
+            0
+        } else {
+            self.window_spent
+        };
+        window_spent
+            .checked_add(amount)
+            .map_or(false, |spent| spent <= self.spending_cap.lamports)
+    }
+
+    fn window_elapsed(&self, slot: u64) -> bool {
+        slot >= self
+            .window_start_slot
+            .saturating_add(self.spending_cap.slots)
+    }
+
+    /// Position in `hops` of the hop whose gatekeeper is `signer`
+    pub fn hop_index(&self, signer: &Pubkey) -> Option<usize> {
+        self.hops
+            .iter()
+            .position(|hop| &hop.gatekeeper_id == signer)
+    }
+
+    /// Account paid for what `gatekeeper_id` forwards
+    pub fn provider_for(&self, gatekeeper_id: &Pubkey) -> &Pubkey {
+        match self.hop_index(gatekeeper_id) {
+            Some(index) => &self.hops[index].provider_id,
+            None => &self.provider_id,
+        }
+    }
+
+    /// Gatekeeper the contract's address was derived for
+    pub fn address_gatekeeper_id(&self) -> &Pubkey {
+        if self.origin_gatekeeper_id == Pubkey::default() {
+            &self.gatekeeper_id
+        } else {
+            &self.origin_gatekeeper_id
+        }
+    }
+
+    /// Whether `signer` is the gatekeeper or one of its delegates
+    pub fn is_gatekeeper(&self, signer: &Pubkey) -> bool {
+        signer == &self.gatekeeper_id || self.delegates.contains(signer)
+    }
+
+    pub fn payee_ids(&self) -> Vec<Pubkey> {
+        self.payees.iter().map(|payee| payee.id).collect()
+    }
+
+    /// Each payee's cut of `amount`, rounded down so any remainder stays with the provider
+    pub fn payee_cuts(&self, amount: u64) -> Vec<u64> {
+        self.payees
+            .iter()
+            .map(|payee| {
+                let cut = u128::from(amount) * u128::from(payee.basis_points)
+                    / u128::from(MAX_BASIS_POINTS);
+                cut as u64
+            })
+            .collect()
     }
 }
 
@@ -54,18 +1170,318 @@ mod test {
     #[test]
     fn test_max_size() {
         let number = BandwidthPrepayState::max_size();
-        assert_eq!(number, 96);
+        assert_eq!(number, 922);
+    }
+
+    #[test]
+    fn test_from_custom_code() {
+        let errors = [
+            BandwidthPrepayError::AlreadyInitialized,
+            BandwidthPrepayError::BalanceTooLow,
+            BandwidthPrepayError::TooManyDelegates,
+        ];
+        for error in errors.iter() {
+            assert_eq!(
+                BandwidthPrepayError::from_custom_code(error.clone() as u32),
+                Some(error.clone())
+            );
+        }
+        assert_eq!(
+            BandwidthPrepayError::from_custom_code(u32::max_value()),
+            None
+        );
+
+        let err = TransactionError::InstructionError(
+            1,
+            InstructionError::CustomError(BandwidthPrepayError::BalanceTooLow as u32),
+        );
+        assert_eq!(
+            BandwidthPrepayError::from_transaction_error(&err),
+            Some(BandwidthPrepayError::BalanceTooLow)
+        );
+        assert_eq!(
+            BandwidthPrepayError::from_transaction_error(&TransactionError::AccountInUse),
+            None
+        );
+        assert_eq!(
+            BandwidthPrepayError::BalanceTooLow.to_string(),
+            "BalanceTooLow: contract balance is too low"
+        );
+    }
+
+    #[test]
+    fn test_tariff_charge() {
+        let tariff = Tariff {
+            lamports_per_kib: 2,
+            minimum_charge: 5,
+        };
+        assert_eq!(tariff.charge(0), Some(0));
+        assert_eq!(tariff.charge(1024), Some(5));
+        assert_eq!(tariff.charge(4096), Some(8));
+        assert_eq!(tariff.charge(4097), Some(8));
+        assert_eq!(tariff.charge(u64::max_value()), None);
+    }
+
+    #[test]
+    fn test_tariff_charge_after() {
+        let tariff = Tariff {
+            lamports_per_kib: 3,
+            minimum_charge: 0,
+        };
+        assert_eq!(tariff.charge_after(100, 0), Some(0));
+        // Four 256-byte charges pay for the KiB they add up to
+        let charges: Vec<_> = (0..4)
+            .map(|i| tariff.charge_after(i * 256, 256).unwrap())
+            .collect();
+        assert_eq!(charges, vec![0, 1, 1, 1]);
+        assert_eq!(tariff.charge_after(0, 1024), tariff.charge(1024));
+        assert_eq!(tariff.charge_after(u64::max_value(), 1), None);
+
+        let tariff = Tariff {
+            lamports_per_kib: 3,
+            minimum_charge: 2,
+        };
+        assert_eq!(tariff.charge_after(256, 256), Some(2));
+        assert_eq!(tariff.charge_after(1024, 4096), Some(12));
+
+        // A hop's share carries its fractions over too
+        let tariff = Tariff {
+            lamports_per_kib: 1,
+            minimum_charge: 0,
+        };
+        let shares: Vec<_> = (0..4)
+            .map(|i| tariff.share_after(5_000, i * 1024, 1024).unwrap())
+            .collect();
+        assert_eq!(shares, vec![0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_payee_cuts() {
+        let state = BandwidthPrepayState {
+            payees: vec![
+                Payee {
+                    id: Pubkey::new_rand(),
+                    basis_points: 2_500,
+                },
+                Payee {
+                    id: Pubkey::new_rand(),
+                    basis_points: 3_333,
+                },
+            ],
+            ..BandwidthPrepayState::default()
+        };
+        assert_eq!(state.payee_cuts(0), vec![0, 0]);
+        assert_eq!(state.payee_cuts(10), vec![2, 3]);
+        assert_eq!(state.payee_cuts(10_000), vec![2_500, 3_333]);
+        assert_eq!(
+            state.payee_cuts(u64::max_value()),
+            vec![u64::max_value() / 4, 6_148_299_799_767_393_553]
+        );
     }
 
     #[test]
     fn test_serializer() {
-        let mut a = Account::new(0, 96, &id());
+        let mut a = Account::new(0, 890, &id());
         let b = BandwidthPrepayState::default();
         b.serialize(&mut a.data).unwrap();
         let c = BandwidthPrepayState::deserialize(&a.data).unwrap();
         assert_eq!(b, c);
     }
 
+    #[test]
+    fn test_deserialize_v0() {
+        let v0 = BandwidthPrepayStateV0 {
+            gatekeeper_id: Pubkey::new_rand(),
+            provider_id: Pubkey::new_rand(),
+            initiator_id: Pubkey::new_rand(),
+        };
+        let data = bincode::serialize(&v0).unwrap();
+        assert_eq!(data.len(), V0_STATE_SIZE);
+        assert!(BandwidthPrepayState::needs_migration(&data));
+
+        let state = BandwidthPrepayState::deserialize(&data).unwrap();
+        assert_eq!(state.gatekeeper_id, v0.gatekeeper_id);
+        assert_eq!(state.provider_id, v0.provider_id);
+        assert_eq!(state.initiator_id, v0.initiator_id);
+        assert_eq!(state.tariff, V0_TARIFF);
+
+        // V0 accounts are too small for the current layout, so they're written
+        // back in their own, without the counters it lacks
+        let mut data = data;
+        let mut state = state;
+        state.bytes_reported = 1024;
+        state.total_withdrawn = 1;
+        state.serialize(&mut data).unwrap();
+        assert_eq!(data, bincode::serialize(&v0).unwrap());
+        assert_eq!(
+            state.serialize_current(&mut data),
+            Err(BandwidthPrepayError::UserdataTooSmall)
+        );
+
+        // Terms the untagged layout can't hold are refused
+        state.payees = vec![Payee::default()];
+        assert_eq!(
+            state.serialize(&mut data),
+            Err(BandwidthPrepayError::UserdataTooSmall)
+        );
+
+        // Closing zeroes it
+        BandwidthPrepayState::default()
+            .serialize(&mut data)
+            .unwrap();
+        assert_eq!(data, vec![0; V0_STATE_SIZE]);
+        assert_eq!(
+            BandwidthPrepayState::deserialize(&data).unwrap(),
+            BandwidthPrepayState::default()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_v1() {
+        let v1 = BandwidthPrepayStateV1 {
+            gatekeeper_id: Pubkey::new_rand(),
+            total_deposited: 42,
+            ..BandwidthPrepayStateV1::default()
+        };
+        let mut data = vec![0; 393];
+        bincode::serialize_into(
+            &mut data[..],
+            &VersionedBandwidthPrepayState::V1(v1.clone()),
+        )
+        .unwrap();
+        assert!(!BandwidthPrepayState::is_v0(&data));
+        assert!(BandwidthPrepayState::needs_migration(&data));
+
+        let state = BandwidthPrepayState::deserialize(&data).unwrap();
+        assert_eq!(state.gatekeeper_id, v1.gatekeeper_id);
+        assert_eq!(state.total_deposited, 42);
+        assert!(state.delegates.is_empty());
+        assert!(BandwidthPrepayState::can_migrate(&data));
+
+        // With every payee filled in, the current layout no longer fits
+        let mut state = BandwidthPrepayState {
+            payees: vec![Payee::default(); MAX_PAYEES],
+            ..state
+        };
+        state.serialize(&mut data).unwrap();
+        assert!(!BandwidthPrepayState::can_migrate(&data));
+        match deserialize(&data).unwrap() {
+            VersionedBandwidthPrepayState::V1(stored) => assert_eq!(stored.payees, state.payees),
+            stored => panic!("unexpected layout: {:?}", stored),
+        }
+        state.delegates = vec![Pubkey::new_rand()];
+        assert_eq!(
+            state.serialize(&mut data),
+            Err(BandwidthPrepayError::UserdataTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_v2() {
+        let v2 = BandwidthPrepayStateV2 {
+            total_withdrawn: 42,
+            delegates: vec![Pubkey::new_rand()],
+            ..BandwidthPrepayStateV2::default()
+        };
+        let mut data = vec![0; 529];
+        bincode::serialize_into(
+            &mut data[..],
+            &VersionedBandwidthPrepayState::V2(v2.clone()),
+        )
+        .unwrap();
+        assert!(BandwidthPrepayState::needs_migration(&data));
+
+        let state = BandwidthPrepayState::deserialize(&data).unwrap();
+        assert_eq!(state.total_withdrawn, 42);
+        assert_eq!(state.delegates, v2.delegates);
+        assert_eq!(state.total_spent, 0);
+        assert_eq!(state.spend_count, 0);
+
+        // Once rewritten in the current layout it is migrated, whatever its size
+        state.serialize(&mut data).unwrap();
+        assert!(!BandwidthPrepayState::needs_migration(&data));
+    }
+
+    #[test]
+    fn test_within_spending_cap() {
+        let mut state = BandwidthPrepayState::default();
+        assert!(state.within_spending_cap(u64::max_value(), 0));
+
+        state.spending_capped = true;
+        state.spending_cap = SpendingCap {
+            lamports: 100,
+            slots: 10,
+        };
+        state.window_start_slot = 5;
+        assert!(state.within_spending_cap(100, 5));
+        assert!(!state.within_spending_cap(101, 5));
+
+        state.record_spend(60, 5);
+        assert!(state.within_spending_cap(40, 14));
+        assert!(!state.within_spending_cap(41, 14));
+
+        // A new window opens once `slots` have passed
+        assert!(state.within_spending_cap(100, 15));
+        state.record_spend(100, 15);
+        assert_eq!(state.window_start_slot, 15);
+        assert_eq!(state.window_spent, 100);
+        assert!(!state.within_spending_cap(1, 24));
+    }
+
+    #[test]
+    fn test_is_gatekeeper() {
+        let delegate = Pubkey::new_rand();
+        let state = BandwidthPrepayState {
+            gatekeeper_id: Pubkey::new_rand(),
+            delegates: vec![delegate],
+            ..BandwidthPrepayState::default()
+        };
+        assert!(state.is_gatekeeper(&state.gatekeeper_id));
+        assert!(state.is_gatekeeper(&delegate));
+        assert!(!state.is_gatekeeper(&Pubkey::new_rand()));
+    }
+
+    #[test]
+    fn test_hops() {
+        let edge = Hop {
+            gatekeeper_id: Pubkey::new_rand(),
+            provider_id: Pubkey::new_rand(),
+            basis_points: 3_000,
+            bytes_reported: 0,
+        };
+        let regional = Hop {
+            gatekeeper_id: Pubkey::new_rand(),
+            provider_id: Pubkey::new_rand(),
+            basis_points: 7_000,
+            bytes_reported: 0,
+        };
+        let state = BandwidthPrepayState {
+            gatekeeper_id: edge.gatekeeper_id,
+            provider_id: edge.provider_id,
+            hops: vec![edge, regional],
+            ..BandwidthPrepayState::default()
+        };
+        assert_eq!(state.hop_index(&regional.gatekeeper_id), Some(1));
+        assert_eq!(state.hop_index(&Pubkey::new_rand()), None);
+        assert_eq!(
+            state.provider_for(&regional.gatekeeper_id),
+            &regional.provider_id
+        );
+        assert_eq!(state.provider_for(&edge.gatekeeper_id), &edge.provider_id);
+        assert_eq!(edge.share(101), 30);
+        assert_eq!(regional.share(101), 70);
+    }
+
+    #[test]
+    fn test_deserialize_zeroed() {
+        let data = vec![0; BandwidthPrepayState::max_size()];
+        assert!(!BandwidthPrepayState::needs_migration(&data));
+        assert_eq!(
+            BandwidthPrepayState::deserialize(&data).unwrap(),
+            BandwidthPrepayState::default()
+        );
+    }
+
     #[test]
     fn test_serializer_userdata_too_small() {
         let mut a = Account::new(0, 1, &id());
@@ -1,5 +1,9 @@
+pub mod bandwidth_prepay_address;
+pub mod bandwidth_prepay_channel;
+pub mod bandwidth_prepay_credit;
 pub mod bandwidth_prepay_instruction;
 pub mod bandwidth_prepay_processor;
+pub mod bandwidth_prepay_receipt;
 pub mod bandwidth_prepay_state;
 
 const BANDWIDTH_PREPAY_PROGRAM_ID: [u8; 32] = [
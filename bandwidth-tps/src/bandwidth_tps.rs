@@ -1,9 +1,12 @@
 use crate::cli::Config;
 use crate::gen_keys::GenKeys;
+use bandwidth_prepay_api::bandwidth_prepay_address::contract_address;
 use bandwidth_prepay_api::bandwidth_prepay_instruction;
+use bandwidth_prepay_api::bandwidth_prepay_state::{ContractTerms, Tariff};
 use gatekeeper::accumulator::Accumulator;
-use gatekeeper::connection_params::NewConnParams;
-use gatekeeper::contract::{check_contract, submit_transaction_loop};
+use gatekeeper::batcher::start_spend_batcher;
+use gatekeeper::connection_params::{NewConnParams, DEFAULT_CONNECT_TIMEOUT};
+use gatekeeper::contract::check_contract;
 use gatekeeper::gatekeeper::process_data;
 use log::*;
 use pubsub_client::client::start_pubsub;
@@ -12,11 +15,9 @@ use solana_sdk::client::Client;
 use solana_sdk::message::Message;
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
-use solana_sdk::system_instruction;
 use solana_sdk::transport::Result as TransportResult;
-use std::sync::mpsc::channel;
 use std::sync::Arc;
-use std::thread::{self, sleep, Builder};
+use std::thread::{sleep, Builder};
 use std::time::Duration;
 
 pub fn do_bandwidth_tps<T>(
@@ -39,6 +40,17 @@ where
     let client = Arc::new(client);
     let gatekeeper_keypairs: Vec<_> = gatekeeper_keypairs.into_iter().map(Arc::new).collect();
     let client_keypairs: Vec<_> = client_keypairs.into_iter().map(Arc::new).collect();
+    // One batcher per gateway settles all of its contracts together
+    let (charge_senders, batchers): (Vec<_>, Vec<_>) = gatekeeper_keypairs
+        .iter()
+        .map(|gatekeeper| {
+            start_spend_batcher(
+                client.clone(),
+                gatekeeper.clone(),
+                Duration::from_millis(u64::from(fee_interval)),
+            )
+        })
+        .unzip();
 
     let threads: Vec<_> = contracts
         .into_iter()
@@ -47,6 +59,7 @@ where
             let client = client.clone();
             let gatekeeper_index = (i + 1) % num_gateways as usize;
             let gatekeeper = gatekeeper_keypairs[gatekeeper_index].clone();
+            let charge_sender = charge_senders[gatekeeper_index].clone();
             let client_keypairs = client_keypairs.clone();
             let refund_lamports = config.lamports / 5;
             Builder::new()
@@ -56,6 +69,8 @@ where
                         contract_pubkey,
                         destination: "somewhere".to_string(),
                         fee_interval,
+                        next_hop: None,
+                        connect_timeout: DEFAULT_CONNECT_TIMEOUT,
                     };
 
                     let pubsub_thread = start_pubsub(
@@ -65,19 +80,12 @@ where
                     )
                     .unwrap();
 
-                    let (solana_sender, solana_receiver) = channel();
-                    thread::spawn(move || {
-                        submit_transaction_loop(&solana_receiver);
-                    });
-
                     let (balance, contract_state) =
                         check_contract(&params, &client, &gatekeeper.pubkey()).unwrap();
 
                     let mut accumulator = Accumulator::default();
                     accumulator.initiator_fund = balance;
 
-                    // Offset contract payments to decrease AccountInUse errors paying provider
-                    sleep(Duration::from_millis(150 * i as u64));
                     let mut counter = 0;
                     loop {
                         if process_data(
@@ -88,7 +96,7 @@ where
                             &mut accumulator,
                             &pubsub_thread.receiver,
                             1024,
-                            &solana_sender,
+                            &charge_sender,
                         ) {
                             break;
                         }
@@ -106,21 +114,32 @@ where
                         }
                         sleep(Duration::from_millis(100));
                     }
-                    info!(
-                        "Bytes transmitted via gatekeeper {}: {}",
-                        gatekeeper.pubkey(),
-                        accumulator.total_data_amount
-                    );
+                    (gatekeeper.pubkey(), accumulator.total_data_amount)
                 })
                 .unwrap()
         })
         .collect();
 
+    let mut transmitted = vec![];
     for t in threads {
-        if let Err(err) = t.join() {
-            println!("  join() failed with: {:?}", err);
+        match t.join() {
+            Ok(bytes) => transmitted.push(bytes),
+            Err(err) => println!("  join() failed with: {:?}", err),
+        }
+    }
+    // Closing the queues lets each batcher send what it still holds and stop
+    drop(charge_senders);
+    for batcher in batchers {
+        if batcher.join().is_err() {
+            error!("Spend batcher stopped before confirming every charge");
         }
     }
+    for (gatekeeper_pubkey, total_data_amount) in transmitted {
+        info!(
+            "Bytes transmitted via gatekeeper {}: {}",
+            gatekeeper_pubkey, total_data_amount
+        );
+    }
 
     Ok(())
 }
@@ -132,9 +151,9 @@ fn fund_contract<T: Client>(
     lamports: u64,
 ) -> TransportResult<Signature> {
     let (blockhash, _) = client.get_recent_blockhash().unwrap();
-    let instruction =
-        system_instruction::transfer(&client_keypair.pubkey(), contract_pubkey, lamports);
-    let message = Message::new(vec![instruction]);
+    let instructions =
+        bandwidth_prepay_instruction::top_up(&client_keypair.pubkey(), contract_pubkey, lamports);
+    let message = Message::new(instructions);
     let signature = client.async_send_message(&[&client_keypair], message, blockhash)?;
     client.get_signature_status(&signature)?;
     Ok(signature)
@@ -144,19 +163,25 @@ pub fn initialize_contracts<T: Client>(
     client: &T,
     client_keypairs: &[Keypair],
     lamports: u64,
+    tariff: Tariff,
     provider: &Pubkey,
     gatekeeper_keypairs: &[Keypair],
 ) -> TransportResult<Vec<(Pubkey, Signature)>> {
     let (blockhash, _) = client.get_recent_blockhash().unwrap();
     let mut contracts = Vec::new();
     for (i, keypair) in client_keypairs.iter().enumerate() {
-        let gatekeeper_index = (i + 1) % gatekeeper_keypairs.len();
-        let contract_pubkey = Pubkey::new_rand();
+        let gatekeeper_pubkey = gatekeeper_keypairs[(i + 1) % gatekeeper_keypairs.len()].pubkey();
+        // Client keypairs are generated per run, so each starts at session 0
+        let contract_pubkey = contract_address(&keypair.pubkey(), &gatekeeper_pubkey, 0);
         let instructions = bandwidth_prepay_instruction::initialize(
             &keypair.pubkey(),
             &contract_pubkey,
-            &gatekeeper_keypairs[gatekeeper_index].pubkey(),
+            &gatekeeper_pubkey,
             provider,
+            ContractTerms {
+                tariff,
+                ..ContractTerms::default()
+            },
             lamports,
         );
         let message = Message::new(instructions);
@@ -243,6 +268,10 @@ mod tests {
             &bank_client,
             &client_keypairs,
             90,
+            Tariff {
+                lamports_per_kib: 1,
+                minimum_charge: 0,
+            },
             &provider,
             &gatekeeper_keypairs,
         )
@@ -257,6 +286,7 @@ mod tests {
         assert_eq!(state.gatekeeper_id, gatekeeper_keypairs[0].pubkey());
         assert_eq!(state.provider_id, provider);
         assert_eq!(state.initiator_id, client_keypairs[0].pubkey());
+        assert_eq!(state.tariff.lamports_per_kib, 1);
     }
 
     #[test]
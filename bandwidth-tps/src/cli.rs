@@ -13,6 +13,7 @@ pub struct Config {
     pub num_clients: u8,
     pub fee_interval: u16,
     pub lamports: u64,
+    pub lamports_per_kib: u64,
     pub provider: Pubkey,
 }
 
@@ -27,6 +28,7 @@ impl Default for Config {
             num_clients: 4,
             fee_interval: 1000,
             lamports: 100_000,
+            lamports_per_kib: 1,
             provider: Pubkey::new_rand(),
         }
     }
@@ -77,6 +79,13 @@ pub fn build_args<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .help("Number of lamports to fund each contract with; default is 100_000"),
         )
+        .arg(
+            Arg::with_name("lamports_per_kib")
+                .long("lamports-per-kib")
+                .value_name("NUM")
+                .takes_value(true)
+                .help("Tariff each contract agrees to, in lamports per KiB; default is 1"),
+        )
         .arg(
             Arg::with_name("provider")
                 .short("p")
@@ -117,6 +126,12 @@ pub fn extract_args<'a>(matches: &ArgMatches<'a>) -> Config {
     if let Some(num) = matches.value_of("lamports") {
         args.lamports = num.to_string().parse().expect("can't parse lamports");
     }
+    if let Some(num) = matches.value_of("lamports_per_kib") {
+        args.lamports_per_kib = num
+            .to_string()
+            .parse()
+            .expect("can't parse lamports per KiB");
+    }
 
     if matches.is_present("provider") {
         args.provider =
@@ -1,3 +1,4 @@
+use bandwidth_prepay_api::bandwidth_prepay_state::Tariff;
 use bandwidth_tps::bandwidth_tps::{
     do_bandwidth_tps, fund_keypairs, generate_keypairs, initialize_contracts,
 };
@@ -63,6 +64,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         &client,
         &client_keypairs,
         config.lamports,
+        Tariff {
+            lamports_per_kib: config.lamports_per_kib,
+            minimum_charge: 0,
+        },
         &config.provider,
         &gatekeeper_keypairs,
     )?
@@ -1,14 +1,22 @@
+use bandwidth_prepay_api::bandwidth_prepay_channel::{ChannelFrame, Voucher};
+use bandwidth_prepay_api::bandwidth_prepay_state::{ContractTerms, SpendingCap, Tariff};
 use clap::{App, Arg};
 use client::bandwidth_client::BandwidthClient;
 use pbr::ProgressBar;
 use provider_drone::DEFAULT_DRONE_PORT;
 use solana_client::rpc_client::RpcClient;
 use solana_sdk::pubkey::read_pubkey;
-use solana_sdk::signature::{read_keypair, KeypairUtil};
+use solana_sdk::signature::read_keypair;
 use std::io::{Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::time::Instant;
 
+// Number of packets between usage receipts sent to the gatekeeper
+const RECEIPT_INTERVAL: usize = 1000;
+
+// Slots the gatekeeper has to settle a payment channel before it can be reclaimed
+const CHANNEL_DISPUTE_WINDOW: u64 = 256;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     let matches = App::new("Data Counter Tester")
@@ -89,6 +97,47 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .takes_value(true)
                 .help("Number of lamports to fund contract with"),
         )
+        .arg(
+            Arg::with_name("lamports_per_kib")
+                .long("lamports-per-kib")
+                .value_name("NUM")
+                .takes_value(true)
+                .help("Tariff to agree to, in lamports per KiB. Defaults to 1"),
+        )
+        .arg(
+            Arg::with_name("receipts")
+                .long("receipts")
+                .help("Only allow charges acknowledged by signed usage receipts"),
+        )
+        .arg(
+            Arg::with_name("channel")
+                .long("channel")
+                .conflicts_with("receipts")
+                .help("Pay through a payment channel settled once at close"),
+        )
+        .arg(
+            Arg::with_name("expiry_timeout")
+                .long("expiry-timeout")
+                .value_name("SLOTS")
+                .takes_value(true)
+                .help("Slots after which the balance can be reclaimed without the gatekeeper"),
+        )
+        .arg(
+            Arg::with_name("spending_cap")
+                .long("spending-cap")
+                .value_name("LAMPORTS")
+                .takes_value(true)
+                .requires("cap_window")
+                .help("Most the gatekeeper may charge per cap window"),
+        )
+        .arg(
+            Arg::with_name("cap_window")
+                .long("cap-window")
+                .value_name("SLOTS")
+                .takes_value(true)
+                .requires("spending_cap")
+                .help("Length of the spending cap window in slots"),
+        )
         .get_matches();
 
     let client_account = read_keypair(matches.value_of("keypair").unwrap())?;
@@ -108,6 +157,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     } else {
         5_000_000
     };
+    let terms = ContractTerms {
+        tariff: Tariff {
+            lamports_per_kib: matches
+                .value_of("lamports_per_kib")
+                .unwrap_or("1")
+                .parse()?,
+            minimum_charge: 0,
+        },
+        receipts_required: matches.is_present("receipts"),
+        channel_dispute_window: if matches.is_present("channel") {
+            Some(CHANNEL_DISPUTE_WINDOW)
+        } else {
+            None
+        },
+        expiry_timeout: match matches.value_of("expiry_timeout") {
+            Some(timeout) => Some(timeout.parse()?),
+            None => None,
+        },
+        spending_cap: match matches.value_of("spending_cap") {
+            Some(lamports) => Some(SpendingCap {
+                lamports: lamports.parse()?,
+                slots: matches.value_of("cap_window").unwrap().parse()?,
+            }),
+            None => None,
+        },
+        ..ContractTerms::default()
+    };
 
     // Make connection request
     let packet_size: usize = matches.value_of("packet_size").unwrap_or("1024").parse()?;
@@ -121,14 +197,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let drone_addr = SocketAddr::new(host, DEFAULT_DRONE_PORT);
     client.request_airdrop(&drone_addr, lamports + 1)?;
-    let prepay_account = client.initialize_contract(lamports, &gatekeeper_pubkey, &provider_pubkey);
+    let receipts_required = terms.receipts_required;
+    let payment_channel = terms.channel_dispute_window.is_some();
+    let tariff = terms.tariff;
+    let (prepay_account, session_nonce) = client.initialize_contract(
+        lamports,
+        ContractTerms {
+            session_nonce: client.next_session_nonce(&gatekeeper_pubkey)?,
+            ..terms
+        },
+        &gatekeeper_pubkey,
+        &provider_pubkey,
+    );
 
     let gatekeeper_addr = matches.value_of("gatekeeper_addr").unwrap();
     let destination = matches.value_of("destination").unwrap();
     let destination: SocketAddr = destination.parse()?;
 
     let data_addr =
-        client.request_connection(gatekeeper_addr, destination, &prepay_account.pubkey())?;
+        client.request_connection(gatekeeper_addr, destination, &prepay_account, session_nonce)?;
 
     let mut data_addr = TcpStream::connect(data_addr)?;
 
@@ -140,15 +227,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     pb.message("Packets recieved: ");
 
     let begin = Instant::now();
-    for _ in 0..num_packets {
+    for i in 1..=num_packets {
         let start = Instant::now();
-        data_addr.write_all(&to_send)?;
+        if payment_channel {
+            // Pay ahead for the round trip; the gatekeeper counts both directions
+            let amount = tariff.charge((2 * packet_size * i) as u64).unwrap();
+            let voucher = Voucher::new(&client.id, &prepay_account, amount);
+            data_addr.write_all(&ChannelFrame::Voucher(voucher).encode())?;
+            data_addr.write_all(&ChannelFrame::Data(to_send.clone()).encode())?;
+        } else {
+            data_addr.write_all(&to_send)?;
+        }
 
         let mut data = [0 as u8; 1024];
         let amount = data_addr.read(&mut data)?;
         pings.push(start.elapsed().subsec_micros());
         assert_eq!(amount, packet_size);
         pb.inc();
+
+        if receipts_required && (i % RECEIPT_INTERVAL == 0 || i == num_packets) {
+            // The gatekeeper counts traffic in both directions
+            let cumulative_bytes = (2 * packet_size * i) as u64;
+            client.submit_receipt(gatekeeper_addr, &prepay_account, cumulative_bytes, i as u64)?;
+        }
     }
     let time = begin.elapsed().subsec_micros();
     pb.finish();
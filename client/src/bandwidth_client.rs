@@ -1,10 +1,17 @@
+use bandwidth_prepay_api::bandwidth_prepay_address::contract_address;
 use bandwidth_prepay_api::bandwidth_prepay_instruction;
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use bandwidth_prepay_api::bandwidth_prepay_state::{
+    BandwidthPrepayError, BandwidthPrepayState, ContractTerms,
+};
 use log::{error, info};
 use serde_derive::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
+use solana_client::client_error::ClientError;
 use solana_client::rpc_client::RpcClient;
 use solana_client::rpc_request::RpcError;
 use solana_drone::drone::request_airdrop_transaction;
+use solana_sdk::account::Account;
 use solana_sdk::message::Message;
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::signature::{Keypair, KeypairUtil};
@@ -16,10 +23,14 @@ use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
 
 const MESSAGE_TERMINATOR: &str = "\n";
 
+/// Most consecutive session nonces without a contract that `find_contracts`
+/// looks past before concluding there are no more
+pub const MAX_SESSION_GAP: u64 = 64;
+
 #[derive(Debug, Deserialize)]
 struct RpcResponse {
     jsonrpc: String,
-    result: HashMap<String, String>,
+    result: HashMap<String, Value>,
     id: u64,
 }
 
@@ -58,23 +69,134 @@ impl BandwidthClient {
         Ok(())
     }
 
+    /// Every contract this client opened with `gatekeeper_pubkey` with its
+    /// session nonce, read from the program's accounts in one request.
+    /// Contracts since handed to another gatekeeper key stay at their address
+    /// and name the new key in their state. Refunded contracts are purged once
+    /// empty and taken addresses hold no contract, so the scan looks past gaps
+    /// of up to `MAX_SESSION_GAP` nonces. Closed contracts read as the default
+    /// state, ready to be reinitialized; accounts that fail to decode are
+    /// reported and skipped.
+    pub fn find_contracts(
+        &self,
+        gatekeeper_pubkey: &Pubkey,
+    ) -> Result<Vec<(u64, BandwidthPrepayState)>, Box<dyn error::Error>> {
+        let accounts: HashMap<Pubkey, Account> = self
+            .fullnode_client
+            .get_program_accounts(&bandwidth_prepay_api::id())?
+            .into_iter()
+            .collect();
+        let mut contracts = vec![];
+        let mut gap = 0;
+        let mut session_nonce = 0;
+        while gap < MAX_SESSION_GAP {
+            let prepay_account =
+                contract_address(&self.id.pubkey(), gatekeeper_pubkey, session_nonce);
+            match accounts.get(&prepay_account) {
+                Some(account) => {
+                    gap = 0;
+                    match BandwidthPrepayState::deserialize(&account.data) {
+                        Ok(state) => contracts.push((session_nonce, state)),
+                        Err(err) => error!("Skipping contract {}: {}", prepay_account, err),
+                    }
+                }
+                None => gap += 1,
+            }
+            session_nonce += 1;
+        }
+        Ok(contracts)
+    }
+
+    /// Session nonce of the next contract to open with `gatekeeper_pubkey`,
+    /// past every address already taken
+    pub fn next_session_nonce(
+        &self,
+        gatekeeper_pubkey: &Pubkey,
+    ) -> Result<u64, Box<dyn error::Error>> {
+        let contracts = self.find_contracts(gatekeeper_pubkey)?;
+        let mut session_nonce = contracts
+            .last()
+            .map_or(0, |(session_nonce, _)| session_nonce + 1);
+        while self.address_taken(&contract_address(
+            &self.id.pubkey(),
+            gatekeeper_pubkey,
+            session_nonce,
+        )) {
+            session_nonce += 1;
+        }
+        Ok(session_nonce)
+    }
+
+    fn address_taken(&self, prepay_account: &Pubkey) -> bool {
+        self.fullnode_client
+            .get_account_data(prepay_account)
+            .is_ok()
+    }
+
+    /// Open a contract at the address derived from `terms.session_nonce`, or
+    /// from the first later nonce whose address nobody has taken, returning
+    /// the contract and the session nonce it was opened with
     pub fn initialize_contract(
         &self,
         lamports: u64,
+        mut terms: ContractTerms,
         gatekeeper_pubkey: &Pubkey,
         provider_pubkey: &Pubkey,
-    ) -> Keypair {
-        let prepay_account = Keypair::new(); // New contract account
-        let (blockhash, _) = self
-            .fullnode_client
-            .get_recent_blockhash()
-            .unwrap_or_default();
+    ) -> (Pubkey, u64) {
+        loop {
+            let prepay_account =
+                contract_address(&self.id.pubkey(), gatekeeper_pubkey, terms.session_nonce);
+            if self.address_taken(&prepay_account) {
+                info!("Contract address {} is taken", prepay_account);
+                terms.session_nonce += 1;
+                continue;
+            }
+            let (blockhash, _) = self
+                .fullnode_client
+                .get_recent_blockhash()
+                .unwrap_or_default();
+
+            let instructions = bandwidth_prepay_instruction::initialize(
+                &self.id.pubkey(),
+                &prepay_account,
+                &gatekeeper_pubkey,
+                &provider_pubkey,
+                terms.clone(),
+                lamports,
+            );
+            let message = Message::new(instructions);
+            let mut transaction = Transaction::new(&[&self.id], message, blockhash);
+            match self
+                .fullnode_client
+                .send_and_confirm_transaction(&mut transaction, &[&self.id])
+            {
+                Ok(_) => return (prepay_account, terms.session_nonce),
+                // Someone created the account between the check and the transaction
+                Err(_) if self.address_taken(&prepay_account) => terms.session_nonce += 1,
+                Err(err) => panic!("{}", decode_client_error(err)),
+            }
+        }
+    }
+
+    /// Start a new session on the contract for `terms.session_nonce`, which the
+    /// gatekeeper has closed
+    pub fn reinitialize_contract(
+        &self,
+        lamports: u64,
+        terms: ContractTerms,
+        gatekeeper_pubkey: &Pubkey,
+        provider_pubkey: &Pubkey,
+    ) -> Result<Pubkey, Box<dyn error::Error>> {
+        let prepay_account =
+            contract_address(&self.id.pubkey(), gatekeeper_pubkey, terms.session_nonce);
+        let (blockhash, _) = self.fullnode_client.get_recent_blockhash()?;
 
-        let instructions = bandwidth_prepay_instruction::initialize(
+        let instructions = bandwidth_prepay_instruction::reinitialize(
             &self.id.pubkey(),
-            &prepay_account.pubkey(),
+            &prepay_account,
             &gatekeeper_pubkey,
             &provider_pubkey,
+            terms,
             lamports,
         );
         let message = Message::new(instructions);
@@ -82,9 +204,42 @@ impl BandwidthClient {
         let _ = self
             .fullnode_client
             .send_and_confirm_transaction(&mut transaction, &[&self.id])
-            .unwrap();
+            .map_err(decode_client_error)?;
+        Ok(prepay_account)
+    }
+
+    /// Add `lamports` to a contract this client initialized
+    pub fn top_up_contract(
+        &self,
+        prepay_account: &Pubkey,
+        lamports: u64,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let (blockhash, _) = self.fullnode_client.get_recent_blockhash()?;
+
+        let instructions =
+            bandwidth_prepay_instruction::top_up(&self.id.pubkey(), prepay_account, lamports);
+        let message = Message::new(instructions);
+        let mut transaction = Transaction::new(&[&self.id], message, blockhash);
+        let _ = self
+            .fullnode_client
+            .send_and_confirm_transaction(&mut transaction, &[&self.id])
+            .map_err(decode_client_error)?;
+        Ok(())
+    }
 
-        prepay_account
+    /// Recover the balance of an expired contract the gatekeeper never refunded
+    pub fn reclaim_expired(&self, prepay_account: &Pubkey) -> Result<(), Box<dyn error::Error>> {
+        let (blockhash, _) = self.fullnode_client.get_recent_blockhash()?;
+
+        let instruction =
+            bandwidth_prepay_instruction::reclaim_expired(&self.id.pubkey(), prepay_account);
+        let message = Message::new(vec![instruction]);
+        let mut transaction = Transaction::new(&[&self.id], message, blockhash);
+        let _ = self
+            .fullnode_client
+            .send_and_confirm_transaction(&mut transaction, &[&self.id])
+            .map_err(decode_client_error)?;
+        Ok(())
     }
 
     pub fn request_connection<A, B>(
@@ -92,23 +247,118 @@ impl BandwidthClient {
         gatekeeper_addr: A,
         destination_addr: B,
         prepay_account: &Pubkey,
+        session_nonce: u64,
     ) -> Result<SocketAddr, Box<dyn error::Error>>
     where
         SocketAddr: std::convert::From<B>,
         A: ToSocketAddrs,
+    {
+        self.open_connection(
+            gatekeeper_addr,
+            None,
+            SocketAddr::from(destination_addr),
+            prepay_account,
+            session_nonce,
+        )
+    }
+
+    /// Like `request_connection`, but have the gatekeeper forward through the
+    /// gatekeeper listening at `next_hop` rather than straight to the destination
+    pub fn request_connection_through<A, B>(
+        &self,
+        gatekeeper_addr: A,
+        next_hop: SocketAddr,
+        destination_addr: B,
+        prepay_account: &Pubkey,
+        session_nonce: u64,
+    ) -> Result<SocketAddr, Box<dyn error::Error>>
+    where
+        SocketAddr: std::convert::From<B>,
+        A: ToSocketAddrs,
+    {
+        self.open_connection(
+            gatekeeper_addr,
+            Some(next_hop),
+            SocketAddr::from(destination_addr),
+            prepay_account,
+            session_nonce,
+        )
+    }
+
+    fn open_connection<A: ToSocketAddrs>(
+        &self,
+        gatekeeper_addr: A,
+        next_hop: Option<SocketAddr>,
+        destination_addr: SocketAddr,
+        prepay_account: &Pubkey,
+        session_nonce: u64,
+    ) -> Result<SocketAddr, Box<dyn error::Error>> {
+        let mut gatekeeper = TcpStream::connect(gatekeeper_addr)?;
+
+        let mut params = json!({
+            "destination": format!("{}", destination_addr),
+            "contract_pubkey": format!("{}", prepay_account),
+            "initiator_pubkey": format!("{}", self.id.pubkey()),
+            "session_nonce": session_nonce,
+        });
+        if let Some(next_hop) = next_hop {
+            params["next_hop"] = json!(format!("{}", next_hop));
+        }
+        let response = self.send_rpc_request(&mut gatekeeper, "newConnection", params)?;
+        if let Some(pricing) = response.result.get("pricing") {
+            info!("Gatekeeper pricing: {}", pricing);
+        }
+
+        let mut conn_addr = gatekeeper.peer_addr()?;
+        conn_addr.set_port(
+            response
+                .result
+                .get("port")
+                .and_then(Value::as_str)
+                .expect("No port returned")
+                .parse()?,
+        );
+
+        gatekeeper.shutdown(Shutdown::Both)?;
+        Ok(conn_addr)
+    }
+
+    /// Acknowledge usage on `prepay_account` so the gatekeeper can charge for it
+    pub fn submit_receipt<A>(
+        &self,
+        gatekeeper_addr: A,
+        prepay_account: &Pubkey,
+        cumulative_bytes: u64,
+        nonce: u64,
+    ) -> Result<(), Box<dyn error::Error>>
+    where
+        A: ToSocketAddrs,
     {
         let mut gatekeeper = TcpStream::connect(gatekeeper_addr)?;
 
-        let destination_addr = SocketAddr::from(destination_addr);
+        let receipt = UsageReceipt::new(&self.id, prepay_account, cumulative_bytes, nonce);
+        let params = json!({
+            "contract_pubkey": format!("{}", receipt.contract_id),
+            "cumulative_bytes": receipt.cumulative_bytes,
+            "nonce": receipt.nonce,
+            "signature": format!("{}", receipt.signature),
+        });
+        let _ = self.send_rpc_request(&mut gatekeeper, "submitReceipt", params)?;
+
+        gatekeeper.shutdown(Shutdown::Both)?;
+        Ok(())
+    }
 
+    fn send_rpc_request(
+        &self,
+        gatekeeper: &mut TcpStream,
+        method: &str,
+        params: Value,
+    ) -> Result<RpcResponse, Box<dyn error::Error>> {
         let request_json = json!({
             "jsonrpc": "2.0",
-            "method": "newConnection",
-            "params": {
-                "destination": format!("{}", destination_addr),
-                "contract_pubkey": format!("{}", prepay_account),
-                "initiator_pubkey": format!("{}", self.id.pubkey()),
-            },
+            "method": method,
+            "params": params,
             "id": 1,
         });
         let request = serde_json::to_string(&request_json).unwrap();
@@ -129,17 +379,17 @@ impl BandwidthClient {
             }
         };
         info!("Recieved: {:?}", response);
+        Ok(response)
+    }
+}
 
-        let mut conn_addr = gatekeeper.peer_addr()?;
-        conn_addr.set_port(
-            response
-                .result
-                .get(&"port".to_string())
-                .expect("No port returned")
-                .parse()?,
-        );
-
-        gatekeeper.shutdown(Shutdown::Both)?;
-        Ok(conn_addr)
+/// Report a failed prepay instruction as the BandwidthPrepayError it returned
+fn decode_client_error(err: ClientError) -> Box<dyn error::Error> {
+    if let ClientError::TransactionError(transaction_error) = &err {
+        if let Some(prepay_error) = BandwidthPrepayError::from_transaction_error(transaction_error)
+        {
+            return Box::new(prepay_error);
+        }
     }
+    Box::new(err)
 }
@@ -1,9 +1,14 @@
+use bandwidth_prepay_api::bandwidth_prepay_channel::Voucher;
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
 use std::time::Instant;
 
 pub struct Accumulator {
     pub total_data_amount: u64,
-    pub amount_charged: u64,
+    pub bytes_charged: u64,
+    pub bytes_settled: u64,
     pub initiator_fund: u64,
+    pub receipt: Option<UsageReceipt>,
+    pub voucher: Option<Voucher>,
     pub now: Instant,
 }
 
@@ -11,8 +16,11 @@ impl Default for Accumulator {
     fn default() -> Accumulator {
         Accumulator {
             total_data_amount: 0,
-            amount_charged: 0,
+            bytes_charged: 0,
+            bytes_settled: 0,
             initiator_fund: 0,
+            receipt: None,
+            voucher: None,
             now: Instant::now(),
         }
     }
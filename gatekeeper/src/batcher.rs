@@ -0,0 +1,727 @@
+use bandwidth_prepay_api::bandwidth_prepay_instruction::{self, BatchCharge};
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use bandwidth_prepay_api::bandwidth_prepay_state::BandwidthPrepayState;
+use log::*;
+use solana_sdk::client::Client;
+use solana_sdk::message::Message;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
+use solana_sdk::transaction::{Transaction, TransactionError};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Most contracts settled by one SpendMany; a receipt makes each charge over a
+/// hundred bytes, so larger batches would not fit in a packet
+pub const MAX_BATCH_SIZE: usize = 4;
+
+// How often submitted spends are checked for confirmation
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Past this a transaction's blockhash is too old for it to land
+const BLOCKHASH_LIFETIME: Duration = Duration::from_secs(120);
+
+// Wait before the first retry of a spend, doubled on each one after
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+// Submissions of one batch before its charges count as failed
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Longer than a batch can take over all its attempts, each waiting out a
+/// blockhash
+pub const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(12 * 60);
+
+/// Usage a forwarder wants charged to its contract
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingCharge {
+    pub contract_pubkey: Pubkey,
+    pub provider_id: Pubkey,
+    pub payee_ids: Vec<Pubkey>,
+    pub data_amount: u64,
+    pub receipt: Option<UsageReceipt>,
+}
+
+impl PendingCharge {
+    pub fn new(
+        contract_pubkey: &Pubkey,
+        contract_state: &BandwidthPrepayState,
+        gatekeeper_id: &Pubkey,
+        data_amount: u64,
+        receipt: Option<&UsageReceipt>,
+    ) -> Self {
+        Self {
+            contract_pubkey: *contract_pubkey,
+            provider_id: *contract_state.provider_for(gatekeeper_id),
+            payee_ids: contract_state.payee_ids(),
+            data_amount,
+            receipt: receipt.cloned(),
+        }
+    }
+}
+
+/// Merge charges on the same contract, then split them by provider into
+/// batches small enough for one SpendMany
+pub fn batch_charges(charges: Vec<PendingCharge>) -> Vec<Vec<PendingCharge>> {
+    let mut merged: Vec<PendingCharge> = vec![];
+    for charge in charges {
+        match merged
+            .iter_mut()
+            .find(|pending| pending.contract_pubkey == charge.contract_pubkey)
+        {
+            Some(pending) => {
+                pending.data_amount += charge.data_amount;
+                // Later receipts acknowledge everything earlier ones did
+                if charge.receipt.is_some() {
+                    pending.receipt = charge.receipt;
+                }
+            }
+            None => merged.push(charge),
+        }
+    }
+
+    let mut by_provider: Vec<Vec<PendingCharge>> = vec![];
+    for charge in merged {
+        match by_provider
+            .iter_mut()
+            .find(|group| group[0].provider_id == charge.provider_id)
+        {
+            Some(group) => group.push(charge),
+            None => by_provider.push(vec![charge]),
+        }
+    }
+    by_provider
+        .into_iter()
+        .flat_map(|group| {
+            group
+                .chunks(MAX_BATCH_SIZE)
+                .map(<[PendingCharge]>::to_vec)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// SpendMany message settling `batch`, whose charges must share a provider
+pub fn build_spend_many_message(gatekeeper: &Keypair, batch: Vec<PendingCharge>) -> Message {
+    let provider_id = batch[0].provider_id;
+    let mut payee_ids: Vec<Pubkey> = vec![];
+    for charge in &batch {
+        for payee_id in &charge.payee_ids {
+            if !payee_ids.contains(payee_id) {
+                payee_ids.push(*payee_id);
+            }
+        }
+    }
+    let charges = batch
+        .into_iter()
+        .map(|charge| {
+            (
+                charge.contract_pubkey,
+                BatchCharge {
+                    data_amount: charge.data_amount,
+                    receipt: charge.receipt,
+                },
+            )
+        })
+        .collect();
+    let instruction = bandwidth_prepay_instruction::spend_many(
+        &gatekeeper.pubkey(),
+        &provider_id,
+        charges,
+        &payee_ids,
+    );
+    Message::new(vec![instruction])
+}
+
+/// Spends by outcome, counted per contract charged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpendCounts {
+    /// Submitted and waiting for confirmation
+    pub pending: u64,
+    pub confirmed: u64,
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Tracked {
+    counts: SpendCounts,
+    /// Bytes queued or submitted for each contract and not yet confirmed
+    unconfirmed: HashMap<Pubkey, u64>,
+    /// Bytes of each contract's charges that failed for good, owed again
+    failed: HashMap<Pubkey, u64>,
+    /// Contracts whose queued charges are sent without waiting for the interval
+    flush: HashSet<Pubkey>,
+}
+
+/// Outcome of every spend the batcher submits
+#[derive(Clone, Default)]
+pub struct SpendTracker {
+    tracked: Arc<Mutex<Tracked>>,
+    /// Signalled whenever charges stop being unconfirmed
+    released: Arc<Condvar>,
+}
+
+impl SpendTracker {
+    pub fn counts(&self) -> SpendCounts {
+        self.tracked.lock().unwrap().counts
+    }
+
+    /// Bytes charged to `contract_pubkey` that have not yet come off its balance
+    pub fn unconfirmed_bytes(&self, contract_pubkey: &Pubkey) -> u64 {
+        let tracked = self.tracked.lock().unwrap();
+        tracked
+            .unconfirmed
+            .get(contract_pubkey)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Have the batcher send the charges queued on `contract_pubkey` now, and
+    /// wait up to `timeout` for all of them to settle; false if some are
+    /// still unconfirmed
+    pub fn drain(&self, contract_pubkey: &Pubkey, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut tracked = self.tracked.lock().unwrap();
+        if tracked.unconfirmed.contains_key(contract_pubkey) {
+            tracked.flush.insert(*contract_pubkey);
+        }
+        while tracked.unconfirmed.contains_key(contract_pubkey) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            tracked = self
+                .released
+                .wait_timeout(tracked, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    /// Bytes of the charges on `contract_pubkey` that failed for good since
+    /// the last call, for the connection to bill again
+    pub fn take_failed(&self, contract_pubkey: &Pubkey) -> u64 {
+        let mut tracked = self.tracked.lock().unwrap();
+        tracked.failed.remove(contract_pubkey).unwrap_or(0)
+    }
+
+    fn take_flush(&self) -> HashSet<Pubkey> {
+        mem::replace(&mut self.tracked.lock().unwrap().flush, HashSet::new())
+    }
+
+    fn queue(&self, charge: &PendingCharge) {
+        let mut tracked = self.tracked.lock().unwrap();
+        *tracked
+            .unconfirmed
+            .entry(charge.contract_pubkey)
+            .or_insert(0) += charge.data_amount;
+    }
+
+    fn submit(&self, batch: &[PendingCharge]) {
+        self.tracked.lock().unwrap().counts.pending += batch.len() as u64;
+    }
+
+    fn resolve(&self, batch: &[PendingCharge], confirmed: bool) {
+        let mut tracked = self.tracked.lock().unwrap();
+        tracked.counts.pending -= batch.len() as u64;
+        if confirmed {
+            tracked.counts.confirmed += batch.len() as u64;
+        } else {
+            tracked.counts.failed += batch.len() as u64;
+        }
+        for charge in batch {
+            tracked.release(charge);
+            if !confirmed {
+                *tracked.failed.entry(charge.contract_pubkey).or_insert(0) += charge.data_amount;
+            }
+        }
+        self.released.notify_all();
+    }
+
+    fn forget(&self, charge: &PendingCharge) {
+        self.tracked.lock().unwrap().release(charge);
+        self.released.notify_all();
+    }
+}
+
+impl Tracked {
+    fn release(&mut self, charge: &PendingCharge) {
+        let unconfirmed =
+            self.unconfirmed
+                .get_mut(&charge.contract_pubkey)
+                .map_or(0, |unconfirmed| {
+                    *unconfirmed = unconfirmed.saturating_sub(charge.data_amount);
+                    *unconfirmed
+                });
+        if unconfirmed == 0 {
+            self.unconfirmed.remove(&charge.contract_pubkey);
+        }
+    }
+}
+
+/// Handle for queueing charges with the spend batcher
+#[derive(Clone)]
+pub struct SpendQueue {
+    sender: Sender<PendingCharge>,
+    tracker: SpendTracker,
+}
+
+impl SpendQueue {
+    pub fn new(sender: Sender<PendingCharge>) -> Self {
+        Self {
+            sender,
+            tracker: SpendTracker::default(),
+        }
+    }
+
+    pub fn send(&self, charge: PendingCharge) -> Result<(), SendError<PendingCharge>> {
+        self.tracker.queue(&charge);
+        self.sender.send(charge).map_err(|e| {
+            self.tracker.forget(&e.0);
+            e
+        })
+    }
+
+    /// Bytes charged to `contract_pubkey` that have not yet come off its balance
+    pub fn unconfirmed_bytes(&self, contract_pubkey: &Pubkey) -> u64 {
+        self.tracker.unconfirmed_bytes(contract_pubkey)
+    }
+
+    /// Bytes of the charges on `contract_pubkey` that failed for good since
+    /// the last call, for the connection to bill again
+    pub fn take_failed(&self, contract_pubkey: &Pubkey) -> u64 {
+        self.tracker.take_failed(contract_pubkey)
+    }
+
+    /// Tracker that outlives the queue, which keeps the batcher running
+    pub fn tracker(&self) -> SpendTracker {
+        self.tracker.clone()
+    }
+}
+
+/// A SpendMany submitted and not yet confirmed or failed
+struct InFlight {
+    batch: Vec<PendingCharge>,
+    /// None while waiting to be submitted again
+    signature: Option<Signature>,
+    sent: Instant,
+    attempts: u32,
+    retry_at: Instant,
+}
+
+impl InFlight {
+    fn new(batch: Vec<PendingCharge>) -> Self {
+        let now = Instant::now();
+        Self {
+            batch,
+            signature: None,
+            sent: now,
+            attempts: 0,
+            retry_at: now,
+        }
+    }
+
+    /// Sign with a fresh blockhash and send, backing off if that fails
+    fn submit<T: Client>(&mut self, client: &Arc<T>, gatekeeper: &Keypair) {
+        self.attempts += 1;
+        let result = client
+            .get_recent_blockhash()
+            .map_err(|e| format!("no blockhash: {:?}", e))
+            .and_then(|(blockhash, _)| {
+                let message = build_spend_many_message(gatekeeper, self.batch.clone());
+                let transaction = Transaction::new(&[gatekeeper], message, blockhash);
+                client
+                    .async_send_transaction(transaction)
+                    .map_err(|e| format!("{:?}", e))
+            });
+        match result {
+            Ok(signature) => {
+                self.signature = Some(signature);
+                self.sent = Instant::now();
+            }
+            Err(e) => {
+                error!("Could not send charges: {}", e);
+                self.back_off();
+            }
+        }
+    }
+
+    fn back_off(&mut self) {
+        self.signature = None;
+        self.retry_at = Instant::now() + RETRY_BACKOFF * 2u32.pow(self.attempts.min(MAX_ATTEMPTS));
+    }
+
+    /// Whether the spend was confirmed, once it is settled either way
+    fn poll<T: Client>(&mut self, client: &Arc<T>, gatekeeper: &Keypair) -> Option<bool> {
+        let signature = match self.signature {
+            Some(signature) => signature,
+            None if Instant::now() < self.retry_at => return None,
+            None if self.attempts >= MAX_ATTEMPTS => {
+                error!("Giving up on charges after {} attempts", self.attempts);
+                return Some(false);
+            }
+            None => {
+                self.submit(client, gatekeeper);
+                return None;
+            }
+        };
+        match client.get_signature_status(&signature) {
+            Ok(Some(Ok(()))) => Some(true),
+            // Another transaction holds the contract; try again once it is done
+            Ok(Some(Err(TransactionError::AccountInUse))) => {
+                self.back_off();
+                None
+            }
+            Ok(Some(Err(e))) => {
+                error!("Charges in {} failed: {:?}", signature, e);
+                Some(false)
+            }
+            // Only re-sign once the old blockhash is too stale to land, so a
+            // batch is never charged twice
+            Ok(None) if self.sent.elapsed() > BLOCKHASH_LIFETIME => {
+                warn!("Charges in {} expired unconfirmed", signature);
+                self.signature = None;
+                self.retry_at = Instant::now();
+                None
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Could not get status of {}: {:?}", signature, e);
+                None
+            }
+        }
+    }
+}
+
+/// Start a thread that settles the charges of every forwarder in SpendMany
+/// transactions, sending whatever has accumulated once per `interval` and
+/// following each until it is confirmed or fails. Charges that fail are left
+/// with the tracker for their connection to bill again. Once every queue is
+/// dropped the thread waits for the last charges and exits.
+pub fn start_spend_batcher<T>(
+    client: Arc<T>,
+    gatekeeper: Arc<Keypair>,
+    interval: Duration,
+) -> (SpendQueue, JoinHandle<()>)
+where
+    T: 'static + Client + Send + Sync,
+{
+    let (sender, receiver) = channel();
+    let queue = SpendQueue::new(sender);
+    let tracker = queue.tracker();
+    let handle = thread::Builder::new()
+        .name("spend-batcher".to_string())
+        .spawn(move || spend_batcher_loop(&client, &gatekeeper, &receiver, &tracker, interval))
+        .unwrap();
+    (queue, handle)
+}
+
+fn spend_batcher_loop<T: Client>(
+    client: &Arc<T>,
+    gatekeeper: &Keypair,
+    receiver: &Receiver<PendingCharge>,
+    tracker: &SpendTracker,
+    interval: Duration,
+) {
+    let mut pending = vec![];
+    let mut in_flight = vec![];
+    let mut deadline = Instant::now() + interval;
+    let mut next_poll = Instant::now() + POLL_INTERVAL;
+    loop {
+        let now = Instant::now();
+        let wake = cmp::min(deadline, next_poll);
+        let timeout = if wake > now {
+            wake - now
+        } else {
+            Duration::from_millis(0)
+        };
+        match receiver.recv_timeout(timeout) {
+            Ok(charge) => {
+                pending.push(charge);
+                pending.extend(receiver.try_iter());
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        // Contracts being closed have their charges sent ahead of the others
+        let flush = tracker.take_flush();
+        if !flush.is_empty() {
+            let (charges, rest) = pending
+                .into_iter()
+                .partition(|charge: &PendingCharge| flush.contains(&charge.contract_pubkey));
+            pending = rest;
+            in_flight.extend(submit_batches(client, gatekeeper, tracker, charges));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            let charges = mem::replace(&mut pending, vec![]);
+            in_flight.extend(submit_batches(client, gatekeeper, tracker, charges));
+            deadline = now + interval;
+        }
+        if now >= next_poll {
+            poll_batches(client, gatekeeper, tracker, &mut in_flight);
+            next_poll = now + POLL_INTERVAL;
+        }
+    }
+
+    in_flight.extend(submit_batches(client, gatekeeper, tracker, pending));
+    while !in_flight.is_empty() {
+        thread::sleep(POLL_INTERVAL);
+        poll_batches(client, gatekeeper, tracker, &mut in_flight);
+    }
+}
+
+fn submit_batches<T: Client>(
+    client: &Arc<T>,
+    gatekeeper: &Keypair,
+    tracker: &SpendTracker,
+    charges: Vec<PendingCharge>,
+) -> Vec<InFlight> {
+    batch_charges(charges)
+        .into_iter()
+        .map(|batch| {
+            tracker.submit(&batch);
+            let mut in_flight = InFlight::new(batch);
+            in_flight.submit(client, gatekeeper);
+            in_flight
+        })
+        .collect()
+}
+
+/// Drop every batch that has settled, recording its outcome with `tracker`
+fn poll_batches<T: Client>(
+    client: &Arc<T>,
+    gatekeeper: &Keypair,
+    tracker: &SpendTracker,
+    in_flight: &mut Vec<InFlight>,
+) {
+    let mut index = 0;
+    while index < in_flight.len() {
+        match in_flight[index].poll(client, gatekeeper) {
+            Some(confirmed) => {
+                let settled = in_flight.swap_remove(index);
+                tracker.resolve(&settled.batch, confirmed);
+            }
+            None => index += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bandwidth_prepay_api::bandwidth_prepay_address::contract_address;
+    use bandwidth_prepay_api::bandwidth_prepay_processor::process_instruction;
+    use bandwidth_prepay_api::bandwidth_prepay_state::{ContractTerms, Tariff};
+    use solana_runtime::bank::Bank;
+    use solana_runtime::bank_client::BankClient;
+    use solana_sdk::client::SyncClient;
+    use solana_sdk::genesis_block::create_genesis_block;
+    use solana_sdk::system_instruction;
+
+    fn pending_charge(
+        contract_pubkey: Pubkey,
+        provider_id: Pubkey,
+        data_amount: u64,
+    ) -> PendingCharge {
+        PendingCharge {
+            contract_pubkey,
+            provider_id,
+            payee_ids: vec![],
+            data_amount,
+            receipt: None,
+        }
+    }
+
+    #[test]
+    fn test_batch_charges() {
+        let provider = Pubkey::new_rand();
+        let other_provider = Pubkey::new_rand();
+        let contracts: Vec<_> = (0..MAX_BATCH_SIZE + 1)
+            .map(|_| Pubkey::new_rand())
+            .collect();
+
+        let mut charges: Vec<_> = contracts
+            .iter()
+            .map(|contract| pending_charge(*contract, provider, 1024))
+            .collect();
+        charges.push(pending_charge(contracts[0], provider, 1024));
+        charges.push(pending_charge(Pubkey::new_rand(), other_provider, 1024));
+
+        let batches = batch_charges(charges);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0].len(), MAX_BATCH_SIZE);
+        assert_eq!(batches[0][0].contract_pubkey, contracts[0]);
+        assert_eq!(batches[0][0].data_amount, 2048);
+        assert_eq!(batches[1].len(), 1);
+        assert_eq!(batches[2].len(), 1);
+        assert_eq!(batches[2][0].provider_id, other_provider);
+    }
+
+    #[test]
+    fn test_spend_batcher() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Arc::new(Keypair::new());
+        let provider = Keypair::new().pubkey();
+        let contracts: Vec<_> = (0..2)
+            .map(|session_nonce| {
+                contract_address(&alice_pubkey, &gatekeeper.pubkey(), session_nonce)
+            })
+            .collect();
+        for (session_nonce, contract) in contracts.iter().enumerate() {
+            let instructions = bandwidth_prepay_instruction::initialize(
+                &alice_pubkey,
+                contract,
+                &gatekeeper.pubkey(),
+                &provider,
+                ContractTerms {
+                    tariff: Tariff {
+                        lamports_per_kib: 1,
+                        minimum_charge: 0,
+                    },
+                    session_nonce: session_nonce as u64,
+                    ..ContractTerms::default()
+                },
+                500,
+            );
+            let message = Message::new(instructions);
+            bank_client
+                .send_message(&[&alice_keypair], message)
+                .unwrap();
+        }
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
+        let message = Message::new(vec![instruction]);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        let (sender, _) = start_spend_batcher(
+            bank_client.clone(),
+            gatekeeper.clone(),
+            Duration::from_millis(10),
+        );
+        sender
+            .send(pending_charge(contracts[0], provider, 100 * 1024))
+            .unwrap();
+        sender
+            .send(pending_charge(contracts[1], provider, 200 * 1024))
+            .unwrap();
+
+        let mut balance = 0;
+        while balance < 300 {
+            balance = bank_client.get_balance(&provider).unwrap();
+        }
+        assert_eq!(balance, 300);
+        assert_eq!(bank_client.get_balance(&contracts[0]).unwrap(), 400);
+        assert_eq!(bank_client.get_balance(&contracts[1]).unwrap(), 300);
+    }
+
+    #[test]
+    fn test_spend_batcher_confirms_on_close() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Arc::new(Keypair::new());
+        let provider = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let mut instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                tariff: Tariff {
+                    lamports_per_kib: 1,
+                    minimum_charge: 0,
+                },
+                ..ContractTerms::default()
+            },
+            500,
+        );
+        instructions.push(system_instruction::transfer(
+            &alice_pubkey,
+            &gatekeeper.pubkey(),
+            1,
+        ));
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+
+        // Nothing is sent before the interval, so both charges wait for the close
+        let (queue, handle) = start_spend_batcher(
+            bank_client.clone(),
+            gatekeeper.clone(),
+            Duration::from_secs(60),
+        );
+        let tracker = queue.tracker();
+        queue
+            .send(pending_charge(contract, provider, 100 * 1024))
+            .unwrap();
+        let missing = Pubkey::new_rand();
+        queue
+            .send(pending_charge(missing, Pubkey::new_rand(), 100 * 1024))
+            .unwrap();
+        assert_eq!(tracker.unconfirmed_bytes(&contract), 100 * 1024);
+        drop(queue);
+
+        handle.join().unwrap();
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 100);
+        // The failed charge is owed again, but only once
+        assert_eq!(tracker.take_failed(&missing), 100 * 1024);
+        assert_eq!(tracker.take_failed(&missing), 0);
+        assert_eq!(tracker.take_failed(&contract), 0);
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 400);
+        assert_eq!(tracker.unconfirmed_bytes(&contract), 0);
+        assert_eq!(
+            tracker.counts(),
+            SpendCounts {
+                pending: 0,
+                confirmed: 1,
+                failed: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_spend_tracker() {
+        let tracker = SpendTracker::default();
+        let contract = Pubkey::new_rand();
+        let provider = Pubkey::new_rand();
+        let charges = vec![
+            pending_charge(contract, provider, 1024),
+            pending_charge(contract, provider, 2048),
+        ];
+        for charge in &charges {
+            tracker.queue(charge);
+        }
+        assert_eq!(tracker.unconfirmed_bytes(&contract), 3072);
+
+        let batch = batch_charges(charges);
+        tracker.submit(&batch[0]);
+        assert_eq!(tracker.counts().pending, 1);
+        tracker.resolve(&batch[0], true);
+        assert_eq!(tracker.unconfirmed_bytes(&contract), 0);
+        assert_eq!(
+            tracker.counts(),
+            SpendCounts {
+                pending: 0,
+                confirmed: 1,
+                failed: 0,
+            }
+        );
+    }
+}
@@ -1,5 +0,0 @@
-pub fn business_logic(data_amount: u64) -> u64 {
-    // let scalar = 1;
-    // data_amount * scalar
-    data_amount / 1024
-}
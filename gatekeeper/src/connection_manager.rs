@@ -0,0 +1,847 @@
+use crate::accumulator::Accumulator;
+use crate::batcher::{SpendQueue, SpendTracker};
+use crate::connection_params::NewConnParams;
+use crate::gatekeeper::{
+    accept_voucher, finish_connection, meter_data, notified_balance, settle_exhausted,
+};
+use crate::journal::{Journal, JournalEntry};
+use crate::pricing::PriceMeter;
+use crate::receipts::ReceiptStore;
+use bandwidth_prepay_api::bandwidth_prepay_channel::{ChannelDecoder, ChannelFrame};
+use bandwidth_prepay_api::bandwidth_prepay_state::BandwidthPrepayState;
+use futures::future::{self, Either, Loop, Shared};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use log::*;
+use pubsub_client::client::{start_pubsub_client, Event, PubSubClient};
+use pubsub_client::request::PubSubRequest;
+use serde_json::Value;
+use solana_sdk::client::Client;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, KeypairUtil};
+use std::collections::HashMap;
+use std::error;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::runtime::{Runtime, TaskExecutor};
+use tokio::timer::Interval;
+use tokio_threadpool::blocking;
+
+// Bytes read from one side before waiting for the other side to accept them
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// Finished connections waiting for their closing transactions before new ones
+// stop being accepted
+const SETTLEMENT_QUEUE: usize = 64;
+
+// Longest metered progress waits in the journal before it is synced to disk
+const JOURNAL_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handle for opening connections on the runtime that forwards them all
+pub struct ConnectionManager {
+    runtime: Mutex<Option<Runtime>>,
+    executor: TaskExecutor,
+    subscriptions: Subscriptions,
+    gatekeeper_id: Pubkey,
+    receipts: ReceiptStore,
+    journal: Journal,
+    /// None once shutting down, so no new connection is opened
+    senders: Mutex<Option<Senders>>,
+    /// Resolves once shutdown starts, stopping every connection
+    stopped: Shared<oneshot::Receiver<()>>,
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+    /// Contracts whose closing transactions failed
+    unsettled: Arc<Mutex<Vec<Pubkey>>>,
+}
+
+struct Senders {
+    charges: SpendQueue,
+    settler: mpsc::Sender<Settlement>,
+}
+
+impl ConnectionManager {
+    /// Connect to `params.destination` and listen for the initiator, resolving
+    /// to the port it should connect to; `price` follows what the gatekeeper
+    /// asks for the traffic
+    pub fn open(
+        &self,
+        params: NewConnParams,
+        contract_state: BandwidthPrepayState,
+        balance: u64,
+        price: PriceMeter,
+    ) -> Box<dyn Future<Item = u16, Error = io::Error> + Send> {
+        // Held until the connection is spawned, so shutdown waits for it
+        let senders = self.senders.lock().unwrap();
+        let (charge_sender, settler) = match senders.as_ref() {
+            Some(senders) => (senders.charges.clone(), senders.settler.clone()),
+            None => {
+                return Box::new(future::err(io::Error::new(
+                    ErrorKind::Other,
+                    "gatekeeper is shutting down",
+                )))
+            }
+        };
+        let addr = match resolve(&params.destination) {
+            Ok(addr) => addr,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let mut accumulator = Accumulator::default();
+        accumulator.initiator_fund = balance;
+        accumulator.bytes_settled = contract_state.bytes_reported;
+        let balances = self
+            .subscriptions
+            .balances(&params.contract_pubkey, &contract_state);
+        let meter = Meter {
+            channel_decoder: if contract_state.payment_channel {
+                Some(ChannelDecoder::default())
+            } else {
+                None
+            },
+            params,
+            contract_state,
+            accumulator,
+            gatekeeper_id: self.gatekeeper_id,
+            receipts: self.receipts.clone(),
+            charge_sender,
+            journal: self.journal.clone(),
+            price,
+        };
+        meter.journal_progress();
+
+        let stopping = self.stopped.clone();
+        let (port_sender, port_receiver) = oneshot::channel();
+        let connection = connect(addr, meter.params.connect_timeout, port_sender)
+            .map_err(|e| error!("Could not open connection: {}", e))
+            // Nothing has been forwarded yet, so there is nothing to settle
+            .select2(stopped(self.stopped.clone()))
+            .map_err(|_| ())
+            .and_then(|either| match either {
+                Either::A((streams, _)) => Ok(streams),
+                Either::B(_) => Err(()),
+            })
+            .and_then(move |(origin, destination)| {
+                let meter = Arc::new(Mutex::new(meter));
+                forward(origin, destination, meter.clone(), balances)
+                    .select(stopped(stopping).map(|_| Closed::Shutdown))
+                    .map(move |(closed, _)| (closed, meter))
+                    .map_err(|_| ())
+            })
+            .and_then(move |(closed, meter)| {
+                let settlement = Settlement::new(&mut meter.lock().unwrap(), closed);
+                settler
+                    .send(settlement)
+                    .map(|_| ())
+                    .map_err(|e| error!("Could not settle connection: {}", e))
+            });
+        self.executor.spawn(connection);
+        // The connection drops `port_sender` if the destination never accepts
+        Box::new(port_receiver.map_err(|_| {
+            io::Error::new(ErrorKind::ConnectionRefused, "destination did not accept")
+        }))
+    }
+
+    /// Stop opening connections and end every open one; each drops its charge
+    /// queue once it has handed over its settlement
+    pub fn stop(&self) {
+        drop(self.senders.lock().unwrap().take());
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            let _ = stop.send(());
+        }
+    }
+
+    /// Stop every connection and wait for all of them to be settled,
+    /// returning the contracts that could not be
+    pub fn shutdown(&self) -> Vec<Pubkey> {
+        self.stop();
+        if let Some(runtime) = self.runtime.lock().unwrap().take() {
+            // The settler finishes once every connection has handed over its settlement
+            let _ = runtime.shutdown_on_idle().wait();
+        }
+        mem::replace(&mut *self.unsettled.lock().unwrap(), vec![])
+    }
+}
+
+/// Start the runtime forwarding every connection, with one pubsub connection
+/// for balance updates and one task for closing transactions
+pub fn start_connection_manager<T>(
+    client: Arc<T>,
+    gatekeeper: Arc<Keypair>,
+    ws_addr: SocketAddr,
+    receipts: ReceiptStore,
+    charge_sender: SpendQueue,
+    journal: Journal,
+) -> Result<ConnectionManager, Box<dyn error::Error>>
+where
+    T: 'static + Client + Send + Sync,
+{
+    let runtime = Runtime::new()?;
+    let executor = runtime.executor();
+    let subscriptions = Subscriptions::start(ws_addr)?;
+    let unsettled = Arc::new(Mutex::new(vec![]));
+    let settler = start_settler(
+        &executor,
+        client,
+        gatekeeper.clone(),
+        receipts.clone(),
+        charge_sender.tracker(),
+        unsettled.clone(),
+        journal.clone(),
+    );
+    let (stop, stopped) = oneshot::channel();
+    let stopped = stopped.shared();
+    start_journal_sync(&executor, journal.clone(), stopped.clone());
+    Ok(ConnectionManager {
+        runtime: Mutex::new(Some(runtime)),
+        executor,
+        subscriptions,
+        gatekeeper_id: gatekeeper.pubkey(),
+        receipts,
+        journal,
+        senders: Mutex::new(Some(Senders {
+            charges: charge_sender,
+            settler,
+        })),
+        stopped,
+        stop: Mutex::new(Some(stop)),
+        unsettled,
+    })
+}
+
+/// Resolves when shutdown starts, or when the manager is dropped without one
+fn stopped(stop: Shared<oneshot::Receiver<()>>) -> impl Future<Item = (), Error = ()> {
+    stop.then(|_| Ok(()))
+}
+
+fn resolve(destination: &str) -> io::Result<SocketAddr> {
+    destination.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("no address for {}", destination),
+        )
+    })
+}
+
+/// Connect to `addr` within `timeout`, then listen for the initiator on the
+/// port sent to `port_sender`
+fn connect(
+    addr: SocketAddr,
+    timeout: Duration,
+    port_sender: oneshot::Sender<u16>,
+) -> impl Future<Item = (TcpStream, TcpStream), Error = io::Error> {
+    info!("Connecting to {}", addr);
+    TcpStream::connect(&addr)
+        .timeout(timeout)
+        .map_err(move |e| {
+            e.into_inner().unwrap_or_else(|| {
+                io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("no response within {:?}", timeout),
+                )
+            })
+        })
+        .and_then(move |destination| {
+            info!("Connected to {}", destination.peer_addr()?);
+            let listener = TcpListener::bind(&"0.0.0.0:0".parse().unwrap())?;
+            port_sender
+                .send(listener.local_addr()?.port())
+                .map_err(|_| io::Error::new(ErrorKind::Other, "caller stopped waiting"))?;
+            Ok((listener, destination))
+        })
+        .and_then(|(listener, destination)| {
+            listener
+                .incoming()
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(|(origin, _)| {
+                    let origin = origin
+                        .ok_or_else(|| io::Error::new(ErrorKind::Other, "listener closed"))?;
+                    info!("Gatekeeper connected to {}", origin.peer_addr()?);
+                    Ok((origin, destination))
+                })
+        })
+}
+
+/// Why a connection stopped forwarding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Closed {
+    Hangup,
+    Exhausted,
+    /// The contract's tariff no longer covers the gatekeeper's pricing
+    Underpriced,
+    Shutdown,
+}
+
+/// Billing for one connection, shared by both directions of forwarding
+pub struct Meter {
+    pub params: NewConnParams,
+    pub contract_state: BandwidthPrepayState,
+    pub accumulator: Accumulator,
+    pub channel_decoder: Option<ChannelDecoder>,
+    pub gatekeeper_id: Pubkey,
+    pub receipts: ReceiptStore,
+    pub charge_sender: SpendQueue,
+    pub journal: Journal,
+    pub price: PriceMeter,
+}
+
+impl Meter {
+    /// Bill what the initiator sent, returning the bytes to pass on
+    fn from_origin(&mut self, data: &[u8]) -> Result<Vec<u8>, Closed> {
+        if self.contract_state.receipts_required {
+            self.accumulator.receipt = self.receipts.get(&self.params.contract_pubkey);
+        }
+        let decoder = match self.channel_decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => return self.bill(data),
+        };
+        decoder.extend(data);
+        let mut payload = vec![];
+        while let Some(frame) = decoder.next_frame() {
+            match frame {
+                Ok(ChannelFrame::Data(data)) => {
+                    self.meter(data.len() as u64)?;
+                    payload.extend_from_slice(&data);
+                }
+                Ok(ChannelFrame::Voucher(voucher)) => accept_voucher(
+                    &self.params,
+                    &self.contract_state,
+                    &mut self.accumulator,
+                    voucher,
+                ),
+                Err(e) => {
+                    error!("Invalid frame on {:?}: {}", self.params.contract_pubkey, e);
+                    return Err(Closed::Hangup);
+                }
+            }
+        }
+        Ok(payload)
+    }
+
+    /// Bill what the destination sent, returning the bytes to pass on
+    fn from_destination(&mut self, data: &[u8]) -> Result<Vec<u8>, Closed> {
+        if self.contract_state.receipts_required {
+            self.accumulator.receipt = self.receipts.get(&self.params.contract_pubkey);
+        }
+        self.bill(data)
+    }
+
+    fn bill(&mut self, data: &[u8]) -> Result<Vec<u8>, Closed> {
+        self.meter(data.len() as u64)?;
+        Ok(data.to_vec())
+    }
+
+    /// Count `data_amount` against the contract, journaling each charge queued;
+    /// nothing is counted for data that is not forwarded
+    fn meter(&mut self, data_amount: u64) -> Result<(), Closed> {
+        // The contract only ever pays its tariff, so stop before forwarding what
+        // the policy asks more for
+        let quote = self.price.quote(data_amount);
+        let paid = self
+            .contract_state
+            .tariff
+            .charge(self.accumulator.total_data_amount + data_amount)
+            .unwrap_or(u64::max_value());
+        if quote.lamports() > paid {
+            warn!(
+                "{:?} would owe {} lamports but its tariff pays {}, closing",
+                self.params.contract_pubkey,
+                quote.lamports(),
+                paid
+            );
+            return Err(Closed::Underpriced);
+        }
+
+        let bytes_settled = self.accumulator.bytes_settled;
+        let exhausted = meter_data(
+            &self.params,
+            &self.gatekeeper_id,
+            &self.contract_state,
+            &mut self.accumulator,
+            data_amount,
+            &self.charge_sender,
+        );
+        if self.accumulator.bytes_settled != bytes_settled {
+            self.journal_progress();
+        }
+        if exhausted {
+            return Err(Closed::Exhausted);
+        }
+        self.price.commit(quote);
+        Ok(())
+    }
+
+    fn journal_progress(&self) {
+        let entry = JournalEntry::new(&self.params.contract_pubkey, &self.accumulator, false);
+        if let Err(e) = self.journal.append(&entry) {
+            error!("Could not journal {:?}: {}", self.params.contract_pubkey, e);
+        }
+    }
+}
+
+/// Forward between `origin` and `destination` until either side hangs up or
+/// the contract runs dry, following the contract balance from `balances`
+pub fn forward<S>(
+    origin: TcpStream,
+    destination: TcpStream,
+    meter: Arc<Mutex<Meter>>,
+    balances: S,
+) -> impl Future<Item = Closed, Error = ()>
+where
+    S: Stream<Item = u64, Error = ()>,
+{
+    let (origin_reader, origin_writer) = origin.split();
+    let (destination_reader, destination_writer) = destination.split();
+    let upstream = pipe(
+        origin_reader,
+        destination_writer,
+        meter.clone(),
+        Meter::from_origin,
+    );
+    let downstream = pipe(
+        destination_reader,
+        origin_writer,
+        meter.clone(),
+        Meter::from_destination,
+    );
+    // Balance updates never end the connection by themselves
+    let updates = balances
+        .for_each(move |balance| {
+            meter.lock().unwrap().accumulator.initiator_fund = balance;
+            Ok(())
+        })
+        .then(|_| future::empty::<Closed, ()>());
+
+    upstream
+        .select(downstream)
+        .map(|(closed, _)| closed)
+        .or_else(|(e, _)| {
+            info!("Connection failed: {}", e);
+            Ok::<_, ()>(Closed::Hangup)
+        })
+        .select(updates)
+        .map(|(closed, _)| closed)
+        .map_err(|_| ())
+}
+
+/// Copy from `reader` to `writer`, letting `bill` charge for each read; a read
+/// waits until the previous one has been written, so a slow side holds back
+/// the other instead of queueing without bound
+fn pipe<R, W>(
+    reader: R,
+    writer: W,
+    meter: Arc<Mutex<Meter>>,
+    bill: fn(&mut Meter, &[u8]) -> Result<Vec<u8>, Closed>,
+) -> impl Future<Item = Closed, Error = io::Error>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    future::loop_fn(
+        (reader, writer, vec![0; BUFFER_SIZE]),
+        move |(reader, writer, buffer)| {
+            let meter = meter.clone();
+            tokio::io::read(reader, buffer).and_then(move |(reader, buffer, data_amount)| {
+                if data_amount == 0 {
+                    return Either::A(future::ok(Loop::Break(Closed::Hangup)));
+                }
+                let payload = match bill(&mut meter.lock().unwrap(), &buffer[..data_amount]) {
+                    Ok(payload) => payload,
+                    Err(closed) => return Either::A(future::ok(Loop::Break(closed))),
+                };
+                Either::B(
+                    tokio::io::write_all(writer, payload)
+                        .map(move |(writer, _)| Loop::Continue((reader, writer, buffer))),
+                )
+            })
+        },
+    )
+}
+
+/// A connection that has stopped forwarding, waiting for its closing transactions
+struct Settlement {
+    params: NewConnParams,
+    contract_state: BandwidthPrepayState,
+    accumulator: Accumulator,
+    exhausted: bool,
+}
+
+impl Settlement {
+    fn new(meter: &mut Meter, closed: Closed) -> Self {
+        info!(
+            "Bytes transmitted for {:?}: {}",
+            meter.params.contract_pubkey, meter.accumulator.total_data_amount
+        );
+        Self {
+            params: meter.params.clone(),
+            contract_state: meter.contract_state.clone(),
+            accumulator: mem::replace(&mut meter.accumulator, Accumulator::default()),
+            exhausted: closed == Closed::Exhausted,
+        }
+    }
+}
+
+/// Spawn the task that sends each finished connection's closing transactions,
+/// one at a time on a thread allowed to block
+fn start_settler<T>(
+    executor: &TaskExecutor,
+    client: Arc<T>,
+    gatekeeper: Arc<Keypair>,
+    receipts: ReceiptStore,
+    charges: SpendTracker,
+    unsettled: Arc<Mutex<Vec<Pubkey>>>,
+    journal: Journal,
+) -> mpsc::Sender<Settlement>
+where
+    T: 'static + Client + Send + Sync,
+{
+    let (sender, receiver) = mpsc::channel(SETTLEMENT_QUEUE);
+    let settler = receiver.for_each(move |settlement: Settlement| {
+        let client = client.clone();
+        let gatekeeper = gatekeeper.clone();
+        let receipts = receipts.clone();
+        let charges = charges.clone();
+        let unsettled = unsettled.clone();
+        let journal = journal.clone();
+        let mut settlement = Some(settlement);
+        future::poll_fn(move || {
+            blocking(|| {
+                let mut settlement = settlement.take().unwrap();
+                let mut settled = true;
+                if settlement.exhausted {
+                    settled = settle_exhausted(
+                        &settlement.params,
+                        &gatekeeper,
+                        &client,
+                        &settlement.contract_state,
+                        &mut settlement.accumulator,
+                        &charges,
+                    );
+                }
+                settled &= finish_connection(
+                    &settlement.params,
+                    &gatekeeper,
+                    &client,
+                    &mut settlement.accumulator,
+                    &receipts,
+                    &charges,
+                );
+                let contract_pubkey = settlement.params.contract_pubkey;
+                // An unsettled contract stays in the journal for the next start,
+                // as does one whose charges could still land or fail
+                let refunded = settled && charges.unconfirmed_bytes(&contract_pubkey) == 0;
+                let entry = JournalEntry::new(&contract_pubkey, &settlement.accumulator, refunded);
+                if let Err(e) = journal.record(&entry) {
+                    error!("Could not journal {:?}: {}", contract_pubkey, e);
+                }
+                if !settled {
+                    unsettled.lock().unwrap().push(contract_pubkey);
+                }
+            })
+        })
+        .map_err(|e| error!("Settlement could not block: {}", e))
+    });
+    executor.spawn(settler);
+    sender
+}
+
+/// Spawn the task that syncs metered progress to disk on a thread allowed to
+/// block, so forwarding only ever appends to the journal
+fn start_journal_sync(
+    executor: &TaskExecutor,
+    journal: Journal,
+    stop: Shared<oneshot::Receiver<()>>,
+) {
+    let sync = Interval::new(
+        Instant::now() + JOURNAL_SYNC_INTERVAL,
+        JOURNAL_SYNC_INTERVAL,
+    )
+    .map_err(|e| error!("Journal sync timer failed: {}", e))
+    .for_each(move |_| {
+        let journal = journal.clone();
+        future::poll_fn(move || blocking(|| journal.sync()))
+            .map_err(|e| error!("Journal sync could not block: {}", e))
+            .map(|synced| {
+                if let Err(e) = synced {
+                    error!("Could not sync journal: {}", e);
+                }
+            })
+    })
+    // Settlements sync their own entries, and everything before them, once stopped
+    .select(stopped(stop))
+    .then(|_| Ok::<_, ()>(()));
+    executor.spawn(sync);
+}
+
+/// Account subscriptions for every open connection, multiplexed over one
+/// pubsub connection
+#[derive(Clone)]
+struct Subscriptions {
+    routes: Arc<Mutex<Routes>>,
+}
+
+struct Routes {
+    pubsub: PubSubClient,
+    next_id: u64,
+    /// Subscriptions requested but not yet numbered, by request id
+    pending: HashMap<u64, UnboundedSender<Value>>,
+    /// Live subscriptions, by subscription number
+    active: HashMap<u64, UnboundedSender<Value>>,
+}
+
+impl Subscriptions {
+    fn start(ws_addr: SocketAddr) -> Result<Self, Box<dyn error::Error>> {
+        let (pubsub, receiver) = start_pubsub_client(format!("ws://{}", ws_addr))?;
+        let subscriptions = Self {
+            routes: Arc::new(Mutex::new(Routes {
+                pubsub,
+                next_id: 1,
+                pending: HashMap::new(),
+                active: HashMap::new(),
+            })),
+        };
+        let routes = subscriptions.routes.clone();
+        thread::Builder::new()
+            .name("pubsub-router".to_string())
+            .spawn(move || {
+                for event in receiver {
+                    match event {
+                        Event::Message(message) => {
+                            if let Ok(json) = message
+                                .into_text()
+                                .map_err(|_| ())
+                                .and_then(|text| serde_json::from_str(&text).map_err(|_| ()))
+                            {
+                                routes.lock().unwrap().route(json);
+                            }
+                        }
+                        Event::Disconnect(_, _) => warn!("PubSub connection dropped"),
+                        Event::Connect(_) => (),
+                    }
+                }
+            })?;
+        Ok(subscriptions)
+    }
+
+    /// Stream of `contract_pubkey`'s balance; dropping it ends the subscription
+    fn balances(
+        &self,
+        contract_pubkey: &Pubkey,
+        contract_state: &BandwidthPrepayState,
+    ) -> impl Stream<Item = u64, Error = ()> {
+        let (sender, receiver): (_, UnboundedReceiver<Value>) = mpsc::unbounded();
+        let mut routes = self.routes.lock().unwrap();
+        let id = routes.next_id;
+        routes.next_id += 1;
+        match routes
+            .pubsub
+            .subscribe(id, &PubSubRequest::Account, contract_pubkey)
+        {
+            Ok(()) => {
+                routes.pending.insert(id, sender);
+            }
+            Err(e) => error!("Could not subscribe to contract balance: {:?}", e),
+        }
+        let contract_state = contract_state.clone();
+        receiver.filter_map(move |notification| notified_balance(&contract_state, &notification))
+    }
+}
+
+impl Routes {
+    /// Pass a subscription reply or notification on to its subscriber
+    fn route(&mut self, json: Value) {
+        if let (Some(id), Some(subscription)) = (json["id"].as_u64(), json["result"].as_u64()) {
+            if let Some(sender) = self.pending.remove(&id) {
+                self.active.insert(subscription, sender);
+            }
+            return;
+        }
+        let subscription = match json["params"]["subscription"].as_u64() {
+            Some(subscription) => subscription,
+            None => return,
+        };
+        let closed = match self.active.get(&subscription) {
+            Some(sender) => sender.unbounded_send(json).is_err(),
+            None => false,
+        };
+        if closed {
+            self.active.remove(&subscription);
+            if let Err(e) = self
+                .pubsub
+                .unsubscribe(0, &PubSubRequest::Account, subscription)
+            {
+                error!("Could not unsubscribe from contract balance: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batcher::PendingCharge;
+    use crate::connection_params::DEFAULT_CONNECT_TIMEOUT;
+    use crate::pricing::PerBytePricing;
+    use bandwidth_prepay_api::bandwidth_prepay_state::Tariff;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net;
+    use std::path::PathBuf;
+    use std::sync::mpsc::{channel, Receiver};
+    use tokio::reactor::Handle;
+
+    fn journal_path() -> PathBuf {
+        env::temp_dir().join(format!("gatekeeper-journal-{}", Pubkey::new_rand()))
+    }
+
+    fn meter(initiator_fund: u64, journal: Journal) -> (Meter, Receiver<PendingCharge>) {
+        let pricing = Arc::new(PerBytePricing {
+            lamports_per_kib: 1,
+        });
+        let (charge_sender, charge_receiver) = channel();
+        let charge_sender = SpendQueue::new(charge_sender);
+        let mut accumulator = Accumulator::default();
+        accumulator.initiator_fund = initiator_fund;
+        let meter = Meter {
+            params: NewConnParams {
+                contract_pubkey: Pubkey::new_rand(),
+                destination: "127.0.0.1:0".to_string(),
+                fee_interval: 0,
+                next_hop: None,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            },
+            contract_state: BandwidthPrepayState {
+                tariff: Tariff {
+                    lamports_per_kib: 1,
+                    minimum_charge: 0,
+                },
+                ..BandwidthPrepayState::default()
+            },
+            accumulator,
+            channel_decoder: None,
+            gatekeeper_id: Pubkey::new_rand(),
+            receipts: ReceiptStore::default(),
+            charge_sender,
+            journal,
+            price: PriceMeter::new(pricing, "127.0.0.1:0"),
+        };
+        (meter, charge_receiver)
+    }
+
+    // Origin connected to `initiator`, and a destination that echoes what it gets
+    fn local_sockets() -> (net::TcpStream, TcpStream, TcpStream) {
+        let echo = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut socket, _) = echo.accept().unwrap();
+            let mut reader = socket.try_clone().unwrap();
+            let _ = io::copy(&mut reader, &mut socket);
+        });
+        let front = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let initiator = net::TcpStream::connect(front.local_addr().unwrap()).unwrap();
+        let (origin, _) = front.accept().unwrap();
+        let destination = net::TcpStream::connect(echo_addr).unwrap();
+        (
+            initiator,
+            TcpStream::from_std(origin, &Handle::default()).unwrap(),
+            TcpStream::from_std(destination, &Handle::default()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_forward() {
+        let mut runtime = Runtime::new().unwrap();
+        let (mut initiator, origin, destination) = local_sockets();
+        let path = journal_path();
+        let (journal, _) = Journal::open(&path).unwrap();
+        let (meter, charge_receiver) = meter(1_000, journal);
+        let meter = Arc::new(Mutex::new(meter));
+
+        let sent = vec![7; 4096];
+        let expected = sent.clone();
+        let client = thread::spawn(move || {
+            initiator.write_all(&sent).unwrap();
+            let mut received = vec![0; sent.len()];
+            initiator.read_exact(&mut received).unwrap();
+            received
+        });
+        let closed = runtime
+            .block_on(forward(origin, destination, meter.clone(), stream::empty()))
+            .unwrap();
+        assert_eq!(closed, Closed::Hangup);
+        assert_eq!(client.join().unwrap(), expected);
+
+        // Both directions are billed, whether already queued as charges or not
+        let meter = meter.lock().unwrap();
+        assert_eq!(meter.accumulator.total_data_amount, 2 * 4096);
+        let charged: u64 = charge_receiver
+            .try_iter()
+            .map(|charge| charge.data_amount)
+            .sum();
+        assert_eq!(charged + meter.accumulator.bytes_charged, 2 * 4096);
+
+        // Every queued charge is journaled, left for replay until settled
+        let (_, outstanding) = Journal::open(&path).unwrap();
+        let journaled = outstanding.last().map_or(0, |entry| entry.settled);
+        assert_eq!(journaled, charged);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_forward_exhausted() {
+        let mut runtime = Runtime::new().unwrap();
+        let (mut initiator, origin, destination) = local_sockets();
+        // Enough for the request but not for the echo
+        let path = journal_path();
+        let (journal, _) = Journal::open(&path).unwrap();
+        let (mut meter, _charge_receiver) = meter(5, journal);
+        // Hold charges back so the whole connection is costed at once
+        meter.params.fee_interval = u16::max_value();
+        let meter = Arc::new(Mutex::new(meter));
+
+        initiator.write_all(&[7; 4096]).unwrap();
+        let closed = runtime
+            .block_on(forward(origin, destination, meter.clone(), stream::empty()))
+            .unwrap();
+        assert_eq!(closed, Closed::Exhausted);
+
+        // The connection closes before the initiator gets back all it sent
+        let mut received = vec![];
+        initiator.read_to_end(&mut received).unwrap();
+        assert!(received.len() < 4096);
+        let meter = meter.lock().unwrap();
+        assert!(meter.accumulator.total_data_amount < 2 * 4096);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_forward_underpriced() {
+        let mut runtime = Runtime::new().unwrap();
+        let (mut initiator, origin, destination) = local_sockets();
+        let path = journal_path();
+        let (journal, _) = Journal::open(&path).unwrap();
+        let (mut meter, _charge_receiver) = meter(1_000, journal);
+        // The gatekeeper asks twice what the contract pays
+        let pricing = Arc::new(PerBytePricing {
+            lamports_per_kib: 2,
+        });
+        meter.price = PriceMeter::new(pricing, "127.0.0.1:0");
+        let meter = Arc::new(Mutex::new(meter));
+
+        initiator.write_all(&[7; 4096]).unwrap();
+        let closed = runtime
+            .block_on(forward(origin, destination, meter.clone(), stream::empty()))
+            .unwrap();
+        assert_eq!(closed, Closed::Underpriced);
+        let mut received = vec![];
+        initiator.read_to_end(&mut received).unwrap();
+        assert!(received.len() < 4096);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,17 @@
 use serde_derive::Deserialize;
 use solana_sdk::pubkey::Pubkey;
+use std::time::Duration;
 
-#[derive(Deserialize)]
+/// How long a forwarder waits for its destination to accept, unless configured
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Deserialize)]
 pub struct NewConnParams {
     pub contract_pubkey: Pubkey,
     pub destination: String,
     pub fee_interval: u16,
+    /// Gatekeeper to forward through on multi-hop contracts, instead of
+    /// connecting straight to `destination`
+    pub next_hop: Option<String>,
+    pub connect_timeout: Duration,
 }
@@ -1,21 +1,31 @@
 use crate::connection_params::NewConnParams;
+use bandwidth_prepay_api::bandwidth_prepay_address::contract_address;
+use bandwidth_prepay_api::bandwidth_prepay_channel::Voucher;
 use bandwidth_prepay_api::bandwidth_prepay_instruction;
-use bandwidth_prepay_api::bandwidth_prepay_state::BandwidthPrepayState;
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use bandwidth_prepay_api::bandwidth_prepay_state::{BandwidthPrepayError, BandwidthPrepayState};
 use bs58;
 use jsonrpc_core::types::error::Error;
 use log::*;
 use solana_sdk::client::Client;
 use solana_sdk::message::Message;
 use solana_sdk::pubkey::Pubkey;
-use solana_sdk::signature::{Keypair, KeypairUtil};
-use solana_sdk::transaction::Transaction;
+use solana_sdk::signature::{Keypair, KeypairUtil, Signature};
 use solana_sdk::transport::{Result as TransportResult, TransportError};
-use std::sync::mpsc::Receiver;
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
 use std::{io, mem};
 
+/// Replace a program's custom error code with the BandwidthPrepayError it stands for
+pub fn decode_error(err: TransportError) -> TransportError {
+    if let TransportError::TransactionError(transaction_error) = &err {
+        if let Some(prepay_error) = BandwidthPrepayError::from_transaction_error(transaction_error)
+        {
+            return TransportError::IoError(io::Error::new(io::ErrorKind::Other, prepay_error));
+        }
+    }
+    err
+}
+
 pub fn check_contract<T: Client>(
     parsed_params: &NewConnParams,
     client: &Arc<T>,
@@ -39,7 +49,9 @@ pub fn check_contract<T: Client>(
             format!("Unable to deserialize contract account: {:?}", err),
         ))
     })?;
-    if gatekeeper_id != &contract_state.gatekeeper_id {
+    if !contract_state.is_gatekeeper(gatekeeper_id)
+        && contract_state.hop_index(gatekeeper_id).is_none()
+    {
         error!(
             "incorrect contract_state gatekeeper_id: {:?}",
             contract_state.gatekeeper_id
@@ -52,7 +64,67 @@ pub fn check_contract<T: Client>(
             ),
         )));
     }
-    Ok((lamports, contract_state))
+    Ok((contract_state.balance(lamports), contract_state))
+}
+
+/// Whether the contract has nothing left to charge or refund: emptied by a
+/// refund, purged, or closed down to its reserve
+pub fn contract_closed<T: Client>(
+    client: &Arc<T>,
+    contract_pubkey: &Pubkey,
+) -> TransportResult<bool> {
+    let lamports = client.get_balance(contract_pubkey)?;
+    // Closed contracts keep a reserve but read as the default state
+    let data = client.get_account_data(contract_pubkey)?;
+    Ok(lamports == 0
+        || data.map_or(true, |data| {
+            BandwidthPrepayState::deserialize(&data) == Ok(BandwidthPrepayState::default())
+        }))
+}
+
+/// Whether the contract belongs to `initiator_id`. With a session nonce, its
+/// address must also be the one derived for the initiator, where the program
+/// only lets the initiator open it; contracts opened before derived addresses
+/// are presented without one.
+pub fn check_initiator(
+    parsed_params: &NewConnParams,
+    contract_state: &BandwidthPrepayState,
+    initiator_id: &Pubkey,
+    session_nonce: Option<u64>,
+) -> bool {
+    if &contract_state.initiator_id != initiator_id {
+        return false;
+    }
+    session_nonce.map_or(true, |session_nonce| {
+        contract_address(
+            initiator_id,
+            contract_state.address_gatekeeper_id(),
+            session_nonce,
+        ) == parsed_params.contract_pubkey
+    })
+}
+
+/// Rewrite a contract created under an older state layout in the current one,
+/// if it fits; contracts it doesn't fit in are charged in the layout they hold
+pub fn migrate_contract<T: Client>(
+    parsed_params: &NewConnParams,
+    client: &Arc<T>,
+    gatekeeper: &Keypair,
+) -> TransportResult<()> {
+    if let Some(data) = client.get_account_data(&parsed_params.contract_pubkey)? {
+        if BandwidthPrepayState::can_migrate(&data) {
+            info!("migrating contract {:?}", parsed_params.contract_pubkey);
+            let instruction = bandwidth_prepay_instruction::migrate(
+                &gatekeeper.pubkey(),
+                &parsed_params.contract_pubkey,
+            );
+            let message = Message::new(vec![instruction]);
+            let _ = client
+                .send_message(&[gatekeeper], message)
+                .map_err(decode_error)?;
+        }
+    }
+    Ok(())
 }
 
 pub fn verify_pubkey(input: String) -> Result<Pubkey, Error> {
@@ -71,91 +143,157 @@ pub fn verify_pubkey(input: String) -> Result<Pubkey, Error> {
     }
 }
 
+pub fn verify_signature(input: String) -> Result<Signature, Error> {
+    let signature_vec = bs58::decode(input).into_vec().map_err(|err| {
+        info!("verify_signature: invalid input: {:?}", err);
+        Error::invalid_request()
+    })?;
+    if signature_vec.len() != mem::size_of::<Signature>() {
+        info!(
+            "verify_signature: invalid signature_vec length: {}",
+            signature_vec.len()
+        );
+        Err(Error::invalid_request())
+    } else {
+        Ok(Signature::new(&signature_vec))
+    }
+}
+
 pub fn charge_contract<T: Client>(
     parsed_params: &NewConnParams,
     client: &Arc<T>,
     contract_state: &BandwidthPrepayState,
     gatekeeper: &Keypair,
-    amount: u64,
+    data_amount: u64,
+    receipt: Option<&UsageReceipt>,
 ) -> TransportResult<()> {
     let message = build_spend_message(
         gatekeeper,
         &parsed_params.contract_pubkey,
-        &contract_state.provider_id,
-        amount,
+        contract_state,
+        data_amount,
+        receipt,
     );
-    let _ = client.send_message(&[gatekeeper], message)?;
+    let _ = client
+        .send_message(&[gatekeeper], message)
+        .map_err(decode_error)?;
     Ok(())
 }
 
 fn build_spend_message(
     gatekeeper: &Keypair,
     contract_pubkey: &Pubkey,
-    provider_id: &Pubkey,
-    amount: u64,
+    contract_state: &BandwidthPrepayState,
+    data_amount: u64,
+    receipt: Option<&UsageReceipt>,
 ) -> Message {
-    let instruction = bandwidth_prepay_instruction::spend(
-        &gatekeeper.pubkey(),
-        &contract_pubkey,
-        &provider_id,
-        amount,
-    );
+    let payee_ids = contract_state.payee_ids();
+    let provider_id = contract_state.provider_for(&gatekeeper.pubkey());
+    let instruction = match receipt {
+        Some(receipt) => bandwidth_prepay_instruction::spend_with_receipt(
+            &gatekeeper.pubkey(),
+            &contract_pubkey,
+            provider_id,
+            &payee_ids,
+            data_amount,
+            receipt,
+        ),
+        None => bandwidth_prepay_instruction::spend(
+            &gatekeeper.pubkey(),
+            &contract_pubkey,
+            provider_id,
+            &payee_ids,
+            data_amount,
+        ),
+    };
     Message::new(vec![instruction])
 }
 
-pub fn build_and_sign_spend_transaction<T: Client>(
+pub fn refund<T: Client>(
+    parsed_params: &NewConnParams,
     client: &Arc<T>,
+    contract_state: &BandwidthPrepayState,
     gatekeeper: &Keypair,
-    contract_pubkey: &Pubkey,
-    provider_id: &Pubkey,
-    amount: u64,
-) -> Transaction {
-    let (blockhash, _) = client.get_recent_blockhash().unwrap();
-    let message = build_spend_message(gatekeeper, contract_pubkey, provider_id, amount);
-    Transaction::new(&[gatekeeper], message, blockhash)
+) -> TransportResult<()> {
+    let instruction = bandwidth_prepay_instruction::refund(
+        &gatekeeper.pubkey(),
+        &parsed_params.contract_pubkey,
+        contract_state.refund_id(),
+    );
+    let message = Message::new(vec![instruction]);
+    let _ = client
+        .send_message(&[gatekeeper], message)
+        .map_err(decode_error)?;
+    Ok(())
 }
 
-pub fn submit_transaction_loop<T: Client>(solana_receiver: &Receiver<(Arc<T>, Transaction)>) {
-    loop {
-        if let Ok((client, transaction)) = solana_receiver.try_recv() {
-            if let Err(e) = client.async_send_transaction(transaction) {
-                error!(
-                    "Error sending charge transaction to solana fullnode: {:?}",
-                    e
-                );
-            };
-        }
-        thread::sleep(Duration::from_millis(10));
+pub fn close<T: Client>(
+    parsed_params: &NewConnParams,
+    client: &Arc<T>,
+    contract_state: &BandwidthPrepayState,
+    gatekeeper: &Keypair,
+) -> TransportResult<()> {
+    let mut instructions = vec![];
+    if contract_state.credits {
+        // Escrowed credits must be returned before the account can be closed
+        instructions.push(bandwidth_prepay_instruction::refund(
+            &gatekeeper.pubkey(),
+            &parsed_params.contract_pubkey,
+            contract_state.refund_id(),
+        ));
     }
+    instructions.push(bandwidth_prepay_instruction::close(
+        &gatekeeper.pubkey(),
+        &parsed_params.contract_pubkey,
+        &contract_state.initiator_id,
+    ));
+    let message = Message::new(instructions);
+    let _ = client
+        .send_message(&[gatekeeper], message)
+        .map_err(decode_error)?;
+    Ok(())
 }
 
-pub fn refund<T: Client>(
+pub fn settle_channel<T: Client>(
     parsed_params: &NewConnParams,
     client: &Arc<T>,
     contract_state: &BandwidthPrepayState,
     gatekeeper: &Keypair,
+    voucher: &Voucher,
 ) -> TransportResult<()> {
-    let instruction = bandwidth_prepay_instruction::refund(
+    let instruction = bandwidth_prepay_instruction::settle_channel(
         &gatekeeper.pubkey(),
         &parsed_params.contract_pubkey,
+        &contract_state.provider_id,
         &contract_state.initiator_id,
+        &contract_state.payee_ids(),
+        voucher,
     );
     let message = Message::new(vec![instruction]);
-    let _ = client.send_message(&[gatekeeper], message)?;
+    let _ = client
+        .send_message(&[gatekeeper], message)
+        .map_err(decode_error)?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::connection_params::DEFAULT_CONNECT_TIMEOUT;
+    use bandwidth_prepay_api::bandwidth_prepay_state::{ContractTerms, Tariff};
     use bandwidth_prepay_api::{self, bandwidth_prepay_processor::process_instruction};
     use solana_runtime::bank::Bank;
     use solana_runtime::bank_client::BankClient;
     use solana_sdk::client::SyncClient;
     use solana_sdk::genesis_block::create_genesis_block;
+    use solana_sdk::instruction::InstructionError;
     use solana_sdk::system_instruction;
-    use std::sync::mpsc::channel;
-    use std::thread::Builder;
+    use solana_sdk::transaction::TransactionError;
+
+    const TARIFF: Tariff = Tariff {
+        lamports_per_kib: 1,
+        minimum_charge: 0,
+    };
 
     #[test]
     fn test_verify_pubkey() {
@@ -179,6 +317,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_verify_signature() {
+        let signature = Keypair::new().sign_message(&[0; 8]);
+        assert_eq!(verify_signature(signature.to_string()).unwrap(), signature);
+        assert_eq!(
+            verify_signature(Pubkey::new_rand().to_string()),
+            Err(Error::invalid_request())
+        );
+        assert_eq!(
+            verify_signature("".to_string()),
+            Err(Error::invalid_request())
+        );
+    }
+
     #[test]
     fn test_check_contract() {
         let (genesis_block, alice_keypair) = create_genesis_block(10_000);
@@ -187,20 +339,26 @@ mod tests {
         let client = Arc::new(BankClient::new(bank));
 
         let alice_pubkey = alice_keypair.pubkey();
-        let contract = Keypair::new().pubkey();
         let gatekeeper = Keypair::new().pubkey();
+        let contract = contract_address(&alice_pubkey, &gatekeeper, 0);
         let provider = Keypair::new().pubkey();
 
         let params = NewConnParams {
             contract_pubkey: contract,
             destination: "127.0.0.1:1234".to_string(),
             fee_interval: 1000,
+            next_hop: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
         };
 
         let expected_state = BandwidthPrepayState {
             gatekeeper_id: gatekeeper.clone(),
             provider_id: provider.clone(),
             initiator_id: alice_pubkey.clone(),
+            tariff: TARIFF,
+            total_deposited: 500,
+            origin_gatekeeper_id: gatekeeper,
+            ..BandwidthPrepayState::default()
         };
 
         let instructions = bandwidth_prepay_instruction::initialize(
@@ -208,6 +366,10 @@ mod tests {
             &contract,
             &gatekeeper,
             &provider,
+            ContractTerms {
+                tariff: TARIFF,
+                ..ContractTerms::default()
+            },
             500,
         );
         let message = Message::new(instructions);
@@ -223,10 +385,46 @@ mod tests {
             contract_pubkey: Pubkey::new(&vec![5; 32]),
             destination: "127.0.0.1:1234".to_string(),
             fee_interval: 1000,
+            next_hop: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
         };
         assert!(check_contract(&params, &client, &gatekeeper).is_err());
     }
 
+    #[test]
+    fn test_check_initiator() {
+        let initiator = Pubkey::new_rand();
+        let state = BandwidthPrepayState {
+            gatekeeper_id: Pubkey::new_rand(),
+            initiator_id: initiator,
+            ..BandwidthPrepayState::default()
+        };
+        let params = NewConnParams {
+            contract_pubkey: contract_address(&initiator, &state.gatekeeper_id, 3),
+            destination: "127.0.0.1:1234".to_string(),
+            fee_interval: 1000,
+            next_hop: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        };
+        assert!(check_initiator(&params, &state, &initiator, Some(3)));
+        assert!(check_initiator(&params, &state, &initiator, None));
+        assert!(!check_initiator(&params, &state, &initiator, Some(4)));
+        assert!(!check_initiator(
+            &params,
+            &state,
+            &Pubkey::new_rand(),
+            Some(3)
+        ));
+
+        // Handing the contract to a new key leaves it where it was derived
+        let state = BandwidthPrepayState {
+            gatekeeper_id: Pubkey::new_rand(),
+            origin_gatekeeper_id: state.gatekeeper_id,
+            ..state
+        };
+        assert!(check_initiator(&params, &state, &initiator, Some(3)));
+    }
+
     #[test]
     fn test_charge_contract() {
         let (genesis_block, alice_keypair) = create_genesis_block(10_000);
@@ -235,8 +433,8 @@ mod tests {
         let bank_client = Arc::new(BankClient::new(bank));
 
         let alice_pubkey = alice_keypair.pubkey();
-        let contract = Keypair::new().pubkey();
         let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
         let provider = Keypair::new().pubkey();
 
         // Initialize Contract
@@ -245,6 +443,10 @@ mod tests {
             &contract,
             &gatekeeper.pubkey(),
             &provider,
+            ContractTerms {
+                tariff: TARIFF,
+                ..ContractTerms::default()
+            },
             500,
         );
         let message = Message::new(instructions);
@@ -263,14 +465,18 @@ mod tests {
             contract_pubkey: contract.clone(),
             destination: "127.0.0.1:1234".to_string(),
             fee_interval: 1000,
+            next_hop: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
         };
         let state = BandwidthPrepayState {
             gatekeeper_id: gatekeeper.pubkey(),
             provider_id: provider.clone(),
             initiator_id: alice_pubkey.clone(),
+            tariff: TARIFF,
+            ..BandwidthPrepayState::default()
         };
 
-        charge_contract(&params, &bank_client, &state, &gatekeeper, 100).unwrap();
+        charge_contract(&params, &bank_client, &state, &gatekeeper, 100 * 1024, None).unwrap();
 
         let balance = bank_client.get_balance(&contract).unwrap();
         assert_eq!(balance, 400);
@@ -284,61 +490,77 @@ mod tests {
     }
 
     #[test]
-    fn test_submit_transaction_loop() {
+    fn test_refund() {
         let (genesis_block, alice_keypair) = create_genesis_block(10_000);
         let mut bank = Bank::new(&genesis_block);
         bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
         let bank_client = Arc::new(BankClient::new(bank));
-        let client_clone0 = bank_client.clone();
-        let client_clone1 = bank_client.clone();
 
         let alice_pubkey = alice_keypair.pubkey();
-        let recipient = Keypair::new().pubkey();
-
-        let (sender, receiver) = channel();
-        Builder::new()
-            .name("test_submit_transaction_loop".to_string())
-            .spawn(move || {
-                submit_transaction_loop(&receiver);
-            })
-            .unwrap();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let provider = Keypair::new().pubkey();
 
-        let instruction = system_instruction::transfer(&alice_pubkey, &recipient, 100);
+        // Initialize Contract
+        let instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                tariff: TARIFF,
+                ..ContractTerms::default()
+            },
+            500,
+        );
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        // Make sure gatekeeper account exists
+        let instruction = system_instruction::transfer(&alice_pubkey, &gatekeeper.pubkey(), 1);
         let message = Message::new(vec![instruction]);
-        let (blockhash, _) = bank_client.get_recent_blockhash().unwrap();
-        let transaction = Transaction::new(&[&alice_keypair], message, blockhash);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 1);
 
-        sender.send((client_clone0, transaction)).unwrap();
-        let mut balance = 0;
-        while balance == 0 {
-            balance = bank_client.get_balance(&recipient).unwrap();
-        }
-        assert_eq!(balance, 100);
-        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_900);
+        let params = NewConnParams {
+            contract_pubkey: contract.clone(),
+            destination: "127.0.0.1:1234".to_string(),
+            fee_interval: 1000,
+            next_hop: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        };
+        let state = BandwidthPrepayState {
+            gatekeeper_id: gatekeeper.pubkey(),
+            provider_id: provider.clone(),
+            initiator_id: alice_pubkey.clone(),
+            tariff: TARIFF,
+            ..BandwidthPrepayState::default()
+        };
 
-        let instruction = system_instruction::transfer(&alice_pubkey, &recipient, 90);
-        let message = Message::new(vec![instruction]);
-        let (blockhash, _) = bank_client.get_recent_blockhash().unwrap();
-        let transaction = Transaction::new(&[&alice_keypair], message, blockhash);
+        charge_contract(&params, &bank_client, &state, &gatekeeper, 100 * 1024, None).unwrap();
+        refund(&params, &bank_client, &state, &gatekeeper).unwrap();
 
-        sender.send((client_clone1, transaction)).unwrap();
-        while balance == 100 {
-            balance = bank_client.get_balance(&recipient).unwrap();
-        }
-        assert_eq!(balance, 190);
-        assert_eq!(bank_client.get_balance(&alice_pubkey).unwrap(), 9_810);
+        let balance = bank_client.get_balance(&contract).unwrap();
+        assert_eq!(balance, 0);
+        let balance = bank_client.get_balance(&provider).unwrap();
+        assert_eq!(balance, 100);
+        let balance = bank_client.get_balance(&alice_pubkey).unwrap();
+        assert_eq!(balance, 9_899);
     }
 
     #[test]
-    fn test_refund() {
+    fn test_settle_channel() {
         let (genesis_block, alice_keypair) = create_genesis_block(10_000);
         let mut bank = Bank::new(&genesis_block);
         bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
         let bank_client = Arc::new(BankClient::new(bank));
 
         let alice_pubkey = alice_keypair.pubkey();
-        let contract = Keypair::new().pubkey();
         let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
         let provider = Keypair::new().pubkey();
 
         // Initialize Contract
@@ -347,6 +569,11 @@ mod tests {
             &contract,
             &gatekeeper.pubkey(),
             &provider,
+            ContractTerms {
+                tariff: TARIFF,
+                channel_dispute_window: Some(10),
+                ..ContractTerms::default()
+            },
             500,
         );
         let message = Message::new(instructions);
@@ -359,27 +586,47 @@ mod tests {
         bank_client
             .send_message(&[&alice_keypair], message)
             .unwrap();
-        assert_eq!(bank_client.get_balance(&gatekeeper.pubkey()).unwrap(), 1);
 
         let params = NewConnParams {
             contract_pubkey: contract.clone(),
             destination: "127.0.0.1:1234".to_string(),
             fee_interval: 1000,
+            next_hop: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
         };
-        let state = BandwidthPrepayState {
-            gatekeeper_id: gatekeeper.pubkey(),
-            provider_id: provider.clone(),
-            initiator_id: alice_pubkey.clone(),
-        };
+        let (_, state) = check_contract(&params, &bank_client, &gatekeeper.pubkey()).unwrap();
+        assert!(state.payment_channel);
 
-        charge_contract(&params, &bank_client, &state, &gatekeeper, 100).unwrap();
-        refund(&params, &bank_client, &state, &gatekeeper).unwrap();
+        let voucher = Voucher::new(&alice_keypair, &contract, 150);
+        settle_channel(&params, &bank_client, &state, &gatekeeper, &voucher).unwrap();
 
         let balance = bank_client.get_balance(&contract).unwrap();
         assert_eq!(balance, 0);
         let balance = bank_client.get_balance(&provider).unwrap();
-        assert_eq!(balance, 100);
+        assert_eq!(balance, 150);
         let balance = bank_client.get_balance(&alice_pubkey).unwrap();
-        assert_eq!(balance, 9_899);
+        assert_eq!(balance, 9_849);
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let err = TransportError::TransactionError(TransactionError::InstructionError(
+            0,
+            InstructionError::CustomError(BandwidthPrepayError::BalanceTooLow as u32),
+        ));
+        match decode_error(err) {
+            TransportError::IoError(err) => assert_eq!(
+                err.get_ref()
+                    .and_then(|err| err.downcast_ref::<BandwidthPrepayError>()),
+                Some(&BandwidthPrepayError::BalanceTooLow)
+            ),
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        let err = TransportError::TransactionError(TransactionError::AccountInUse);
+        match decode_error(err) {
+            TransportError::TransactionError(TransactionError::AccountInUse) => (),
+            err => panic!("unexpected error: {:?}", err),
+        }
     }
 }
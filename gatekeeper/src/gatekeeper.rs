@@ -1,174 +1,133 @@
 use crate::accumulator::Accumulator;
-use crate::business_logic::business_logic;
+use crate::batcher::{PendingCharge, SpendQueue, SpendTracker, CONFIRMATION_TIMEOUT};
 use crate::connection_params::NewConnParams;
 use crate::contract::*;
+use crate::receipts::ReceiptStore;
+use bandwidth_prepay_api::bandwidth_prepay_channel::Voucher;
 use bandwidth_prepay_api::bandwidth_prepay_state::BandwidthPrepayState;
 use log::*;
-use mio::net::TcpStream;
-use mio::unix::UnixReady;
-use mio::{Events, Poll, PollOpt, Ready, Token};
-use pubsub_client::client::{start_pubsub, Event};
-use pubsub_client::request::PubSubRequest;
+use pubsub_client::client::Event;
 use serde_json::Value;
 use solana_sdk::account::Account;
 use solana_sdk::client::Client;
+use solana_sdk::pubkey::Pubkey;
 use solana_sdk::signature::{Keypair, KeypairUtil};
-use solana_sdk::transaction::Transaction;
-use std::io::ErrorKind;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::cmp;
+use std::sync::mpsc::Receiver;
 use std::sync::Arc;
-use std::thread;
 use std::time::Instant;
 
-const DESTINATION: Token = Token(0);
-const ORIGIN: Token = Token(1);
+// Lamports of usage carried on a payment channel ahead of the initiator's vouchers
+const CHANNEL_CREDIT: u64 = 64;
 
-pub fn forwarder<T>(
+/// Settle a finished connection once its queued charges have landed: charge
+/// what is left and close the contract, or settle its payment channel on the
+/// latest voucher; false if any of it failed
+pub fn finish_connection<T: Client>(
     params: &NewConnParams,
     gatekeeper: &Keypair,
     client: &Arc<T>,
-    contract_state: &BandwidthPrepayState,
-    starting_balance: u64,
-    ws_addr: SocketAddr,
-    sender: Sender<u16>,
-) where
-    T: 'static + Client + Send + Sync,
-{
-    let poll = Poll::new().unwrap();
-    let mut events = Events::with_capacity(1024);
-
-    info!("Connecting to {}", params.destination);
-    let destination = std::net::TcpStream::connect(params.destination.clone()).unwrap(); // Blocking call, unlike mio's sockets
-    let mut destination = TcpStream::from_stream(destination).unwrap(); // Convert to mio socket
-    info!("Connected to {}", destination.peer_addr().unwrap());
-    poll.register(
-        &destination,
-        DESTINATION,
-        Ready::readable(),
-        PollOpt::edge(),
-    )
-    .unwrap();
-
-    let listener = TcpListener::bind("0.0.0.0:0".to_string()).unwrap();
-    sender.send(listener.local_addr().unwrap().port()).unwrap();
-
-    let (socket, addr) = listener.accept().unwrap();
-    info!("Gatekeeper connected to {}", addr);
-
-    let mut origin = TcpStream::from_stream(socket).unwrap();
-    poll.register(
-        &origin,
-        ORIGIN,
-        Ready::readable() | UnixReady::hup(),
-        PollOpt::edge(),
-    )
-    .unwrap();
-
-    let pubsub_thread = start_pubsub(
-        format!("ws://{}", ws_addr),
-        PubSubRequest::Account,
-        &params.contract_pubkey,
-    )
-    .unwrap();
-
-    let (solana_sender, solana_receiver) = channel();
-    thread::spawn(move || {
-        submit_transaction_loop(&solana_receiver);
-    });
-
-    let mut accumulator = Accumulator::default();
-    let mut data = [0 as u8; 1024];
-    accumulator.initiator_fund = starting_balance;
-    let initiator = origin.peer_addr().unwrap();
-    let recipient = destination.peer_addr().unwrap();
-
-    'outer: loop {
-        poll.poll(&mut events, None).unwrap();
-
-        for event in &events {
-            match event.token() {
-                ORIGIN => {
-                    if UnixReady::from(event.readiness()).is_hup() {
-                        break 'outer;
+    accumulator: &mut Accumulator,
+    receipts: &ReceiptStore,
+    charges: &SpendTracker,
+) -> bool {
+    if !drain_charges(params, accumulator, charges) {
+        return false;
+    }
+    match contract_closed(client, &params.contract_pubkey) {
+        // Refunded when funds ran out, or already closed
+        Ok(true) => {
+            receipts.remove(&params.contract_pubkey);
+            return true;
+        }
+        Ok(false) => (),
+        Err(e) => {
+            error!("Could not fetch {:?}: {:?}", params.contract_pubkey, e);
+            receipts.remove(&params.contract_pubkey);
+            return false;
+        }
+    }
+    let mut settled = true;
+    match check_contract(params, client, &gatekeeper.pubkey()) {
+        Ok((_, contract_state)) => {
+            if contract_state.payment_channel {
+                let result = match &accumulator.voucher {
+                    Some(voucher) => {
+                        settle_channel(params, client, &contract_state, gatekeeper, voucher)
                     }
-                    if event.readiness().is_readable() {
-                        while match origin.read(&mut data) {
-                            Ok(data_amount) => {
-                                if process_data(
-                                    params,
-                                    gatekeeper,
-                                    client,
-                                    contract_state,
-                                    &mut accumulator,
-                                    &pubsub_thread.receiver,
-                                    data_amount as u64,
-                                    &solana_sender,
-                                ) {
-                                    break 'outer;
-                                }
-                                destination.write_all(&data[0..data_amount]).unwrap();
-                                true
-                            }
-                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
-                            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
-                                break 'outer;
-                            }
-                            Err(e) => Err(e).unwrap(),
-                        } {}
+                    None => close(params, client, &contract_state, gatekeeper),
+                };
+                if let Err(e) = result {
+                    error!("Could not settle {:?}: {:?}", params.contract_pubkey, e);
+                    settled = false;
+                }
+            } else {
+                if contract_state.receipts_required {
+                    accumulator.receipt = receipts.get(&params.contract_pubkey);
+                }
+                let data_amount = settleable_bytes(&contract_state, &accumulator);
+                if data_amount > 0 {
+                    if let Err(e) = charge_contract(
+                        params,
+                        client,
+                        &contract_state,
+                        gatekeeper,
+                        data_amount,
+                        accumulator.receipt.as_ref(),
+                    ) {
+                        error!("Could not charge {:?}: {:?}", params.contract_pubkey, e);
+                        settled = false;
                     }
                 }
-                DESTINATION => {
-                    while match destination.read(&mut data) {
-                        Ok(data_amount) => {
-                            if process_data(
-                                params,
-                                gatekeeper,
-                                client,
-                                contract_state,
-                                &mut accumulator,
-                                &pubsub_thread.receiver,
-                                data_amount as u64,
-                                &solana_sender,
-                            ) {
-                                break 'outer;
-                            }
-                            origin.write_all(&data[0..data_amount]).unwrap();
-                            true
-                        }
-                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
-                        Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
-                            break 'outer;
-                        }
-                        Err(e) => Err(e).unwrap(),
-                    } {}
+                // Only the contract's own gatekeeper settles it; later hops just charge their share
+                if contract_state.is_gatekeeper(&gatekeeper.pubkey()) {
+                    if let Err(e) = close(params, client, &contract_state, gatekeeper) {
+                        error!("Could not close {:?}: {:?}", params.contract_pubkey, e);
+                        settled = false;
+                    }
                 }
-                token => info!("Invalid token: {:?}", token),
             }
         }
-    }
-    if let Ok((_, contract_state)) = check_contract(params, client, &gatekeeper.pubkey()) {
-        if accumulator.amount_charged > 0 {
-            charge_contract(
-                params,
-                client,
-                &contract_state,
-                gatekeeper,
-                accumulator.amount_charged,
-            )
-            .unwrap();
+        Err(e) => {
+            error!("Could not check {:?}: {:?}", params.contract_pubkey, e);
+            settled = false;
         }
-        refund(params, client, &contract_state, gatekeeper).unwrap();
     }
+    receipts.remove(&params.contract_pubkey);
+    settled
+}
 
-    info!(
-        "Bytes transmitted between {} and {}: {}",
-        initiator, recipient, accumulator.total_data_amount
-    );
+/// Wait for the batcher to settle the charges queued on the contract, which
+/// closing or refunding it would otherwise leave nothing to pay, and bill
+/// again those that failed
+fn drain_charges(
+    params: &NewConnParams,
+    accumulator: &mut Accumulator,
+    charges: &SpendTracker,
+) -> bool {
+    if !charges.drain(&params.contract_pubkey, CONFIRMATION_TIMEOUT) {
+        error!(
+            "Charges on {:?} are still unconfirmed",
+            params.contract_pubkey
+        );
+        return false;
+    }
+    let failed = charges.take_failed(&params.contract_pubkey);
+    reclaim_failed(params, accumulator, failed);
+    true
+}
 
-    // close the socket
-    drop(listener);
+/// Count the bytes of charges the batcher gave up on as not yet settled
+fn reclaim_failed(params: &NewConnParams, accumulator: &mut Accumulator, failed: u64) {
+    if failed == 0 {
+        return;
+    }
+    warn!(
+        "Billing {} bytes on {:?} again after their charge failed",
+        failed, params.contract_pubkey
+    );
+    accumulator.bytes_charged += failed;
+    accumulator.bytes_settled = accumulator.bytes_settled.saturating_sub(failed);
 }
 
 pub fn process_data<T: Client>(
@@ -179,19 +138,23 @@ pub fn process_data<T: Client>(
     accumulator: &mut Accumulator,
     pubsub_receiver: &Receiver<Event>,
     data_amount: u64,
-    solana_sender: &Sender<(Arc<T>, Transaction)>,
+    charge_sender: &SpendQueue,
 ) -> bool {
     if let Ok(event) = pubsub_receiver.try_recv() {
         match event {
             Event::Message(notification) => {
-                let json: Value = serde_json::from_str(&notification.into_text().unwrap()).unwrap();
-                let account_json = json["params"]["result"].clone();
-                let account: Account = serde_json::from_value(account_json).unwrap();
-                info!(
-                    "received notification. account balance: {}",
-                    account.lamports
-                );
-                accumulator.initiator_fund = account.lamports;
+                let json: Result<Value, ()> = notification
+                    .into_text()
+                    .map_err(|_| ())
+                    .and_then(|text| serde_json::from_str(&text).map_err(|_| ()));
+                match json {
+                    Ok(json) => {
+                        if let Some(balance) = notified_balance(contract_state, &json) {
+                            accumulator.initiator_fund = balance;
+                        }
+                    }
+                    Err(()) => warn!("Ignoring unreadable PubSub notification"),
+                }
             }
             Event::Disconnect(_, _) => {
                 warn!("PubSub connection dropped");
@@ -200,49 +163,391 @@ pub fn process_data<T: Client>(
         };
     }
 
-    let cost = business_logic(data_amount);
-    if accumulator.amount_charged + cost <= accumulator.initiator_fund {
-        accumulator.amount_charged += cost;
-        accumulator.total_data_amount += data_amount;
-
-        if accumulator.now.elapsed().as_millis() > u128::from(params.fee_interval) {
-            info!(
-                "Account balance: {}, Cost: {}",
-                accumulator.initiator_fund, accumulator.amount_charged
-            );
-            let transaction = build_and_sign_spend_transaction(
-                client,
-                gatekeeper,
-                &params.contract_pubkey,
-                &contract_state.provider_id,
-                accumulator.amount_charged,
-            );
-            let client = client.clone();
-            if let Err(e) = solana_sender.send((client, transaction)) {
-                error!("Error sending amount to be charged: {}", e);
-            } else {
-                accumulator.initiator_fund -= accumulator.amount_charged;
-                accumulator.amount_charged = 0;
-            }
-            accumulator.now = Instant::now();
-        }
-        false
+    if meter_data(
+        params,
+        &gatekeeper.pubkey(),
+        contract_state,
+        accumulator,
+        data_amount,
+        charge_sender,
+    ) {
+        settle_exhausted(
+            params,
+            gatekeeper,
+            client,
+            contract_state,
+            accumulator,
+            &charge_sender.tracker(),
+        );
+        return true;
+    }
+    false
+}
+
+/// Balance reported by an account notification on the contract
+pub fn notified_balance(
+    contract_state: &BandwidthPrepayState,
+    notification: &Value,
+) -> Option<u64> {
+    let account_json = notification["params"]["result"].clone();
+    let account: Account = serde_json::from_value(account_json).ok()?;
+    let balance = if contract_state.credits {
+        BandwidthPrepayState::deserialize(&account.data)
+            .map(|state| state.credit_balance)
+            .unwrap_or(0)
     } else {
+        account.lamports
+    };
+    info!("received notification. account balance: {}", balance);
+    Some(balance)
+}
+
+/// Count `data_amount` more bytes against the contract, queueing a charge once
+/// per fee interval; true once the initiator's funds can no longer cover them
+pub fn meter_data(
+    params: &NewConnParams,
+    gatekeeper_id: &Pubkey,
+    contract_state: &BandwidthPrepayState,
+    accumulator: &mut Accumulator,
+    data_amount: u64,
+    charge_sender: &SpendQueue,
+) -> bool {
+    if contract_state.payment_channel {
+        return process_channel_data(contract_state, accumulator, data_amount);
+    }
+
+    let failed = charge_sender.take_failed(&params.contract_pubkey);
+    reclaim_failed(params, accumulator, failed);
+    let bytes_charged = accumulator.bytes_charged + data_amount;
+    // The balance only drops once spends are confirmed, so count those still in flight
+    let unconfirmed = charge_sender.unconfirmed_bytes(&params.contract_pubkey);
+    let cost = contract_state
+        .tariff
+        .charge(bytes_charged + unconfirmed)
+        .unwrap_or(u64::max_value());
+    if cost > accumulator.initiator_fund {
+        return true;
+    }
+    accumulator.bytes_charged = bytes_charged;
+    accumulator.total_data_amount += data_amount;
+
+    let data_amount = settleable_bytes(contract_state, accumulator);
+    if data_amount > 0 && accumulator.now.elapsed().as_millis() > u128::from(params.fee_interval) {
         info!(
             "Account balance: {}, Cost: {}",
-            accumulator.initiator_fund, accumulator.amount_charged
+            accumulator.initiator_fund, cost
         );
-        charge_contract(
+        let charge = PendingCharge::new(
+            &params.contract_pubkey,
+            contract_state,
+            gatekeeper_id,
+            data_amount,
+            accumulator.receipt.as_ref(),
+        );
+        if let Err(e) = charge_sender.send(charge) {
+            error!("Error sending amount to be charged: {}", e);
+        } else {
+            accumulator.bytes_charged -= data_amount;
+            accumulator.bytes_settled += data_amount;
+        }
+        accumulator.now = Instant::now();
+    }
+    false
+}
+
+/// Once funds run out and the queued charges have landed, charge what the
+/// initiator can still pay for and hand back the rest, false if either failed;
+/// payment channels wait for `finish_connection` instead
+pub fn settle_exhausted<T: Client>(
+    params: &NewConnParams,
+    gatekeeper: &Keypair,
+    client: &Arc<T>,
+    contract_state: &BandwidthPrepayState,
+    accumulator: &mut Accumulator,
+    charges: &SpendTracker,
+) -> bool {
+    if contract_state.payment_channel {
+        return true;
+    }
+    if !drain_charges(params, accumulator, charges) {
+        return false;
+    }
+    let mut settled = true;
+    let data_amount = settleable_bytes(contract_state, accumulator);
+    let cost = contract_state.tariff.charge(data_amount).unwrap_or(0);
+    info!(
+        "Account balance: {}, Cost: {}",
+        accumulator.initiator_fund, cost
+    );
+    if data_amount > 0 {
+        match charge_contract(
             params,
             client,
             contract_state,
             gatekeeper,
-            accumulator.amount_charged,
-        )
-        .unwrap();
-        if accumulator.initiator_fund - accumulator.amount_charged > 0 {
-            refund(params, client, contract_state, gatekeeper).unwrap();
+            data_amount,
+            accumulator.receipt.as_ref(),
+        ) {
+            Ok(()) => {
+                accumulator.bytes_charged -= data_amount;
+                accumulator.bytes_settled += data_amount;
+            }
+            Err(e) => {
+                error!("Could not charge {:?}: {:?}", params.contract_pubkey, e);
+                settled = false;
+            }
         }
-        true
+    }
+    if accumulator.initiator_fund > cost && contract_state.is_gatekeeper(&gatekeeper.pubkey()) {
+        if let Err(e) = refund(params, client, contract_state, gatekeeper) {
+            error!("Could not refund {:?}: {:?}", params.contract_pubkey, e);
+            settled = false;
+        }
+    }
+    settled
+}
+
+/// Bytes that can be charged now; in receipt mode only usage the initiator has acknowledged
+fn settleable_bytes(contract_state: &BandwidthPrepayState, accumulator: &Accumulator) -> u64 {
+    if !contract_state.receipts_required {
+        return accumulator.bytes_charged;
+    }
+    accumulator.receipt.as_ref().map_or(0, |receipt| {
+        let acknowledged = receipt
+            .cumulative_bytes
+            .saturating_sub(accumulator.bytes_settled);
+        cmp::min(accumulator.bytes_charged, acknowledged)
+    })
+}
+
+/// Payment channels settle once at close, so only check that vouchers keep up with usage
+fn process_channel_data(
+    contract_state: &BandwidthPrepayState,
+    accumulator: &mut Accumulator,
+    data_amount: u64,
+) -> bool {
+    let bytes_charged = accumulator.bytes_charged + data_amount;
+    let cost = contract_state
+        .tariff
+        .charge(bytes_charged)
+        .unwrap_or(u64::max_value());
+    if cost > accumulator.initiator_fund {
+        info!(
+            "Channel balance: {}, Cost: {}",
+            accumulator.initiator_fund, cost
+        );
+        return true;
+    }
+    let vouchered = accumulator
+        .voucher
+        .as_ref()
+        .map_or(0, |voucher| voucher.amount);
+    if cost > vouchered.saturating_add(CHANNEL_CREDIT) {
+        warn!(
+            "Vouchers cover {} of {} lamports owed, closing channel",
+            vouchered, cost
+        );
+        return true;
+    }
+    accumulator.bytes_charged = bytes_charged;
+    accumulator.total_data_amount += data_amount;
+    false
+}
+
+/// Keep the highest valid voucher the initiator has sent
+pub fn accept_voucher(
+    params: &NewConnParams,
+    contract_state: &BandwidthPrepayState,
+    accumulator: &mut Accumulator,
+    voucher: Voucher,
+) {
+    if voucher.contract_id != params.contract_pubkey
+        || !voucher.verify(&contract_state.initiator_id)
+    {
+        warn!("Ignoring invalid voucher for {:?}", params.contract_pubkey);
+        return;
+    }
+    if accumulator
+        .voucher
+        .as_ref()
+        .map_or(true, |latest| voucher.amount > latest.amount)
+    {
+        accumulator.voucher = Some(voucher);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batcher::start_spend_batcher;
+    use crate::connection_params::DEFAULT_CONNECT_TIMEOUT;
+    use bandwidth_prepay_api::bandwidth_prepay_address::contract_address;
+    use bandwidth_prepay_api::bandwidth_prepay_instruction;
+    use bandwidth_prepay_api::bandwidth_prepay_processor::process_instruction;
+    use bandwidth_prepay_api::bandwidth_prepay_state::{
+        ContractTerms, Tariff, CLOSED_CONTRACT_RESERVE,
+    };
+    use solana_runtime::bank::Bank;
+    use solana_runtime::bank_client::BankClient;
+    use solana_sdk::client::SyncClient;
+    use solana_sdk::genesis_block::create_genesis_block;
+    use solana_sdk::message::Message;
+    use solana_sdk::system_instruction;
+    use std::time::Duration;
+
+    // A contract holding 500 lamports at 1 lamport per KiB, returned with
+    // its initiator, gatekeeper and provider
+    fn open_contract() -> (Arc<BankClient>, Keypair, Arc<Keypair>, Pubkey, Pubkey) {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Arc::new(Keypair::new());
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let provider = Keypair::new().pubkey();
+        let mut instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                tariff: Tariff {
+                    lamports_per_kib: 1,
+                    minimum_charge: 0,
+                },
+                ..ContractTerms::default()
+            },
+            500,
+        );
+        instructions.push(system_instruction::transfer(
+            &alice_pubkey,
+            &gatekeeper.pubkey(),
+            1,
+        ));
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        (bank_client, alice_keypair, gatekeeper, contract, provider)
+    }
+
+    fn params(contract_pubkey: Pubkey) -> NewConnParams {
+        NewConnParams {
+            contract_pubkey,
+            destination: String::new(),
+            fee_interval: 0,
+            next_hop: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    #[test]
+    fn test_finish_connection_with_queued_charge() {
+        let (bank_client, alice_keypair, gatekeeper, contract, provider) = open_contract();
+        let alice_pubkey = alice_keypair.pubkey();
+        let alice_balance = bank_client.get_balance(&alice_pubkey).unwrap();
+
+        // 100 KiB wait for the batcher's interval when the connection finishes
+        // with 50 KiB more metered
+        let (queue, _) = start_spend_batcher(
+            bank_client.clone(),
+            gatekeeper.clone(),
+            Duration::from_secs(60),
+        );
+        queue
+            .send(PendingCharge {
+                contract_pubkey: contract,
+                provider_id: provider,
+                payee_ids: vec![],
+                data_amount: 100 * 1024,
+                receipt: None,
+            })
+            .unwrap();
+        let mut accumulator = Accumulator::default();
+        accumulator.total_data_amount = 150 * 1024;
+        accumulator.bytes_charged = 50 * 1024;
+        accumulator.bytes_settled = 100 * 1024;
+        let params = params(contract);
+        let tracker = queue.tracker();
+        assert!(finish_connection(
+            &params,
+            &gatekeeper,
+            &bank_client,
+            &mut accumulator,
+            &ReceiptStore::default(),
+            &tracker,
+        ));
+
+        // The queued charge landed before the close took the rest
+        assert_eq!(tracker.unconfirmed_bytes(&contract), 0);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 150);
+        assert_eq!(
+            bank_client.get_balance(&contract).unwrap(),
+            CLOSED_CONTRACT_RESERVE
+        );
+        assert_eq!(
+            bank_client.get_balance(&alice_pubkey).unwrap(),
+            alice_balance + 350 - CLOSED_CONTRACT_RESERVE
+        );
+    }
+
+    #[test]
+    fn test_finish_exhausted_connection() {
+        let (bank_client, alice_keypair, gatekeeper, contract, provider) = open_contract();
+        let alice_pubkey = alice_keypair.pubkey();
+        let alice_balance = bank_client.get_balance(&alice_pubkey).unwrap();
+
+        // Funds run out with 100 KiB queued and 100 KiB more metered
+        let (queue, _) = start_spend_batcher(
+            bank_client.clone(),
+            gatekeeper.clone(),
+            Duration::from_secs(60),
+        );
+        queue
+            .send(PendingCharge {
+                contract_pubkey: contract,
+                provider_id: provider,
+                payee_ids: vec![],
+                data_amount: 100 * 1024,
+                receipt: None,
+            })
+            .unwrap();
+        let mut accumulator = Accumulator::default();
+        accumulator.initiator_fund = 500;
+        accumulator.total_data_amount = 200 * 1024;
+        accumulator.bytes_charged = 100 * 1024;
+        accumulator.bytes_settled = 100 * 1024;
+        let params = params(contract);
+        let tracker = queue.tracker();
+        let (_, contract_state) =
+            check_contract(&params, &bank_client, &gatekeeper.pubkey()).unwrap();
+        assert!(settle_exhausted(
+            &params,
+            &gatekeeper,
+            &bank_client,
+            &contract_state,
+            &mut accumulator,
+            &tracker,
+        ));
+        assert_eq!(bank_client.get_balance(&contract).unwrap(), 0);
+
+        // The refunded contract counts as settled when the connection finishes
+        let receipts = ReceiptStore::default();
+        assert!(finish_connection(
+            &params,
+            &gatekeeper,
+            &bank_client,
+            &mut accumulator,
+            &receipts,
+            &tracker,
+        ));
+        assert_eq!(tracker.unconfirmed_bytes(&contract), 0);
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 200);
+        assert_eq!(
+            bank_client.get_balance(&alice_pubkey).unwrap(),
+            alice_balance + 300
+        );
     }
 }
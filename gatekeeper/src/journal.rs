@@ -0,0 +1,350 @@
+use crate::accumulator::Accumulator;
+use crate::batcher::SpendTracker;
+use crate::connection_params::{NewConnParams, DEFAULT_CONNECT_TIMEOUT};
+use crate::contract::{check_contract, contract_closed};
+use crate::gatekeeper::finish_connection;
+use crate::receipts::ReceiptStore;
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use solana_sdk::client::Client;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, KeypairUtil};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Billing state of one contract when it was journaled
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub contract_pubkey: Pubkey,
+    /// Bytes forwarded on the contract
+    pub bytes: u64,
+    /// Bytes metered but not yet queued for charging
+    pub charged: u64,
+    /// Cumulative bytes charged, or queued to be
+    pub settled: u64,
+    /// Nothing more is owed: the last charge landed and the contract's own
+    /// gatekeeper returned the balance to the initiator
+    pub refunded: bool,
+    /// Latest receipt, without which contracts requiring one cannot be charged
+    pub receipt: Option<UsageReceipt>,
+}
+
+impl JournalEntry {
+    pub fn new(contract_pubkey: &Pubkey, accumulator: &Accumulator, refunded: bool) -> Self {
+        Self {
+            contract_pubkey: *contract_pubkey,
+            bytes: accumulator.total_data_amount,
+            charged: accumulator.bytes_charged,
+            settled: accumulator.bytes_settled,
+            refunded,
+            receipt: accumulator.receipt.clone(),
+        }
+    }
+}
+
+/// Write-ahead log of every contract's billing, so that charges and refunds
+/// survive a crash; settlements are synced as they are recorded, metered
+/// progress whenever `sync` is next called
+#[derive(Clone)]
+pub struct Journal {
+    file: Arc<Mutex<File>>,
+    /// The same file, synced without holding up appends
+    sync_file: Arc<File>,
+    /// Entries appended since the last sync
+    dirty: Arc<AtomicBool>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, returning the latest entry of each contract
+    /// not yet refunded; the file is compacted down to those entries
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Vec<JournalEntry>)> {
+        let path = path.as_ref();
+        let mut latest = BTreeMap::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                // A crash can leave the last entry half written
+                match serde_json::from_str::<JournalEntry>(&line?) {
+                    Ok(entry) => {
+                        latest.insert(entry.contract_pubkey, entry);
+                    }
+                    Err(e) => warn!("Skipping unreadable journal entry: {}", e),
+                }
+            }
+        }
+        let outstanding: Vec<_> = latest
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| !entry.refunded)
+            .collect();
+
+        let compacted = path.with_extension("compacting");
+        let mut file = File::create(&compacted)?;
+        for entry in &outstanding {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&compacted, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        let journal = Self {
+            sync_file: Arc::new(file.try_clone()?),
+            file: Arc::new(Mutex::new(file)),
+            dirty: Arc::new(AtomicBool::new(false)),
+        };
+        Ok((journal, outstanding))
+    }
+
+    /// Append `entry`, returning once it is on disk
+    pub fn record(&self, entry: &JournalEntry) -> io::Result<()> {
+        self.append(entry)?;
+        self.sync()
+    }
+
+    /// Append `entry` without waiting for the disk; the next `sync` puts it there
+    pub fn append(&self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.lock().unwrap().write_all(line.as_bytes())?;
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Put every entry appended so far on disk
+    pub fn sync(&self) -> io::Result<()> {
+        if self.dirty.swap(false, Ordering::SeqCst) {
+            if let Err(e) = self.sync_file.sync_data() {
+                self.dirty.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finish the charges and refunds of contracts left in the journal by an
+/// earlier run, returning those that still could not be settled
+pub fn replay_journal<T: Client>(
+    client: &Arc<T>,
+    gatekeeper: &Keypair,
+    journal: &Journal,
+    entries: Vec<JournalEntry>,
+    receipts: &ReceiptStore,
+) -> Vec<Pubkey> {
+    let mut unsettled = vec![];
+    for entry in entries {
+        info!("Replaying journal entry for {:?}", entry.contract_pubkey);
+        let (accumulator, settled) = replay_entry(client, gatekeeper, &entry, receipts);
+        if let Err(e) = journal.record(&JournalEntry::new(
+            &entry.contract_pubkey,
+            &accumulator,
+            settled,
+        )) {
+            error!("Could not journal {:?}: {}", entry.contract_pubkey, e);
+        }
+        if !settled {
+            unsettled.push(entry.contract_pubkey);
+        }
+    }
+    unsettled
+}
+
+fn replay_entry<T: Client>(
+    client: &Arc<T>,
+    gatekeeper: &Keypair,
+    entry: &JournalEntry,
+    receipts: &ReceiptStore,
+) -> (Accumulator, bool) {
+    let mut accumulator = Accumulator::default();
+    accumulator.total_data_amount = entry.bytes;
+    accumulator.bytes_charged = entry.charged;
+    accumulator.bytes_settled = entry.settled;
+    accumulator.receipt = entry.receipt.clone();
+    match contract_closed(client, &entry.contract_pubkey) {
+        // Nothing is left to charge or refund
+        Ok(true) => return (accumulator, true),
+        Ok(false) => (),
+        Err(e) => {
+            error!("Could not fetch {:?}: {:?}", entry.contract_pubkey, e);
+            return (accumulator, false);
+        }
+    }
+    let params = NewConnParams {
+        contract_pubkey: entry.contract_pubkey,
+        destination: String::new(),
+        fee_interval: 0,
+        next_hop: None,
+        connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+    };
+    let contract_state = match check_contract(&params, client, &gatekeeper.pubkey()) {
+        Ok((_, contract_state)) => contract_state,
+        Err(e) => {
+            error!("Could not check {:?}: {:?}", entry.contract_pubkey, e);
+            return (accumulator, false);
+        }
+    };
+    // Charges queued before the crash may never have reached the contract
+    let reported = match contract_state.hop_index(&gatekeeper.pubkey()) {
+        Some(index) => contract_state.hops[index].bytes_reported,
+        None => contract_state.bytes_reported,
+    };
+    accumulator.bytes_charged = (entry.settled + entry.charged).saturating_sub(reported);
+    accumulator.bytes_settled = reported;
+    if let Some(receipt) = &entry.receipt {
+        receipts.insert(&contract_state.initiator_id, receipt.clone());
+    }
+    // Nothing is queued with the batcher yet, so there are no charges to wait for
+    let charges = SpendTracker::default();
+    let settled = finish_connection(
+        &params,
+        gatekeeper,
+        client,
+        &mut accumulator,
+        receipts,
+        &charges,
+    );
+    (accumulator, settled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bandwidth_prepay_api::bandwidth_prepay_address::contract_address;
+    use bandwidth_prepay_api::bandwidth_prepay_instruction;
+    use bandwidth_prepay_api::bandwidth_prepay_processor::process_instruction;
+    use bandwidth_prepay_api::bandwidth_prepay_state::{
+        ContractTerms, Tariff, CLOSED_CONTRACT_RESERVE,
+    };
+    use solana_runtime::bank::Bank;
+    use solana_runtime::bank_client::BankClient;
+    use solana_sdk::client::SyncClient;
+    use solana_sdk::genesis_block::create_genesis_block;
+    use solana_sdk::message::Message;
+    use solana_sdk::system_instruction;
+    use std::env;
+    use std::path::PathBuf;
+
+    fn journal_path() -> PathBuf {
+        env::temp_dir().join(format!("gatekeeper-journal-{}", Pubkey::new_rand()))
+    }
+
+    fn entry(contract_pubkey: Pubkey, settled: u64, refunded: bool) -> JournalEntry {
+        JournalEntry {
+            contract_pubkey,
+            bytes: settled + 1024,
+            charged: 1024,
+            settled,
+            refunded,
+            receipt: None,
+        }
+    }
+
+    #[test]
+    fn test_journal_open() {
+        let path = journal_path();
+        let (journal, outstanding) = Journal::open(&path).unwrap();
+        assert!(outstanding.is_empty());
+
+        let refunded = Pubkey::new_rand();
+        let open = Pubkey::new_rand();
+        journal.record(&entry(refunded, 0, false)).unwrap();
+        journal.append(&entry(open, 0, false)).unwrap();
+        journal.append(&entry(open, 2048, false)).unwrap();
+        journal.sync().unwrap();
+        journal.record(&entry(refunded, 1024, true)).unwrap();
+        drop(journal);
+        // A write cut short by a crash
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"contract_pubkey\":")
+            .unwrap();
+
+        let (_, outstanding) = Journal::open(&path).unwrap();
+        assert_eq!(outstanding, vec![entry(open, 2048, false)]);
+
+        // Reopening reads back the compacted journal
+        let (_, outstanding) = Journal::open(&path).unwrap();
+        assert_eq!(outstanding, vec![entry(open, 2048, false)]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_journal() {
+        let (genesis_block, alice_keypair) = create_genesis_block(10_000);
+        let mut bank = Bank::new(&genesis_block);
+        bank.add_instruction_processor(bandwidth_prepay_api::id(), process_instruction);
+        let bank_client = Arc::new(BankClient::new(bank));
+
+        let alice_pubkey = alice_keypair.pubkey();
+        let gatekeeper = Keypair::new();
+        let contract = contract_address(&alice_pubkey, &gatekeeper.pubkey(), 0);
+        let provider = Keypair::new().pubkey();
+        let mut instructions = bandwidth_prepay_instruction::initialize(
+            &alice_pubkey,
+            &contract,
+            &gatekeeper.pubkey(),
+            &provider,
+            ContractTerms {
+                tariff: Tariff {
+                    lamports_per_kib: 1,
+                    minimum_charge: 0,
+                },
+                ..ContractTerms::default()
+            },
+            500,
+        );
+        instructions.push(system_instruction::transfer(
+            &alice_pubkey,
+            &gatekeeper.pubkey(),
+            1,
+        ));
+        let message = Message::new(instructions);
+        bank_client
+            .send_message(&[&alice_keypair], message)
+            .unwrap();
+        let alice_balance = bank_client.get_balance(&alice_pubkey).unwrap();
+
+        // 100 KiB were queued for charging but never sent, and 50 KiB more metered
+        let path = journal_path();
+        let (journal, _) = Journal::open(&path).unwrap();
+        let missing = Pubkey::new_rand();
+        journal
+            .record(&JournalEntry {
+                contract_pubkey: contract,
+                bytes: 150 * 1024,
+                charged: 50 * 1024,
+                settled: 100 * 1024,
+                refunded: false,
+                receipt: None,
+            })
+            .unwrap();
+        journal.record(&entry(missing, 0, false)).unwrap();
+        drop(journal);
+
+        let (journal, outstanding) = Journal::open(&path).unwrap();
+        assert_eq!(outstanding.len(), 2);
+        let receipts = ReceiptStore::default();
+        let unsettled = replay_journal(&bank_client, &gatekeeper, &journal, outstanding, &receipts);
+        assert!(unsettled.is_empty());
+        assert_eq!(bank_client.get_balance(&provider).unwrap(), 150);
+        assert_eq!(
+            bank_client.get_balance(&contract).unwrap(),
+            CLOSED_CONTRACT_RESERVE
+        );
+        assert_eq!(
+            bank_client.get_balance(&alice_pubkey).unwrap(),
+            alice_balance + 350 - CLOSED_CONTRACT_RESERVE
+        );
+        drop(journal);
+
+        let (_, outstanding) = Journal::open(&path).unwrap();
+        assert!(outstanding.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,10 @@
 pub mod accumulator;
-mod business_logic;
+pub mod batcher;
+pub mod connection_manager;
 pub mod connection_params;
 pub mod contract;
 pub mod gatekeeper;
+pub mod journal;
+pub mod next_hop;
+pub mod pricing;
+pub mod receipts;
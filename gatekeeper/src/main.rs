@@ -1,7 +1,15 @@
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use bandwidth_prepay_api::bandwidth_prepay_state::BandwidthPrepayState;
 use clap::{App, Arg};
-use gatekeeper::connection_params::NewConnParams;
+use futures::future::{self, Future};
+use gatekeeper::batcher::start_spend_batcher;
+use gatekeeper::connection_manager::start_connection_manager;
+use gatekeeper::connection_params::{NewConnParams, DEFAULT_CONNECT_TIMEOUT};
 use gatekeeper::contract::*;
-use gatekeeper::gatekeeper::forwarder;
+use gatekeeper::journal::{replay_journal, Journal};
+use gatekeeper::next_hop::request_next_hop;
+use gatekeeper::pricing::{accepts_tariff, PriceMeter, PricingConfig, PricingPolicy};
+use gatekeeper::receipts::ReceiptStore;
 use jsonrpc_core::types::error::{Error, ErrorCode};
 use jsonrpc_core::{IoHandler, Params};
 use jsonrpc_tcp_server::ServerBuilder;
@@ -11,12 +19,12 @@ use solana_client::rpc_client::RpcClient;
 use solana_client::rpc_request::RpcRequest;
 use solana_client::thin_client::create_client;
 use solana_drone::drone::request_airdrop_transaction;
-use solana_sdk::client::{AsyncClient, SyncClient};
+use solana_sdk::client::{AsyncClient, Client, SyncClient};
 use solana_sdk::signature::{read_keypair, KeypairUtil};
 use std::net::SocketAddr;
 use std::sync::mpsc::channel;
 use std::sync::Arc;
-use std::thread;
+use std::time::Duration;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
@@ -54,6 +62,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .takes_value(true)
                 .help("How often to charge contract"),
         )
+        .arg(
+            Arg::with_name("connect_timeout")
+                .long("connect-timeout")
+                .value_name("SECS")
+                .takes_value(true)
+                .help("How long to wait for a destination to accept a connection"),
+        )
+        .arg(
+            Arg::with_name("journal")
+                .short("j")
+                .long("journal")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Where to journal billing until settled. Defaults to gatekeeper.journal"),
+        )
+        .arg(
+            Arg::with_name("pricing")
+                .long("pricing")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("JSON pricing config for admitting contracts. Defaults to 1 lamport per KiB"),
+        )
         .get_matches();
     let gatekeeper_keypair_path = matches.value_of("keypair").unwrap().to_string();
     let gatekeeper = read_keypair(&gatekeeper_keypair_path).unwrap();
@@ -84,6 +114,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         1
     } * 1000;
 
+    let connect_timeout = matches
+        .value_of("connect_timeout")
+        .map(|secs| Duration::from_secs(secs.parse().unwrap()))
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+
+    let pricing_config = match matches.value_of("pricing") {
+        Some(path) => PricingConfig::load(path)?,
+        None => PricingConfig::default(),
+    };
+    info!("Pricing: {:?}", pricing_config);
+    let pricing: Arc<dyn PricingPolicy> = Arc::from(pricing_config.build());
+
     // TODO: handle initial account funding properly, probably separate from this script
     let balance = client.get_balance(&gatekeeper.pubkey()).unwrap_or(0);
     if balance == 0 {
@@ -103,88 +145,273 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let client = Arc::new(client);
+    let receipts = ReceiptStore::default();
+    let gatekeeper = Arc::new(gatekeeper);
+
+    // Settle whatever an earlier run left behind before taking new connections
+    let (journal, outstanding) =
+        Journal::open(matches.value_of("journal").unwrap_or("gatekeeper.journal"))?;
+    if !outstanding.is_empty() {
+        info!("Replaying {} contracts from the journal", outstanding.len());
+        for contract_pubkey in
+            replay_journal(&client, &gatekeeper, &journal, outstanding, &receipts)
+        {
+            error!("Could not settle journaled contract {:?}", contract_pubkey);
+        }
+    }
+
+    // Charges from every connection are settled together
+    let (spend_queue, spend_batcher) = start_spend_batcher(
+        client.clone(),
+        gatekeeper.clone(),
+        Duration::from_millis(u64::from(fee_interval)),
+    );
+    let spend_tracker = spend_queue.tracker();
+    let connections = Arc::new(start_connection_manager(
+        client.clone(),
+        gatekeeper,
+        ws_addr,
+        receipts.clone(),
+        spend_queue,
+        journal,
+    )?);
+
+    let (terminate_sender, terminate_receiver) = channel();
+    ctrlc::set_handler(move || {
+        let _ = terminate_sender.send(());
+    })?;
 
     let mut io = IoHandler::default();
-    io.add_method("newConnection", move |params: Params| {
+    let receipt_client = client.clone();
+    let receipt_store = receipts.clone();
+    io.add_method("submitReceipt", move |params: Params| {
         let flat_params: serde_json::map::Map<String, Value> = params.parse()?;
-        let parsed_params = NewConnParams {
-            contract_pubkey: verify_pubkey(
-                flat_params["contract_pubkey"].as_str().unwrap().to_string(),
+        let receipt = UsageReceipt {
+            contract_id: verify_pubkey(
+                flat_params["contract_pubkey"]
+                    .as_str()
+                    .ok_or_else(Error::invalid_request)?
+                    .to_string(),
+            )?,
+            cumulative_bytes: flat_params["cumulative_bytes"]
+                .as_u64()
+                .ok_or_else(Error::invalid_request)?,
+            nonce: flat_params["nonce"]
+                .as_u64()
+                .ok_or_else(Error::invalid_request)?,
+            signature: verify_signature(
+                flat_params["signature"]
+                    .as_str()
+                    .ok_or_else(Error::invalid_request)?
+                    .to_string(),
             )?,
-            destination: flat_params["destination"].as_str().unwrap().to_string(),
-            fee_interval,
         };
-        let initiator_pubkey = verify_pubkey(
-            flat_params["initiator_pubkey"]
-                .as_str()
-                .unwrap()
-                .to_string(),
-        )?;
-        info!(
-            "Received forward request to '{}', contract: {:?}",
-            &parsed_params.destination, &parsed_params.contract_pubkey
-        );
 
-        let gatekeeper = read_keypair(&gatekeeper_keypair_path).unwrap();
-
-        let (balance, contract_state) =
-            check_contract(&parsed_params, &client, &gatekeeper.pubkey()).map_err(|e| {
+        let data = receipt_client
+            .get_account_data(&receipt.contract_id)
+            .map_err(|e| {
                 error!(
-                    "could not check contract: {:?} {:?}",
-                    parsed_params.contract_pubkey, e
+                    "could not fetch contract: {:?} {:?}",
+                    receipt.contract_id, e
                 );
                 Error::invalid_request()
-            })?;
-        if balance == 0 {
-            error!("prepay balance is 0: {:?}", parsed_params.contract_pubkey);
-            return Err(Error::invalid_request());
-        }
-        if contract_state.initiator_id != initiator_pubkey {
+            })?
+            .ok_or_else(Error::invalid_request)?;
+        let contract_state =
+            BandwidthPrepayState::deserialize(&data).map_err(|_| Error::invalid_request())?;
+        if !receipt_store.insert(&contract_state.initiator_id, receipt.clone()) {
             error!(
-                "initator pubkey {} does not match contract state",
-                initiator_pubkey
+                "rejected receipt {} for contract {:?}",
+                receipt.nonce, receipt.contract_id
             );
             return Err(Error::invalid_request());
         }
+        info!(
+            "Receipt {} acknowledges {} bytes on contract {:?}",
+            receipt.nonce, receipt.cumulative_bytes, receipt.contract_id
+        );
+        Ok(json!({ "nonce": format!("{}", receipt.nonce) }))
+    });
+
+    io.add_method("getSpendCounts", move |_params: Params| {
+        let counts = spend_tracker.counts();
+        Ok(json!({
+            "pending": counts.pending,
+            "confirmed": counts.confirmed,
+            "failed": counts.failed,
+        }))
+    });
 
+    let new_connections = connections.clone();
+    io.add_method("newConnection", move |params: Params| {
+        let accepted = accept_connection(
+            params,
+            &client,
+            &gatekeeper_keypair_path,
+            fee_interval,
+            connect_timeout,
+            &pricing,
+        );
+        let (parsed_params, contract_state, balance, price) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => return Box::new(future::err(e)) as BoxFuture<Value>,
+        };
+        let pricing_config = price.config();
         info!(
             "Starting new connection to '{}'",
             &parsed_params.destination
         );
 
-        let client = client.clone();
-        let (send, recv) = channel();
-        thread::spawn(move || {
-            forwarder(
-                &parsed_params,
-                &gatekeeper,
-                &client,
-                &contract_state,
-                balance,
-                ws_addr,
-                send,
-            )
-        });
-        match recv.recv() {
-            Ok(new_port) => {
-                let ret = json!({ "port": format!("{}", new_port) });
-                info!(
-                    "Started new gatekeeper channel at {}, returning {:?}",
-                    new_port, ret
-                );
-                Ok(ret)
-            }
-            Err(_e) => {
-                error!("Could not get port from forwarder thread");
-                Err(Error::new(ErrorCode::ServerError(2)))
-            }
-        }
+        Box::new(
+            new_connections
+                .open(parsed_params, contract_state, balance, price)
+                .then(move |result| match result {
+                    Ok(new_port) => {
+                        let ret = json!({
+                            "port": format!("{}", new_port),
+                            "pricing": pricing_config,
+                        });
+                        info!(
+                            "Started new gatekeeper channel at {}, returning {:?}",
+                            new_port, ret
+                        );
+                        Ok(ret)
+                    }
+                    Err(e) => {
+                        error!("Could not open connection: {}", e);
+                        Err(Error::new(ErrorCode::ServerError(2)))
+                    }
+                }),
+        )
     });
 
     let gatekeeper = ServerBuilder::new(io).start(&format!("0.0.0.0:{}", port).parse()?)?;
     info!("Gatekeeper listening on port {}", port);
 
-    gatekeeper.wait();
+    terminate_receiver.recv()?;
+    info!("Shutting down, settling open connections");
+    gatekeeper.close();
+    connections.stop();
+    // The batcher confirms its last charges once every connection has dropped
+    // its queue, and only then are the contracts closed
+    if spend_batcher.join().is_err() {
+        error!("Spend batcher stopped before confirming every charge");
+    }
+    let mut unsettled = connections.shutdown();
+    unsettled.sort();
+    unsettled.dedup();
+    if unsettled.is_empty() {
+        info!("All connections settled");
+        return Ok(());
+    }
+    for contract_pubkey in &unsettled {
+        error!("Could not settle contract {:?}", contract_pubkey);
+    }
+    Err(format!("{} contracts left unsettled", unsettled.len()).into())
+}
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// Check a newConnection request against its contract and the gatekeeper's
+/// pricing, returning the connection to open with the contract state, balance
+/// and price it starts from
+fn accept_connection<T: Client>(
+    params: Params,
+    client: &Arc<T>,
+    gatekeeper_keypair_path: &str,
+    fee_interval: u16,
+    connect_timeout: Duration,
+    pricing: &Arc<dyn PricingPolicy>,
+) -> Result<(NewConnParams, BandwidthPrepayState, u64, PriceMeter), Error> {
+    let flat_params: serde_json::map::Map<String, Value> = params.parse()?;
+    let mut parsed_params = NewConnParams {
+        contract_pubkey: verify_pubkey(
+            flat_params["contract_pubkey"].as_str().unwrap().to_string(),
+        )?,
+        destination: flat_params["destination"].as_str().unwrap().to_string(),
+        fee_interval,
+        next_hop: flat_params
+            .get("next_hop")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        connect_timeout,
+    };
+    let initiator_pubkey = verify_pubkey(
+        flat_params["initiator_pubkey"]
+            .as_str()
+            .unwrap()
+            .to_string(),
+    )?;
+    info!(
+        "Received forward request to '{}', contract: {:?}",
+        &parsed_params.destination, &parsed_params.contract_pubkey
+    );
+
+    let gatekeeper = read_keypair(gatekeeper_keypair_path).unwrap();
+    let session_nonce = flat_params.get("session_nonce").and_then(Value::as_u64);
+
+    let (balance, contract_state) = check_contract(&parsed_params, client, &gatekeeper.pubkey())
+        .map_err(|e| {
+            error!(
+                "could not check contract: {:?} {:?}",
+                parsed_params.contract_pubkey, e
+            );
+            Error::invalid_request()
+        })?;
+    if balance == 0 {
+        error!("prepay balance is 0: {:?}", parsed_params.contract_pubkey);
+        return Err(Error::invalid_request());
+    }
+    if !check_initiator(
+        &parsed_params,
+        &contract_state,
+        &initiator_pubkey,
+        session_nonce,
+    ) {
+        error!(
+            "contract {:?} does not belong to initiator {}",
+            parsed_params.contract_pubkey, initiator_pubkey
+        );
+        return Err(Error::invalid_request());
+    }
+    if !accepts_tariff(
+        pricing.as_ref(),
+        &contract_state.tariff,
+        &parsed_params.destination,
+    ) {
+        error!(
+            "contract tariff {:?} is below the gatekeeper's rate for {}",
+            contract_state.tariff, parsed_params.destination
+        );
+        return Err(Error::invalid_request());
+    }
+    migrate_contract(&parsed_params, client, &gatekeeper).map_err(|e| {
+        error!(
+            "could not migrate contract: {:?} {:?}",
+            parsed_params.contract_pubkey, e
+        );
+        Error::invalid_request()
+    })?;
+
+    if parsed_params.next_hop.is_some() && contract_state.hops.is_empty() {
+        error!(
+            "contract {:?} has no route for next hop",
+            parsed_params.contract_pubkey
+        );
+        return Err(Error::invalid_request());
+    }
+    // Priced by where the initiator asked to go, not by the hop it goes through
+    let price = PriceMeter::new(pricing.clone(), &parsed_params.destination);
+    // Forward to the next gatekeeper on the route, which connects on to the destination
+    if let Some(next_hop) = &parsed_params.next_hop {
+        parsed_params.destination =
+            request_next_hop(next_hop, &parsed_params, &initiator_pubkey, session_nonce).map_err(
+                |e| {
+                    error!("could not connect through next hop {}: {:?}", next_hop, e);
+                    Error::invalid_request()
+                },
+            )?;
+    }
 
-    Ok(())
+    Ok((parsed_params, contract_state, balance, price))
 }
@@ -0,0 +1,51 @@
+use crate::connection_params::NewConnParams;
+use log::*;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+
+/// Ask the gatekeeper at `next_hop` to forward this connection on to
+/// `params.destination`, returning the address this gatekeeper should forward to
+pub fn request_next_hop(
+    next_hop: &str,
+    params: &NewConnParams,
+    initiator_id: &Pubkey,
+    session_nonce: Option<u64>,
+) -> io::Result<String> {
+    let mut stream = TcpStream::connect(next_hop)?;
+    let mut request_params = json!({
+        "destination": params.destination,
+        "contract_pubkey": format!("{}", params.contract_pubkey),
+        "initiator_pubkey": format!("{}", initiator_id),
+    });
+    if let Some(session_nonce) = session_nonce {
+        request_params["session_nonce"] = json!(session_nonce);
+    }
+    let request = json!({
+        "jsonrpc": "2.0",
+        "method": "newConnection",
+        "params": request_params,
+        "id": 1,
+    });
+    info!("Requesting next hop {}: {}", next_hop, request);
+    stream.write_all(format!("{}\n", request).as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response)?;
+    let mut addr = stream.peer_addr()?;
+    stream.shutdown(Shutdown::Both)?;
+    let response: Value = serde_json::from_str(&response)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let port = response["result"]["port"].as_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Next hop {} refused connection: {}", next_hop, response),
+        )
+    })?;
+    addr.set_port(
+        port.parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+    );
+    Ok(addr.to_string())
+}
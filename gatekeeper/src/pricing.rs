@@ -0,0 +1,591 @@
+use bandwidth_prepay_api::bandwidth_prepay_state::Tariff;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Prices are in 1/PRICE_SCALE lamports, fine enough to price a single byte
+/// or millisecond exactly
+pub const PRICE_SCALE: u128 = 1024 * 1000;
+
+/// Rate charged when the gatekeeper is given no pricing config
+pub const DEFAULT_LAMPORTS_PER_KIB: u64 = 1;
+
+/// Traffic on one connection since it was last priced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage<'a> {
+    pub destination: &'a str,
+    /// Bytes already priced on the connection
+    pub bytes_before: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Hour of the day, UTC
+    pub hour: u8,
+}
+
+/// How the gatekeeper prices the traffic it forwards, which decides the
+/// contracts it accepts and when their connections close; what a contract is
+/// charged is always its own tariff
+pub trait PricingPolicy: Send + Sync {
+    /// Price of `usage`, in 1/PRICE_SCALE lamports
+    fn price(&self, usage: &Usage) -> u128;
+
+    /// Config the policy is built from, as reported to clients
+    fn config(&self) -> PricingConfig;
+}
+
+/// Lamports for every KiB forwarded
+pub struct PerBytePricing {
+    pub lamports_per_kib: u64,
+}
+
+impl PricingPolicy for PerBytePricing {
+    fn price(&self, usage: &Usage) -> u128 {
+        per_kib(usage.bytes, self.lamports_per_kib)
+    }
+
+    fn config(&self) -> PricingConfig {
+        PricingConfig::PerByte {
+            lamports_per_kib: self.lamports_per_kib,
+        }
+    }
+}
+
+/// Lamports for every second a connection stays open, whatever it carries;
+/// time is priced as traffic is read, so a connection left idle is priced for
+/// it on its next read
+pub struct PerSecondPricing {
+    pub lamports_per_second: u64,
+}
+
+impl PricingPolicy for PerSecondPricing {
+    fn price(&self, usage: &Usage) -> u128 {
+        usage.elapsed.as_millis() * u128::from(self.lamports_per_second) * 1024
+    }
+
+    fn config(&self) -> PricingConfig {
+        PricingConfig::PerSecond {
+            lamports_per_second: self.lamports_per_second,
+        }
+    }
+}
+
+/// Rate for the bytes of a connection from `from_bytes` on
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tier {
+    pub from_bytes: u64,
+    pub lamports_per_kib: u64,
+}
+
+/// Volume discounts: each tier's rate applies to the bytes of a connection
+/// falling within it
+pub struct TieredPricing {
+    /// Sorted by `from_bytes`; bytes before the first tier are free
+    tiers: Vec<Tier>,
+}
+
+impl TieredPricing {
+    pub fn new(mut tiers: Vec<Tier>) -> Self {
+        tiers.sort_by_key(|tier| tier.from_bytes);
+        Self { tiers }
+    }
+}
+
+impl PricingPolicy for TieredPricing {
+    fn price(&self, usage: &Usage) -> u128 {
+        let start = usage.bytes_before;
+        let end = usage.bytes_before.saturating_add(usage.bytes);
+        let ends = self
+            .tiers
+            .iter()
+            .skip(1)
+            .map(|tier| tier.from_bytes)
+            .chain(Some(u64::max_value()));
+        self.tiers
+            .iter()
+            .zip(ends)
+            .map(|(tier, tier_end)| {
+                let from = start.max(tier.from_bytes);
+                let to = end.min(tier_end);
+                per_kib(to.saturating_sub(from), tier.lamports_per_kib)
+            })
+            .sum()
+    }
+
+    fn config(&self) -> PricingConfig {
+        PricingConfig::Tiered {
+            tiers: self.tiers.clone(),
+        }
+    }
+}
+
+/// One policy during peak hours and another the rest of the day
+pub struct PeakPricing {
+    /// First hour of the peak, UTC
+    pub start_hour: u8,
+    /// Hour the peak ends, UTC; before `start_hour` if the peak spans midnight
+    pub end_hour: u8,
+    pub peak: Box<dyn PricingPolicy>,
+    pub off_peak: Box<dyn PricingPolicy>,
+}
+
+impl PeakPricing {
+    fn is_peak(&self, hour: u8) -> bool {
+        if self.start_hour <= self.end_hour {
+            hour >= self.start_hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+impl PricingPolicy for PeakPricing {
+    fn price(&self, usage: &Usage) -> u128 {
+        if self.is_peak(usage.hour) {
+            self.peak.price(usage)
+        } else {
+            self.off_peak.price(usage)
+        }
+    }
+
+    fn config(&self) -> PricingConfig {
+        PricingConfig::Peak {
+            start_hour: self.start_hour,
+            end_hour: self.end_hour,
+            peak: Box::new(self.peak.config()),
+            off_peak: Box::new(self.off_peak.config()),
+        }
+    }
+}
+
+/// A policy per destination, matched on the full address or on its host
+pub struct DestinationPricing {
+    pub destinations: BTreeMap<String, Box<dyn PricingPolicy>>,
+    pub default: Box<dyn PricingPolicy>,
+}
+
+impl DestinationPricing {
+    fn policy(&self, destination: &str) -> &dyn PricingPolicy {
+        let host = destination.rsplitn(2, ':').last().unwrap_or(destination);
+        self.destinations
+            .get(destination)
+            .or_else(|| self.destinations.get(host))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+}
+
+impl PricingPolicy for DestinationPricing {
+    fn price(&self, usage: &Usage) -> u128 {
+        self.policy(usage.destination).price(usage)
+    }
+
+    fn config(&self) -> PricingConfig {
+        PricingConfig::PerDestination {
+            destinations: self
+                .destinations
+                .iter()
+                .map(|(destination, policy)| (destination.clone(), policy.config()))
+                .collect(),
+            default: Box::new(self.default.config()),
+        }
+    }
+}
+
+/// Pricing section of the gatekeeper config
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum PricingConfig {
+    PerByte {
+        lamports_per_kib: u64,
+    },
+    PerSecond {
+        lamports_per_second: u64,
+    },
+    Tiered {
+        tiers: Vec<Tier>,
+    },
+    Peak {
+        start_hour: u8,
+        end_hour: u8,
+        peak: Box<PricingConfig>,
+        off_peak: Box<PricingConfig>,
+    },
+    PerDestination {
+        destinations: BTreeMap<String, PricingConfig>,
+        default: Box<PricingConfig>,
+    },
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        PricingConfig::PerByte {
+            lamports_per_kib: DEFAULT_LAMPORTS_PER_KIB,
+        }
+    }
+}
+
+impl PricingConfig {
+    /// Read the config from the JSON file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn error::Error>> {
+        let config: Self = serde_json::from_reader(File::open(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            PricingConfig::Peak {
+                start_hour,
+                end_hour,
+                peak,
+                off_peak,
+            } => {
+                if *start_hour > 23 || *end_hour > 23 {
+                    return Err(format!(
+                        "peak hours {}-{} are not hours of the day",
+                        start_hour, end_hour
+                    ));
+                }
+                peak.validate()?;
+                off_peak.validate()
+            }
+            PricingConfig::PerDestination {
+                destinations,
+                default,
+            } => {
+                for policy in destinations.values() {
+                    policy.validate()?;
+                }
+                default.validate()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn PricingPolicy> {
+        match self {
+            PricingConfig::PerByte { lamports_per_kib } => Box::new(PerBytePricing {
+                lamports_per_kib: *lamports_per_kib,
+            }),
+            PricingConfig::PerSecond {
+                lamports_per_second,
+            } => Box::new(PerSecondPricing {
+                lamports_per_second: *lamports_per_second,
+            }),
+            PricingConfig::Tiered { tiers } => Box::new(TieredPricing::new(tiers.clone())),
+            PricingConfig::Peak {
+                start_hour,
+                end_hour,
+                peak,
+                off_peak,
+            } => Box::new(PeakPricing {
+                start_hour: *start_hour,
+                end_hour: *end_hour,
+                peak: peak.build(),
+                off_peak: off_peak.build(),
+            }),
+            PricingConfig::PerDestination {
+                destinations,
+                default,
+            } => Box::new(DestinationPricing {
+                destinations: destinations
+                    .iter()
+                    .map(|(destination, policy)| (destination.clone(), policy.build()))
+                    .collect(),
+                default: default.build(),
+            }),
+        }
+    }
+}
+
+/// Whether `tariff` pays at least what `policy` asks for the first KiB
+/// forwarded to `destination` now
+pub fn accepts_tariff(policy: &dyn PricingPolicy, tariff: &Tariff, destination: &str) -> bool {
+    covers_first_kib(policy, tariff, destination, utc_hour())
+}
+
+fn covers_first_kib(
+    policy: &dyn PricingPolicy,
+    tariff: &Tariff,
+    destination: &str,
+    hour: u8,
+) -> bool {
+    let quote = policy.price(&Usage {
+        destination,
+        bytes_before: 0,
+        bytes: 1024,
+        elapsed: Duration::default(),
+        hour,
+    });
+    tariff
+        .charge(1024)
+        .map_or(false, |charge| u128::from(charge) * PRICE_SCALE >= quote)
+}
+
+/// Price of reads not yet committed to a connection's `PriceMeter`
+pub struct Quote {
+    bytes: u64,
+    owed: u128,
+    at: Instant,
+}
+
+impl Quote {
+    /// Whole lamports owed on the connection once the read is committed
+    pub fn lamports(&self) -> u64 {
+        (self.owed / PRICE_SCALE) as u64
+    }
+}
+
+/// Running price of one connection, carrying fractions of a lamport from one
+/// read to the next
+pub struct PriceMeter {
+    policy: Arc<dyn PricingPolicy>,
+    destination: String,
+    bytes: u64,
+    owed: u128,
+    last_priced: Instant,
+}
+
+impl PriceMeter {
+    pub fn new(policy: Arc<dyn PricingPolicy>, destination: &str) -> Self {
+        Self {
+            policy,
+            destination: destination.to_string(),
+            bytes: 0,
+            owed: 0,
+            last_priced: Instant::now(),
+        }
+    }
+
+    /// Price reading `bytes` more now, without counting them until the quote
+    /// is committed
+    pub fn quote(&self, bytes: u64) -> Quote {
+        let now = Instant::now();
+        self.quote_usage(bytes, now - self.last_priced, utc_hour(), now)
+    }
+
+    /// Count a quoted read, once it is forwarded
+    pub fn commit(&mut self, quote: Quote) {
+        self.bytes += quote.bytes;
+        self.owed = quote.owed;
+        self.last_priced = quote.at;
+    }
+
+    fn quote_usage(&self, bytes: u64, elapsed: Duration, hour: u8, at: Instant) -> Quote {
+        let price = self.policy.price(&Usage {
+            destination: &self.destination,
+            bytes_before: self.bytes,
+            bytes,
+            elapsed,
+            hour,
+        });
+        Quote {
+            bytes,
+            owed: self.owed + price,
+            at,
+        }
+    }
+
+    pub fn lamports(&self) -> u64 {
+        (self.owed / PRICE_SCALE) as u64
+    }
+
+    pub fn config(&self) -> PricingConfig {
+        self.policy.config()
+    }
+}
+
+fn per_kib(bytes: u64, lamports_per_kib: u64) -> u128 {
+    u128::from(bytes) * u128::from(lamports_per_kib) * 1000
+}
+
+fn utc_hour() -> u8 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    (secs / 3600 % 24) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(bytes_before: u64, bytes: u64) -> Usage<'static> {
+        Usage {
+            destination: "127.0.0.1:8080",
+            bytes_before,
+            bytes,
+            elapsed: Duration::default(),
+            hour: 0,
+        }
+    }
+
+    fn lamports(price: u128) -> u128 {
+        price / PRICE_SCALE
+    }
+
+    #[test]
+    fn test_price_meter_carries_fractions() {
+        let policy = Arc::new(PerBytePricing {
+            lamports_per_kib: 1,
+        });
+        let mut meter = PriceMeter::new(policy, "127.0.0.1:8080");
+        let add = |meter: &mut PriceMeter, bytes| {
+            let quote = meter.quote_usage(bytes, Duration::default(), 0, Instant::now());
+            meter.commit(quote);
+        };
+        // No single read is worth a lamport, but together they add up
+        for _ in 0..10 {
+            add(&mut meter, 100);
+        }
+        assert_eq!(meter.lamports(), 0);
+        add(&mut meter, 100);
+        assert_eq!(meter.lamports(), 1);
+        for _ in 0..9 {
+            add(&mut meter, 100);
+        }
+        assert_eq!(meter.lamports(), 1);
+
+        // A read that is quoted but never forwarded costs nothing
+        let quote = meter.quote_usage(4096, Duration::default(), 0, Instant::now());
+        assert_eq!(quote.lamports(), 5);
+        assert_eq!(meter.lamports(), 1);
+        add(&mut meter, 48);
+        assert_eq!(meter.lamports(), 2);
+    }
+
+    #[test]
+    fn test_per_second_pricing() {
+        let policy = PerSecondPricing {
+            lamports_per_second: 4,
+        };
+        let mut usage = usage(0, 1024);
+        usage.elapsed = Duration::from_millis(2500);
+        assert_eq!(lamports(policy.price(&usage)), 10);
+        usage.elapsed = Duration::from_millis(100);
+        assert_eq!(policy.price(&usage), PRICE_SCALE * 4 / 10);
+    }
+
+    #[test]
+    fn test_tiered_pricing() {
+        let policy = TieredPricing::new(vec![
+            Tier {
+                from_bytes: 10 * 1024,
+                lamports_per_kib: 1,
+            },
+            Tier {
+                from_bytes: 0,
+                lamports_per_kib: 4,
+            },
+        ]);
+        assert_eq!(lamports(policy.price(&usage(0, 1024))), 4);
+        assert_eq!(lamports(policy.price(&usage(20 * 1024, 1024))), 1);
+        // A read across the tier boundary pays each tier's rate for its share
+        assert_eq!(lamports(policy.price(&usage(8 * 1024, 4 * 1024))), 10);
+    }
+
+    #[test]
+    fn test_peak_pricing() {
+        let policy = PeakPricing {
+            start_hour: 22,
+            end_hour: 2,
+            peak: Box::new(PerBytePricing {
+                lamports_per_kib: 3,
+            }),
+            off_peak: Box::new(PerBytePricing {
+                lamports_per_kib: 1,
+            }),
+        };
+        let mut usage = usage(0, 1024);
+        for (hour, rate) in &[(21, 1), (22, 3), (23, 3), (0, 3), (1, 3), (2, 1), (12, 1)] {
+            usage.hour = *hour;
+            assert_eq!(lamports(policy.price(&usage)), *rate, "hour {}", hour);
+        }
+    }
+
+    #[test]
+    fn test_destination_pricing() {
+        let mut destinations: BTreeMap<String, Box<dyn PricingPolicy>> = BTreeMap::new();
+        destinations.insert(
+            "10.0.0.1".to_string(),
+            Box::new(PerBytePricing {
+                lamports_per_kib: 2,
+            }),
+        );
+        destinations.insert(
+            "10.0.0.1:443".to_string(),
+            Box::new(PerBytePricing {
+                lamports_per_kib: 5,
+            }),
+        );
+        let policy = DestinationPricing {
+            destinations,
+            default: Box::new(PerBytePricing {
+                lamports_per_kib: 1,
+            }),
+        };
+        let mut usage = usage(0, 1024);
+        for (destination, rate) in &[("10.0.0.1:443", 5), ("10.0.0.1:80", 2), ("10.0.0.2:80", 1)] {
+            usage.destination = *destination;
+            assert_eq!(lamports(policy.price(&usage)), *rate, "{}", destination);
+        }
+    }
+
+    #[test]
+    fn test_accepts_tariff() {
+        let tariff = Tariff {
+            lamports_per_kib: 2,
+            minimum_charge: 0,
+        };
+        let policy = |lamports_per_kib| PerBytePricing { lamports_per_kib };
+        assert!(covers_first_kib(&policy(1), &tariff, "10.0.0.1:80", 0));
+        assert!(covers_first_kib(&policy(2), &tariff, "10.0.0.1:80", 0));
+        assert!(!covers_first_kib(&policy(3), &tariff, "10.0.0.1:80", 0));
+
+        let peak = PeakPricing {
+            start_hour: 8,
+            end_hour: 18,
+            peak: Box::new(policy(3)),
+            off_peak: Box::new(policy(1)),
+        };
+        assert!(covers_first_kib(&peak, &tariff, "10.0.0.1:80", 6));
+        assert!(!covers_first_kib(&peak, &tariff, "10.0.0.1:80", 12));
+    }
+
+    #[test]
+    fn test_pricing_config() {
+        let json = r#"{
+            "policy": "per_destination",
+            "destinations": {
+                "10.0.0.1": {"policy": "tiered", "tiers": [{"from_bytes": 0, "lamports_per_kib": 2}]}
+            },
+            "default": {
+                "policy": "peak",
+                "start_hour": 8,
+                "end_hour": 18,
+                "peak": {"policy": "per_byte", "lamports_per_kib": 3},
+                "off_peak": {"policy": "per_second", "lamports_per_second": 1}
+            }
+        }"#;
+        let config: PricingConfig = serde_json::from_str(json).unwrap();
+        assert!(config.validate().is_ok());
+        // Built policies report back the config they came from
+        assert_eq!(config.build().config(), config);
+        let reported = serde_json::to_value(&config).unwrap();
+        assert_eq!(reported["policy"], "per_destination");
+        assert_eq!(reported["default"]["peak"]["lamports_per_kib"], 3);
+
+        let config = PricingConfig::Peak {
+            start_hour: 8,
+            end_hour: 24,
+            peak: Box::new(PricingConfig::default()),
+            off_peak: Box::new(PricingConfig::default()),
+        };
+        assert!(config.validate().is_err());
+    }
+}
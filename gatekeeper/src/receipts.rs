@@ -0,0 +1,66 @@
+use bandwidth_prepay_api::bandwidth_prepay_receipt::UsageReceipt;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Latest usage receipt submitted for each open contract
+#[derive(Clone, Default)]
+pub struct ReceiptStore {
+    receipts: Arc<RwLock<HashMap<Pubkey, UsageReceipt>>>,
+}
+
+impl ReceiptStore {
+    /// Keep `receipt` if it is signed by `initiator_id` and supersedes the one on file
+    pub fn insert(&self, initiator_id: &Pubkey, receipt: UsageReceipt) -> bool {
+        if !receipt.verify(initiator_id) {
+            return false;
+        }
+        let mut receipts = self.receipts.write().unwrap();
+        if let Some(latest) = receipts.get(&receipt.contract_id) {
+            if receipt.nonce <= latest.nonce || receipt.cumulative_bytes < latest.cumulative_bytes {
+                return false;
+            }
+        }
+        receipts.insert(receipt.contract_id, receipt);
+        true
+    }
+
+    pub fn get(&self, contract_id: &Pubkey) -> Option<UsageReceipt> {
+        self.receipts.read().unwrap().get(contract_id).cloned()
+    }
+
+    pub fn remove(&self, contract_id: &Pubkey) {
+        self.receipts.write().unwrap().remove(contract_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::signature::{Keypair, KeypairUtil};
+
+    #[test]
+    fn test_receipt_store() {
+        let initiator = Keypair::new();
+        let contract = Pubkey::new_rand();
+        let store = ReceiptStore::default();
+        assert_eq!(store.get(&contract), None);
+
+        let receipt = UsageReceipt::new(&initiator, &contract, 1024, 1);
+        assert!(!store.insert(&Pubkey::new_rand(), receipt.clone()));
+        assert!(store.insert(&initiator.pubkey(), receipt.clone()));
+        assert_eq!(store.get(&contract), Some(receipt.clone()));
+
+        // Replays and receipts that walk back usage are ignored
+        assert!(!store.insert(&initiator.pubkey(), receipt.clone()));
+        let lower = UsageReceipt::new(&initiator, &contract, 512, 2);
+        assert!(!store.insert(&initiator.pubkey(), lower));
+
+        let newer = UsageReceipt::new(&initiator, &contract, 2048, 2);
+        assert!(store.insert(&initiator.pubkey(), newer.clone()));
+        assert_eq!(store.get(&contract), Some(newer));
+
+        store.remove(&contract);
+        assert_eq!(store.get(&contract), None);
+    }
+}
@@ -17,24 +17,13 @@ pub fn start_pubsub<T>(
 where
     T: fmt::Display,
 {
-    let (ws_mpsc_sender, ws_mpsc_receiver) = channel();
-
-    let client = thread::spawn(move || {
-        info!("Connecting to {}", ws_addr);
-        connect(ws_addr, move |sender| Client {
-            ws_out: sender,
-            thread_out: ws_mpsc_sender.clone(),
-        })
-        .unwrap();
-    });
-
-    let ws_sender = if let Event::Connect(s) = ws_mpsc_receiver.recv()? {
-        s
-    } else {
-        return Err(PubSubError::ConnectionFailed)?;
-    };
-
-    info!("Connected to PubSub websocket");
+    let (
+        PubSubClient {
+            handle: client,
+            sender: ws_sender,
+        },
+        ws_mpsc_receiver,
+    ) = start_pubsub_client(ws_addr)?;
 
     info!("Sending PubSub subscription request");
     let params = json!([format!("{}", param)]);
@@ -72,6 +61,66 @@ where
     })
 }
 
+/// Open a websocket that can carry any number of subscriptions; their replies
+/// and notifications all arrive on the returned receiver
+pub fn start_pubsub_client(
+    ws_addr: String,
+) -> Result<(PubSubClient, Receiver<Event>), Box<dyn error::Error>> {
+    let (ws_mpsc_sender, ws_mpsc_receiver) = channel();
+
+    let client = thread::spawn(move || {
+        info!("Connecting to {}", ws_addr);
+        connect(ws_addr, move |sender| Client {
+            ws_out: sender,
+            thread_out: ws_mpsc_sender.clone(),
+        })
+        .unwrap();
+    });
+
+    let ws_sender = if let Event::Connect(s) = ws_mpsc_receiver.recv()? {
+        s
+    } else {
+        return Err(PubSubError::ConnectionFailed)?;
+    };
+
+    info!("Connected to PubSub websocket");
+
+    let pubsub_client = PubSubClient {
+        handle: client,
+        sender: ws_sender,
+    };
+    Ok((pubsub_client, ws_mpsc_receiver))
+}
+
+#[derive(Debug)]
+pub struct PubSubClient {
+    pub handle: JoinHandle<()>,
+    pub sender: WSSender,
+}
+
+impl PubSubClient {
+    /// Ask to subscribe to `param`; the reply carrying the subscription number
+    /// is tagged with `id`
+    pub fn subscribe<T>(&self, id: u64, method: &PubSubRequest, param: &T) -> Result<(), WSError>
+    where
+        T: fmt::Display,
+    {
+        let params = json!([format!("{}", param)]);
+        let request_json = method.build_request_json(id, Some(params));
+        self.sender.send(request_json.to_string())
+    }
+
+    pub fn unsubscribe(
+        &self,
+        id: u64,
+        method: &PubSubRequest,
+        subscription_num: u64,
+    ) -> Result<(), WSError> {
+        let request_json = method.build_unsubscribe_json(id, subscription_num);
+        self.sender.send(request_json.to_string())
+    }
+}
+
 #[derive(Debug)]
 pub struct PubSubThread {
     pub handle: JoinHandle<()>,
@@ -24,4 +24,18 @@ impl PubSubRequest {
         }
         request
     }
+
+    pub fn build_unsubscribe_json(&self, id: u64, subscription_num: u64) -> Value {
+        let method = match self {
+            PubSubRequest::Account => "accountUnsubscribe",
+            PubSubRequest::Program => "programUnsubscribe",
+            PubSubRequest::Signature => "signatureUnsubscribe",
+        };
+        json!({
+           "jsonrpc": "2.0",
+           "id": id,
+           "method": method,
+           "params": [subscription_num],
+        })
+    }
 }
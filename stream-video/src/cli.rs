@@ -1,9 +1,10 @@
+use bandwidth_prepay_api::bandwidth_prepay_state::{ContractTerms, Tariff};
 use clap::{App, Arg, SubCommand};
 use client::bandwidth_client::BandwidthClient;
 use provider_drone::DEFAULT_DRONE_PORT;
 use solana_client::rpc_client::RpcClient;
 use solana_sdk::pubkey::read_pubkey;
-use solana_sdk::signature::{read_keypair, KeypairUtil};
+use solana_sdk::signature::read_keypair;
 use std::net::SocketAddr;
 use stream_video::stream_video::*;
 
@@ -73,6 +74,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .value_name("NUM")
                         .takes_value(true)
                         .help("Number of lamports to fund contract with"),
+                )
+                .arg(
+                    Arg::with_name("lamports_per_kib")
+                        .long("lamports-per-kib")
+                        .value_name("NUM")
+                        .takes_value(true)
+                        .help("Tariff to agree to, in lamports per KiB. Defaults to 1"),
                 ),
         )
         .subcommand(
@@ -116,22 +124,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         } else {
             5_000_000
         };
+        let mut terms = ContractTerms {
+            tariff: Tariff {
+                lamports_per_kib: matches
+                    .value_of("lamports_per_kib")
+                    .unwrap_or("1")
+                    .parse()?,
+                minimum_charge: 0,
+            },
+            ..ContractTerms::default()
+        };
 
         let fullnode_client = RpcClient::new_socket(rpc_addr);
         let client = BandwidthClient::new(client_account, fullnode_client);
 
         let drone_addr = SocketAddr::new(host, DEFAULT_DRONE_PORT);
         client.request_airdrop(&drone_addr, lamports + 1)?;
-        let prepay_account =
-            client.initialize_contract(lamports, &gatekeeper_pubkey, &provider_pubkey);
+        terms.session_nonce = client.next_session_nonce(&gatekeeper_pubkey)?;
+        let (prepay_account, session_nonce) =
+            client.initialize_contract(lamports, terms, &gatekeeper_pubkey, &provider_pubkey);
 
         // Start connection
         let gatekeeper_addr = matches.value_of("gatekeeper_addr").unwrap();
         let destination = matches.value_of("destination").unwrap();
         let destination: SocketAddr = destination.parse()?;
 
-        let connection_addr =
-            client.request_connection(gatekeeper_addr, destination, &prepay_account.pubkey())?;
+        let connection_addr = client.request_connection(
+            gatekeeper_addr,
+            destination,
+            &prepay_account,
+            session_nonce,
+        )?;
 
         let mut video_connecter = VideoManager::new_video_connecter(&connection_addr, None)?;
 
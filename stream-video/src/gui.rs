@@ -3,6 +3,7 @@
 #![cfg_attr(feature = "ui-only", allow(unused_variables))]
 #![cfg_attr(test, recursion_limit = "128")]
 
+use bandwidth_prepay_api::bandwidth_prepay_state::{BandwidthPrepayState, ContractTerms, Tariff};
 use clap::{App, Arg};
 use client::bandwidth_client::BandwidthClient;
 use custom_error::custom_error;
@@ -167,6 +168,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         config.default_airdrop_lamports
     };
 
+    let terms = ContractTerms {
+        tariff: Tariff {
+            lamports_per_kib: config.lamports_per_kib,
+            minimum_charge: 0,
+        },
+        ..ContractTerms::default()
+    };
+
     debug!("Looking up gatekeeper hostname");
     let gatekeeper_addrs: Vec<SocketAddr> =
         (config.gatekeeper_addr.as_str(), config.gatekeeper_port)
@@ -336,15 +345,46 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             'stopped: loop {
                 match connecter_recv.recv() {
                     Ok(ConnecterCommand::StartConnection(addr, lamports)) => {
-                        let prepay_account = client.initialize_contract(
-                            lamports,
-                            &gatekeeper_pubkey,
-                            &provider_pubkey,
-                        );
+                        // Reuse a contract the gatekeeper closed at the end of an
+                        // earlier session, even one from before a restart
+                        let mut session_terms = terms.clone();
+                        let closed_session = client
+                            .find_contracts(&gatekeeper_pubkey)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .find(|(_, state)| *state == BandwidthPrepayState::default());
+                        let reused = closed_session.and_then(|(session_nonce, _)| {
+                            session_terms.session_nonce = session_nonce;
+                            client
+                                .reinitialize_contract(
+                                    lamports,
+                                    session_terms.clone(),
+                                    &gatekeeper_pubkey,
+                                    &provider_pubkey,
+                                )
+                                .ok()
+                        });
+                        let prepay_account = reused.unwrap_or_else(|| {
+                            session_terms.session_nonce =
+                                client.next_session_nonce(&gatekeeper_pubkey).unwrap();
+                            let (prepay_account, session_nonce) = client.initialize_contract(
+                                lamports,
+                                session_terms.clone(),
+                                &gatekeeper_pubkey,
+                                &provider_pubkey,
+                            );
+                            session_terms.session_nonce = session_nonce;
+                            prepay_account
+                        });
 
                         info!("Requesting connection to {:?}", addr);
                         let connection_addr = client
-                            .request_connection(&gatekeeper_addr, addr, &prepay_account.pubkey())
+                            .request_connection(
+                                &gatekeeper_addr,
+                                addr,
+                                &prepay_account,
+                                session_terms.session_nonce,
+                            )
                             .unwrap();
 
                         info!("Connecting to {:?}", connection_addr);
@@ -777,6 +817,7 @@ struct Config {
     gatekeeper_port: u16,
     default_airdrop_lamports: u64,
     default_contract_lamports: u64,
+    lamports_per_kib: u64,
     destinations: [String; NUM_DESTINATIONS],
     images: [String; NUM_DESTINATIONS],
     exit_button_image: String,